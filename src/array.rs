@@ -0,0 +1,88 @@
+//! Many independent mutexes in one segment.
+//!
+//! Every element is a complete [`SharedMutexInner`] with its own header, futex and
+//! robust-list node, laid out back to back like any Rust array. The robust list's
+//! `futex_offset` is the distance from a node to its futex word within one element, so
+//! it's the same for all of them and the kernel can clean up any mix of held elements.
+
+use std::{marker::PhantomData, sync::Arc};
+
+use crate::{
+    options::SharedMutexOptions,
+    shared_data::{SharedMutexInner, type_fingerprint},
+    shared_mem::{self, SharedMemorySafe, ShmemWrapper},
+};
+
+pub struct SharedMutexArray<T: SharedMemorySafe, const N: usize> {
+    memory: ShmemWrapper,
+    _quacks_like_a: PhantomData<Arc<[std::sync::Mutex<T>; N]>>,
+}
+
+unsafe impl<T: SharedMemorySafe, const N: usize> Send for SharedMutexArray<T, N> {}
+unsafe impl<T: SharedMemorySafe, const N: usize> Sync for SharedMutexArray<T, N> {}
+
+impl<T: SharedMemorySafe, const N: usize> SharedMutexArray<T, N> {
+    /// Maps `N` mutexes under one `name`, costing one segment instead of `N`. Each element
+    /// is initialized like [`SharedMutex::new`], with `initial(i)` for element `i`.
+    ///
+    /// # Panics
+    ///
+    /// If the shared memory can't be opened, or `name` was created for a different `T` or
+    /// `N`.
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T` and `N`
+    ///
+    /// [`SharedMutex::new`]: crate::SharedMutex::new
+    pub unsafe fn new(name: &str, mut initial: impl FnMut(usize) -> T) -> Self {
+        let memory = shared_mem::get_memory::<[SharedMutexInner<T>; N]>(name)
+            .unwrap_or_else(|e| panic!("`{name}`: {e:#}"));
+        let first: *mut SharedMutexInner<T> = memory.pointer().cast();
+        let fingerprint = type_fingerprint::<[T; N]>();
+        let recover_from_poison = true;
+        let options = SharedMutexOptions::default();
+        for i in 0..N {
+            unsafe {
+                SharedMutexInner::attach(
+                    first.add(i),
+                    &format!("{name}[{i}]"),
+                    fingerprint,
+                    || initial(i),
+                    recover_from_poison,
+                    |_| true,
+                    &options,
+                )
+            }
+            .unwrap_or_else(|e| panic!("`{name}`[{i}]: {e:#}"));
+        }
+        Self {
+            memory,
+            _quacks_like_a: PhantomData,
+        }
+    }
+
+    /// # Panics
+    ///
+    /// If `i` is out of bounds.
+    pub fn get(&self, i: usize) -> &SharedMutexInner<T> {
+        &self.as_slice()[i]
+    }
+
+    pub fn len(&self) -> usize {
+        N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        N == 0
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, SharedMutexInner<T>> {
+        self.as_slice().iter()
+    }
+
+    fn as_slice(&self) -> &[SharedMutexInner<T>; N] {
+        unsafe { &*self.memory.pointer().cast() }
+    }
+}
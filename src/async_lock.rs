@@ -0,0 +1,234 @@
+//! Awaitable locking for [`SharedMutex`].
+//!
+//! PI ownership belongs to a thread, so the lock can't be taken on an executor thread
+//! and released from whichever thread the task happens to resume on. Instead every
+//! [`SharedMutex::lock_async`] pins the lock to a helper thread: the helper blocks
+//! in `FUTEX_LOCK_PI`, hands the data to the task, and unlocks once the
+//! [`AsyncSharedGuard`] is dropped. The guard itself never owns the futex, which is
+//! why it may be held across `.await` points and move between executor threads. The
+//! helper keeps the mapping alive, so a future dropped while it waits doesn't have to
+//! wait too: the helper is left to take the lock and release it again on its own.
+//!
+//! The price is a thread spawn per acquisition, so this is meant for async code that
+//! occasionally touches cross-process state rather than hot paths.
+
+use std::{
+    future::Future,
+    io,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::{Arc, Mutex, mpsc},
+    task::{Context, Poll, Waker},
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    mutex::Acquired,
+    shared_data::{LockError, LockResult, SharedGuard, SharedMutex, SharedMutexInner},
+    shared_mem::{SharedMemorySafe, ShmemWrapper},
+};
+
+impl<T: SharedMemorySafe> SharedMutex<T> {
+    /// Locks without blocking the calling thread. Resolves like
+    /// [`SharedMutexInner::lock`], to [`LockError::Poisoned`] if the lock was poisoned
+    /// and [`LockError::Failed`] if it couldn't be taken.
+    ///
+    /// [`SharedMutexInner::lock`]: crate::shared_data::SharedMutexInner::lock
+    pub fn lock_async(&self) -> LockFuture<'_, T> {
+        LockFuture {
+            mutex: self,
+            holder: None,
+        }
+    }
+}
+
+/// Dropping the future before it resolves returns right away, and leaves the helper
+/// thread to get the lock and release it again.
+pub struct LockFuture<'a, T: SharedMemorySafe> {
+    mutex: &'a SharedMutex<T>,
+    holder: Option<Holder>,
+}
+
+impl<'a, T: SharedMemorySafe> Future for LockFuture<'a, T> {
+    type Output = LockResult<AsyncSharedGuard<'a, T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let holder = this
+            .holder
+            .get_or_insert_with(|| Holder::spawn(&**this.mutex, this.mutex.mapping()));
+
+        let acquired = {
+            let mut state = holder.state.lock().unwrap();
+            match state.acquired.take() {
+                Some(acquired) => acquired,
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        };
+        let owner_died = match acquired {
+            Ok(owner_died) => owner_died,
+            Err(e) => {
+                // the helper has nothing to unlock and is on its way out
+                this.holder = None;
+                return Poll::Ready(Err(LockError::Failed(e)));
+            }
+        };
+
+        let guard = AsyncSharedGuard {
+            guard: ManuallyDrop::new(this.mutex.guard(true)),
+            holder: this.holder.take(),
+        };
+        Poll::Ready(match owner_died {
+            false => Ok(guard),
+            true => Err(LockError::Poisoned(guard)),
+        })
+    }
+}
+
+/// Guard handed out by [`SharedMutex::lock_async`]. Dropping it poisons, checksums and
+/// ends the write like dropping a [`SharedGuard`], then tells the helper thread to unlock
+/// and waits for it to do so.
+pub struct AsyncSharedGuard<'a, T: SharedMemorySafe> {
+    /// Never dropped, since the futex isn't this thread's to unlock
+    guard: ManuallyDrop<SharedGuard<'a, T>>,
+    /// Taken on drop, after `guard` is finished
+    holder: Option<Holder>,
+}
+
+impl<'a, T: SharedMemorySafe + std::fmt::Debug> std::fmt::Debug for AsyncSharedGuard<'a, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        <T as std::fmt::Debug>::fmt(self, f)
+    }
+}
+
+unsafe impl<'a, T: SharedMemorySafe> Send for AsyncSharedGuard<'a, T> {}
+unsafe impl<'a, T: SharedMemorySafe> Sync for AsyncSharedGuard<'a, T> {}
+
+impl<T: SharedMemorySafe> Deref for AsyncSharedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<T: SharedMemorySafe> DerefMut for AsyncSharedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<T: SharedMemorySafe> AsyncSharedGuard<'_, T> {
+    /// See [`SharedGuard::poison_with`].
+    pub fn poison_with(&self, code: u32) {
+        self.guard.poison_with(code);
+    }
+
+    /// See [`SharedGuard::poison_reason`].
+    pub fn poison_reason(&self) -> Option<u32> {
+        self.guard.poison_reason()
+    }
+}
+
+impl<T: SharedMemorySafe> Drop for AsyncSharedGuard<'_, T> {
+    fn drop(&mut self) {
+        self.guard.finish();
+        drop(self.holder.take());
+    }
+}
+
+#[derive(Default)]
+struct HolderState {
+    /// Set by the helper once it holds the lock, to whether the owner died, or failed to
+    /// take it. Taken by the future.
+    acquired: Option<io::Result<bool>>,
+    /// Whether `acquired` was ever set, so the helper only has an unlock left to do
+    resolved: bool,
+    waker: Option<Waker>,
+}
+
+/// The helper thread that owns the lock on behalf of a task.
+struct Holder {
+    state: Arc<Mutex<HolderState>>,
+    release: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// The mutex, with the mapping that holds it. Its type is erased so that the helper
+/// thread doesn't need `T: 'static`.
+struct Mapped {
+    mutex: *const (),
+    acquire: unsafe fn(*const ()) -> io::Result<Acquired>,
+    unlock: unsafe fn(*const (), bool),
+    _mapping: ShmemWrapper,
+}
+
+unsafe impl Send for Mapped {}
+
+impl Holder {
+    fn spawn<T: SharedMemorySafe>(mutex: &SharedMutexInner<T>, mapping: ShmemWrapper) -> Self {
+        let state = Arc::new(Mutex::new(HolderState::default()));
+        let (release, released) = mpsc::channel::<()>();
+        let mapped = Mapped {
+            mutex: (mutex as *const SharedMutexInner<T>).cast(),
+            acquire: |mutex| unsafe { (*mutex.cast::<SharedMutexInner<T>>()).acquire(false) },
+            unlock: |mutex, poisoned| unsafe {
+                (*mutex.cast::<SharedMutexInner<T>>()).unlock(poisoned)
+            },
+            _mapping: mapping,
+        };
+
+        let thread = thread::spawn({
+            let state = state.clone();
+            move || {
+                let mapped = mapped;
+                // SAFETY: `mapped` keeps the mutex mapped for as long as this thread runs,
+                // whether or not the holder is still around. Taking the lock the way a
+                // guard would keeps the metrics, owners, poison and checksum up to date.
+                let acquired =
+                    unsafe { (mapped.acquire)(mapped.mutex) }.map(|acquired| acquired.owner_died);
+                let locked = acquired.is_ok();
+                {
+                    let mut state = state.lock().unwrap();
+                    state.acquired = Some(acquired);
+                    state.resolved = true;
+                    if let Some(waker) = state.waker.take() {
+                        waker.wake();
+                    }
+                }
+                if locked {
+                    // either an explicit release or the holder going away
+                    let _ = released.recv();
+                    // poison nobody got to see is left for the next owner
+                    let unseen = matches!(state.lock().unwrap().acquired, Some(Ok(true)));
+                    unsafe { (mapped.unlock)(mapped.mutex, unseen) };
+                }
+            }
+        });
+
+        Self {
+            state,
+            release: Some(release),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Holder {
+    fn drop(&mut self) {
+        drop(self.release.take());
+        // waiting for the lock could take forever, so a helper still waiting is left to
+        // unlock whenever it gets it; one that has it unlocks right away, and is waited
+        // for so that the lock is free once the guard is gone
+        let resolved = self.state.lock().unwrap().resolved;
+        if let Some(thread) = self.thread.take()
+            && resolved
+        {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! A reusable barrier that any process can use.
+//!
+//! Arrivals count up in `arrived`; the last one resets the count and bumps `generation`,
+//! which everyone else is futex-waiting on. A participant that dies before arriving can't
+//! be detected the way a dead lock owner can, since it holds nothing. Instead waiters can
+//! give up with [`SharedBarrierInner::wait_timeout`], which breaks the barrier for
+//! everyone, so a crash ends up as an error in every process rather than a hang.
+
+use std::{
+    io,
+    marker::PhantomData,
+    ops::Deref,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use nix::errno::Errno;
+
+use crate::{
+    futex::{duration_to_timespec, sys},
+    mutex::{DEFAULT_MAX_RETRIES, PiMutex},
+    shared_mem::{self, MemoryBackend, ShmBackend, ShmemWrapper},
+};
+
+pub struct SharedBarrier {
+    memory: ShmemWrapper,
+    _quacks_like_a: PhantomData<Arc<std::sync::Barrier>>,
+}
+
+unsafe impl Send for SharedBarrier {}
+unsafe impl Sync for SharedBarrier {}
+
+impl SharedBarrier {
+    /// Opens the barrier `name` for `parties` participants, creating it if it doesn't exist
+    /// yet. The first process to create it decides `parties`, later callers' values are
+    /// ignored.
+    ///
+    /// # Panics
+    ///
+    /// If `parties` is zero.
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that `name` is only ever opened as a `SharedBarrier`
+    pub unsafe fn new(name: &str, parties: u32) -> Self {
+        unsafe { Self::new_in(&ShmBackend, name, parties) }
+    }
+
+    /// Like [`Self::new`], with the segment from `backend` instead of `/dev/shm`.
+    ///
+    /// # Panics
+    ///
+    /// If `parties` is zero.
+    ///
+    /// # Safety
+    ///
+    /// As for [`Self::new`].
+    pub unsafe fn new_in(
+        backend: &(impl MemoryBackend + ?Sized),
+        name: &str,
+        parties: u32,
+    ) -> Self {
+        assert!(parties > 0, "a barrier needs at least one participant");
+        let memory =
+            shared_mem::get_memory_in::<SharedBarrierInner>(backend, name, 0, false).unwrap();
+
+        let inner: *mut SharedBarrierInner = memory.pointer().cast();
+        unsafe {
+            (*inner)
+                .init_lock
+                .lock_inner(None, false, DEFAULT_MAX_RETRIES)
+                .unwrap();
+            if (*inner).parties.load(Ordering::Relaxed) == 0 {
+                (*inner).arrived.store(0, Ordering::Relaxed);
+                (*inner).broken.store(0, Ordering::Relaxed);
+                (*inner).parties.store(parties, Ordering::Release);
+            }
+            (*inner).init_lock.unlock();
+        }
+
+        Self {
+            memory,
+            _quacks_like_a: PhantomData,
+        }
+    }
+}
+
+impl Deref for SharedBarrier {
+    type Target = SharedBarrierInner;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.memory.pointer().cast() }
+    }
+}
+
+#[repr(C)]
+pub struct SharedBarrierInner {
+    /// Only held while setting up the segment
+    init_lock: PiMutex,
+    /// 0 until initialized
+    parties: AtomicU32,
+    arrived: AtomicU32,
+    generation: AtomicU32,
+    broken: AtomicU32,
+}
+
+/// Returned to every participant of a completed round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult {
+    leader: bool,
+}
+
+impl BarrierWaitResult {
+    /// `true` for exactly one participant per round, the last to arrive.
+    pub fn is_leader(&self) -> bool {
+        self.leader
+    }
+}
+
+/// A participant gave up waiting, so the round can never complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierBroken;
+
+impl std::fmt::Display for BarrierBroken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("shared barrier is broken")
+    }
+}
+
+impl std::error::Error for BarrierBroken {}
+
+impl SharedBarrierInner {
+    /// Blocks until all participants have called `wait` for this round. Fails if the
+    /// barrier is or gets broken.
+    pub fn wait(&self) -> Result<BarrierWaitResult, BarrierBroken> {
+        self.wait_until(None)
+    }
+
+    /// Like [`Self::wait`], but if the round isn't complete within `timeout`, breaks the
+    /// barrier, failing this and every other current and future wait. Meant for noticing
+    /// a participant that died before arriving.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<BarrierWaitResult, BarrierBroken> {
+        self.wait_until(Instant::now().checked_add(timeout))
+    }
+
+    pub fn is_broken(&self) -> bool {
+        self.broken.load(Ordering::Acquire) != 0
+    }
+
+    fn wait_until(&self, deadline: Option<Instant>) -> Result<BarrierWaitResult, BarrierBroken> {
+        let generation = self.generation.load(Ordering::Acquire);
+        if self.is_broken() {
+            return Err(BarrierBroken);
+        }
+        let parties = self.parties.load(Ordering::Acquire);
+        if self.arrived.fetch_add(1, Ordering::AcqRel) + 1 >= parties {
+            self.arrived.store(0, Ordering::Relaxed);
+            self.generation.fetch_add(1, Ordering::Release);
+            self.wake_all();
+            return Ok(BarrierWaitResult { leader: true });
+        }
+
+        loop {
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        self.broken.store(1, Ordering::Release);
+                        self.generation.fetch_add(1, Ordering::Release);
+                        self.wake_all();
+                        return Err(BarrierBroken);
+                    }
+                    Some(duration_to_timespec(left))
+                }
+                None => None,
+            };
+            match unsafe { sys::wait(&self.generation, generation, timeout) } {
+                Ok(()) | Err(Errno::EAGAIN | Errno::EINTR | Errno::ETIMEDOUT) => {}
+                Err(e) => debug_assert!(false, "{}", io::Error::from(e)),
+            }
+            if self.generation.load(Ordering::Acquire) != generation {
+                return match self.is_broken() {
+                    true => Err(BarrierBroken),
+                    false => Ok(BarrierWaitResult { leader: false }),
+                };
+            }
+        }
+    }
+
+    fn wake_all(&self) {
+        if let Err(e) = unsafe { sys::wake(&self.generation, i32::MAX) } {
+            debug_assert!(false, "{}", io::Error::from(e));
+        }
+    }
+}
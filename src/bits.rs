@@ -0,0 +1,63 @@
+//! Read-modify-write of flag bits under the lock.
+
+use std::ops::{BitAnd, BitOr, BitXor, Not};
+
+use crate::{
+    shared_data::{LockResult, SharedMutexInner},
+    shared_mem::SharedMemorySafe,
+};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// The primitive integers, for [`SharedMutexInner::set_bits`] and friends. Sealed, the
+/// methods only make sense for types whose bits are the value.
+pub trait IntOps:
+    sealed::Sealed
+    + SharedMemorySafe
+    + Copy
+    + BitAnd<Output = Self>
+    + BitOr<Output = Self>
+    + BitXor<Output = Self>
+    + Not<Output = Self>
+{
+}
+
+macro_rules! impl_int_ops {
+    ($($t:ty),*) => {
+        $(
+            impl sealed::Sealed for $t {}
+            impl IntOps for $t {}
+        )*
+    };
+}
+
+impl_int_ops!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize
+);
+
+impl<T: IntOps> SharedMutexInner<T> {
+    /// Sets the bits of `mask` under the lock and returns the previous value, like
+    /// `AtomicU32::fetch_or`. If the lock was poisoned the bits are set anyway and the
+    /// previous value comes back inside [`LockError::Poisoned`].
+    ///
+    /// [`LockError::Poisoned`]: crate::LockError::Poisoned
+    pub fn set_bits(&self, mask: T) -> LockResult<T> {
+        self.update_bits(|value| value | mask)
+    }
+
+    /// Clears the bits of `mask`, see [`Self::set_bits`].
+    pub fn clear_bits(&self, mask: T) -> LockResult<T> {
+        self.update_bits(|value| value & !mask)
+    }
+
+    /// Flips the bits of `mask`, see [`Self::set_bits`].
+    pub fn toggle_bits(&self, mask: T) -> LockResult<T> {
+        self.update_bits(|value| value ^ mask)
+    }
+
+    fn update_bits(&self, op: impl FnOnce(T) -> T) -> LockResult<T> {
+        self.with_lock(|value| std::mem::replace(value, op(*value)))
+    }
+}
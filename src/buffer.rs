@@ -0,0 +1,66 @@
+//! A length-prefixed byte buffer to keep messages in a [`SharedMutex`].
+//!
+//! [`SharedMutex`]: crate::SharedMutex
+
+/// Up to `N` bytes and how many of them are in use. It's `Copy` and plain data, so it can
+/// be the `T` of a shared mutex directly or a field of one.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SharedBuffer<const N: usize> {
+    len: usize,
+    bytes: [u8; N],
+}
+
+impl<const N: usize> SharedBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            len: 0,
+            bytes: [0; N],
+        }
+    }
+
+    /// Replaces the contents with as much of `data` as fits, and returns how much that was.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let written = data.len().min(N);
+        self.bytes[..written].copy_from_slice(&data[..written]);
+        self.len = written;
+        written
+    }
+
+    /// The bytes written last.
+    pub fn read(&self) -> &[u8] {
+        // a length from a dead owner could be anything, don't slice with it
+        &self.bytes[..self.len.min(N)]
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for SharedBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> std::fmt::Debug for SharedBuffer<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedBuffer")
+            .field("capacity", &N)
+            .field("data", &self.read())
+            .finish()
+    }
+}
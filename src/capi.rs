@@ -0,0 +1,166 @@
+//! C ABI for the `capi` feature, so C and C++ programs can share a segment with Rust ones.
+//!
+//! Build a shared library with `cargo rustc --release --features capi --crate-type cdylib`
+//! and declare:
+//!
+//! ```c
+//! typedef struct rsm_mutex rsm_mutex;
+//! rsm_mutex *rsm_open(const char *name, size_t size);
+//! int rsm_lock(rsm_mutex *mutex);
+//! int rsm_try_lock(rsm_mutex *mutex);
+//! int rsm_unlock(rsm_mutex *mutex);
+//! void *rsm_data(rsm_mutex *mutex);
+//! void rsm_close(rsm_mutex *mutex);
+//! ```
+//!
+//! A C handle is a `SharedMutex<()>` opened with [`SharedMutex::new_sized`], and its
+//! `size` bytes of data are the tail. Rust code opening the same name that way sees the
+//! same lock and bytes. The segment layout is `SharedMutexInner<()>`, which is
+//! `#[repr(C)]`: header (magic, version, type fingerprint), the [`AosMutex`], lock
+//! metrics, owner bookkeeping, the fair queue, then the tail. It's stable for a given
+//! header version; anything that changes it bumps the version, and a segment with an
+//! unknown version is reinitialized rather than misread.
+//!
+//! [`AosMutex`]: crate::futex::AosMutex
+
+use std::{
+    ffi::{CStr, c_char, c_int, c_void},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+
+use crate::shared_data::{LockError, SharedMutex};
+
+/// Locked, or unlocked for `rsm_unlock`.
+pub const RSM_OK: c_int = 0;
+/// Locked, but the previous owner died holding the lock, so the data may be half written.
+pub const RSM_OWNER_DIED: c_int = 1;
+/// `rsm_try_lock` only: held by someone else.
+pub const RSM_BUSY: c_int = 2;
+/// A null handle, the lock couldn't be taken for another reason, or `rsm_unlock` by a
+/// thread that doesn't hold it.
+pub const RSM_ERROR: c_int = -1;
+
+/// Runs the body of an `extern "C"` function, returning `on_panic` instead of unwinding
+/// into the C caller, which would abort it.
+fn no_unwind<R>(on_panic: R, body: impl FnOnce() -> R) -> R {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or(on_panic)
+}
+
+/// Opens or creates the mutex `name` with `size` bytes of zeroed data. The process that
+/// creates it decides the size. Returns null if `name` isn't valid UTF-8 or the shared
+/// memory can't be opened or mapped.
+///
+/// # Safety
+///
+/// `name` must be a valid NUL-terminated string. The name must only be used for mutexes
+/// opened through this function or `SharedMutex::<()>::new_sized`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rsm_open(name: *const c_char, size: usize) -> *mut SharedMutex<()> {
+    if name.is_null() {
+        return ptr::null_mut();
+    }
+    let Ok(name) = unsafe { CStr::from_ptr(name) }.to_str() else {
+        return ptr::null_mut();
+    };
+    no_unwind(ptr::null_mut(), || {
+        match unsafe { SharedMutex::try_new_sized(name, size, || ()) } {
+            Ok(mutex) => Box::into_raw(Box::new(mutex)),
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+/// Blocks until the lock is held. Returns `RSM_OK`, `RSM_OWNER_DIED` or `RSM_ERROR`,
+/// which includes the calling thread holding the lock already.
+///
+/// # Safety
+///
+/// `mutex` must come from `rsm_open` and not be closed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rsm_lock(mutex: *mut SharedMutex<()>) -> c_int {
+    let Some(mutex) = (unsafe { mutex.as_ref() }) else {
+        return RSM_ERROR;
+    };
+    no_unwind(RSM_ERROR, || match mutex.lock_uninterruptible() {
+        Ok(guard) => {
+            std::mem::forget(guard);
+            RSM_OK
+        }
+        Err(LockError::Poisoned(guard)) => {
+            std::mem::forget(guard);
+            RSM_OWNER_DIED
+        }
+        Err(LockError::Failed(_)) => RSM_ERROR,
+    })
+}
+
+/// Like `rsm_lock`, but returns `RSM_BUSY` instead of blocking.
+///
+/// # Safety
+///
+/// `mutex` must come from `rsm_open` and not be closed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rsm_try_lock(mutex: *mut SharedMutex<()>) -> c_int {
+    let Some(mutex) = (unsafe { mutex.as_ref() }) else {
+        return RSM_ERROR;
+    };
+    no_unwind(RSM_ERROR, || match mutex.try_lock() {
+        Ok(Some(guard)) => {
+            std::mem::forget(guard);
+            RSM_OK
+        }
+        Ok(None) => RSM_BUSY,
+        Err(LockError::Poisoned(guard)) => {
+            std::mem::forget(guard);
+            RSM_OWNER_DIED
+        }
+        Err(LockError::Failed(_)) => RSM_ERROR,
+    })
+}
+
+/// Releases the lock. Returns `RSM_OK`, or `RSM_ERROR` for a null handle or if the
+/// calling thread doesn't hold the lock, which is left alone then.
+///
+/// # Safety
+///
+/// `mutex` must come from `rsm_open` and not be closed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rsm_unlock(mutex: *mut SharedMutex<()>) -> c_int {
+    let Some(mutex) = (unsafe { mutex.as_ref() }) else {
+        return RSM_ERROR;
+    };
+    no_unwind(RSM_ERROR, || {
+        if !mutex.held_by_current_thread() {
+            return RSM_ERROR;
+        }
+        drop(mutex.guard(true));
+        RSM_OK
+    })
+}
+
+/// The `size` bytes of data, null for a null handle. Only access them while holding the
+/// lock.
+///
+/// # Safety
+///
+/// `mutex` must come from `rsm_open` and not be closed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rsm_data(mutex: *mut SharedMutex<()>) -> *mut c_void {
+    match unsafe { mutex.as_ref() } {
+        Some(mutex) => no_unwind(ptr::null_mut(), || mutex.tail().cast()),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Unmaps the mutex. The segment itself stays for other users.
+///
+/// # Safety
+///
+/// `mutex` must come from `rsm_open` or be null, and not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rsm_close(mutex: *mut SharedMutex<()>) {
+    if !mutex.is_null() {
+        no_unwind((), || drop(unsafe { Box::from_raw(mutex) }));
+    }
+}
@@ -1,69 +1,198 @@
-use std::{io, sync::atomic::Ordering, time::Duration};
+use std::{
+    io,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
 
 use nix::errno::Errno;
 
-use crate::futex::{
-    AosCondition, AosMutex, FUTEX_OWNER_DIED, FUTEX_TID_MASK, duration_to_timespec,
-    sys::{cmp_requeue_pi, lock_pi, unlock_pi, wait_requeue_pi},
-    tid,
+use crate::{
+    futex::{
+        AosCondition, monotonic_deadline,
+        sys::{cmp_requeue_pi, wait_requeue_pi},
+    },
+    mutex::{DEFAULT_MAX_RETRIES, PiMutex, PiMutexGuard},
 };
 
 pub struct PiCondvar(AosCondition);
+
+/// Whether a [`PiCondvar::wait_timeout`] ended by timing out, like
+/// [`std::sync::WaitTimeoutResult`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+impl Default for PiCondvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PiCondvar {
     pub const fn new() -> Self {
         Self(AosCondition::new(0))
     }
 
     pub fn wait<'a>(&self, guard: PiMutexGuard<'a>) -> io::Result<PiMutexGuard<'a>> {
-        self.wait_inner(guard, None)
+        self.wait_inner(guard, None).map(|(guard, _)| guard)
     }
+    /// Like [`Self::wait`], but gives up after `d`. The lock is held again either way, so
+    /// the guard comes back with whether it timed out.
     pub fn wait_timeout<'a>(
         &self,
         guard: PiMutexGuard<'a>,
         d: Duration,
-    ) -> io::Result<PiMutexGuard<'a>> {
-        self.wait_inner(guard, Some(d))
+    ) -> io::Result<(PiMutexGuard<'a>, WaitTimeoutResult)> {
+        self.wait_inner(guard, Instant::now().checked_add(d))
     }
     pub fn notify_one(&self, m: &PiMutex) -> io::Result<()> {
-        self.wake(m, 0)
+        self.wake(m, 0).map(|_| ())
     }
     pub fn notify_all(&self, m: &PiMutex) -> io::Result<()> {
+        self.wake(m, i32::MAX).map(|_| ())
+    }
+    /// Like [`Self::notify_one`], but says whether there was a waiter to wake. A waiter
+    /// that was notified on its way into the kernel returns without being counted, so a
+    /// 0 doesn't prove nobody was waiting.
+    pub fn notify_one_count(&self, m: &PiMutex) -> io::Result<usize> {
+        self.wake(m, 0)
+    }
+    /// Like [`Self::notify_all`], but returns how many waiters were handed to `m`, the one
+    /// woken to take it right away plus those requeued to wait for it, with the same
+    /// caveat as [`Self::notify_one_count`].
+    pub fn notify_all_count(&self, m: &PiMutex) -> io::Result<usize> {
         self.wake(m, i32::MAX)
     }
+    /// Hands up to `n` waiters to `m`, one woken to take it and the rest requeued to wait
+    /// for it, e.g. one for each item a producer added, where [`Self::notify_all`] would
+    /// have the rest find nothing left. Returns how many, with the same caveat as
+    /// [`Self::notify_one_count`]. Does nothing for `n` of 0 or less.
+    pub fn notify_n(&self, m: &PiMutex, n: i32) -> io::Result<usize> {
+        if n <= 0 {
+            return Ok(0);
+        }
+        // the woken one isn't part of the requeue count
+        self.wake(m, n - 1)
+    }
 
     // ---------- internals ----------
     fn wait_inner<'a>(
         &self,
         guard: PiMutexGuard<'a>,
-        dur: Option<Duration>,
-    ) -> io::Result<PiMutexGuard<'a>> {
+        deadline: Option<Instant>,
+    ) -> io::Result<(PiMutexGuard<'a>, WaitTimeoutResult)> {
         let start = self.0.load(Ordering::SeqCst);
+        // the mutex outlives the guard, take it before the guard is gone
+        let mutex: &'a PiMutex = guard.0;
         // unlock before sleeping
         drop(guard);
 
-        let ts = dur.map(duration_to_timespec);
-        unsafe {
-            match wait_requeue_pi(&self.0, start, ts, &guard.0.0.futex) {
-                Ok(_) => {}
-                Err(Errno::ETIMEDOUT) => return Err(io::ErrorKind::TimedOut.into()),
+        // the kernel wants an absolute CLOCK_MONOTONIC time here
+        let ts = deadline.map(monotonic_deadline);
+        let timed_out = unsafe {
+            match wait_requeue_pi(&self.0, start, ts, &mutex.0.futex) {
+                Ok(_) => false,
+                // notified between the load and the wait, which is just an early wakeup
+                Err(Errno::EAGAIN) => {
+                    mutex.lock_inner(None, false, DEFAULT_MAX_RETRIES)?;
+                    return Ok((PiMutexGuard(mutex), WaitTimeoutResult(false)));
+                }
+                Err(Errno::ETIMEDOUT) => true,
                 Err(Errno::EINTR) => return Err(io::ErrorKind::Interrupted.into()),
                 Err(e) => return Err(e.into()),
             }
+        };
+
+        if timed_out && !mutex.is_locked_by_me() {
+            // timed out before being requeued, or while waiting on the mutex after, and
+            // either way the kernel gave up without taking the lock for us
+            mutex.lock_inner(None, false, DEFAULT_MAX_RETRIES)?;
+        } else {
+            // relock delivered by kernel – create new guard
+            unsafe { mutex.adopt() };
         }
+        Ok((PiMutexGuard(mutex), WaitTimeoutResult(timed_out)))
+    }
+
+    fn wake(&self, m: &PiMutex, requeue: i32) -> io::Result<usize> {
+        let mut generation = self.0.fetch_add(1, Ordering::SeqCst).wrapping_add(1);
+        loop {
+            match unsafe { cmp_requeue_pi(&self.0, 1, requeue, &m.0.futex, generation) } {
+                Ok(woken) => return Ok(woken as usize),
+                // another notify bumped the generation first. Its waiters are woken by it,
+                // but ours may have gone to sleep on the newer value, so go again with that.
+                Err(Errno::EAGAIN) => generation = self.0.load(Ordering::SeqCst),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// `N` condition variables for one [`PiMutex`], e.g. "not empty" and "not full" for a
+/// bounded buffer. Each has its own generation word, so notifying one doesn't disturb
+/// waiters on the others, and all of them hand their waiters to the same lock. Like
+/// [`PiCondvar`] it's plain `#[repr(C)]` memory, so it can sit in a shared segment next
+/// to the mutex.
+#[repr(C)]
+pub struct PiCondvarGroup<const N: usize> {
+    conditions: [PiCondvar; N],
+}
+
+impl<const N: usize> Default for PiCondvarGroup<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        if guard.0.0.futex.load(Ordering::Acquire) & FUTEX_OWNER_DIED != 0 {
-            guard
-                .0
-                .0
-                .futex
-                .fetch_and(!FUTEX_OWNER_DIED, Ordering::Relaxed);
+impl<const N: usize> PiCondvarGroup<N> {
+    pub const fn new() -> Self {
+        Self {
+            conditions: [const { PiCondvar::new() }; N],
         }
-        // relock delivered by kernel – create new guard
-        Ok(PiMutexGuard(&guard.0))
     }
 
-    fn wake(&self, m: &PiMutex, requeue: i32) -> io::Result<()> {
-        let new_gen = self.0.fetch_add(1, Ordering::SeqCst) + 1;
-        unsafe { cmp_requeue_pi(&self.0, 1, requeue, &m.0.futex, new_gen) }.map_err(|e| e.into())
+    /// [`PiCondvar::wait`] on condition `i`.
+    ///
+    /// # Panics
+    ///
+    /// If `i` is out of bounds, as do the other `_on` methods.
+    pub fn wait_on<'a>(&self, i: usize, guard: PiMutexGuard<'a>) -> io::Result<PiMutexGuard<'a>> {
+        self.conditions[i].wait(guard)
+    }
+
+    pub fn wait_timeout_on<'a>(
+        &self,
+        i: usize,
+        guard: PiMutexGuard<'a>,
+        d: Duration,
+    ) -> io::Result<(PiMutexGuard<'a>, WaitTimeoutResult)> {
+        self.conditions[i].wait_timeout(guard, d)
+    }
+
+    /// Wakes one waiter on condition `i`. `m` must be the mutex every condition is used
+    /// with.
+    pub fn notify_one_on(&self, i: usize, m: &PiMutex) -> io::Result<()> {
+        self.conditions[i].notify_one(m)
+    }
+
+    pub fn notify_all_on(&self, i: usize, m: &PiMutex) -> io::Result<()> {
+        self.conditions[i].notify_all(m)
+    }
+
+    pub fn notify_n_on(&self, i: usize, m: &PiMutex, n: i32) -> io::Result<usize> {
+        self.conditions[i].notify_n(m, n)
+    }
+
+    pub fn len(&self) -> usize {
+        N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        N == 0
     }
 }
@@ -0,0 +1,78 @@
+//! A `u64` counter in shared memory, with the overflow behavior spelled out at each call.
+
+use crate::shared_data::{SharedGuard, SharedMutex};
+
+/// A [`SharedMutex<u64>`] that's only ever added to. Any process can open `name` as a
+/// plain `SharedMutex<u64>` too, e.g. to reset it.
+pub struct SharedCounter {
+    mutex: SharedMutex<u64>,
+}
+
+/// Adding would have taken a [`SharedCounter`] past `u64::MAX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CounterOverflow;
+
+impl std::fmt::Display for CounterOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("shared counter overflowed")
+    }
+}
+
+impl std::error::Error for CounterOverflow {}
+
+impl SharedCounter {
+    /// Opens the counter `name`, creating it at `initial` if it doesn't exist yet. For
+    /// other options open the mutex with [`SharedMutexOptions`] and convert it with
+    /// `From`.
+    ///
+    /// # Panics
+    ///
+    /// Like [`SharedMutex::new`].
+    ///
+    /// [`SharedMutexOptions`]: crate::SharedMutexOptions
+    pub fn new(name: &str, initial: u64) -> Self {
+        // SAFETY: anyone else opening `name` for another `T` fails the fingerprint check
+        unsafe { SharedMutex::new_with_val(name, initial) }.into()
+    }
+
+    pub fn load(&self) -> u64 {
+        *self.lock()
+    }
+
+    /// Adds `n`, stopping at `u64::MAX`. Returns the previous value.
+    pub fn fetch_add_saturating(&self, n: u64) -> u64 {
+        self.update(|value| value.saturating_add(n))
+    }
+
+    /// Adds `n`, wrapping around past `u64::MAX`. Returns the previous value.
+    pub fn fetch_add_wrapping(&self, n: u64) -> u64 {
+        self.update(|value| value.wrapping_add(n))
+    }
+
+    /// Adds `n` unless that would overflow, in which case the counter is left alone.
+    /// Returns the previous value.
+    pub fn fetch_add_checked(&self, n: u64) -> Result<u64, CounterOverflow> {
+        let mut value = self.lock();
+        let previous = *value;
+        *value = previous.checked_add(n).ok_or(CounterOverflow)?;
+        Ok(previous)
+    }
+
+    fn update(&self, f: impl FnOnce(u64) -> u64) -> u64 {
+        let mut value = self.lock();
+        let previous = *value;
+        *value = f(previous);
+        previous
+    }
+
+    fn lock(&self) -> SharedGuard<'_, u64> {
+        // a u64 is stored in one go, so a dead owner can't have left half of one behind
+        self.mutex.grab()
+    }
+}
+
+impl From<SharedMutex<u64>> for SharedCounter {
+    fn from(mutex: SharedMutex<u64>) -> Self {
+        Self { mutex }
+    }
+}
@@ -0,0 +1,186 @@
+//! An event that any process can set and wait for.
+//!
+//! The whole state is one futex word, 1 while set. Waiters `FUTEX_WAIT` on it while it's
+//! 0 and setters `FUTEX_WAKE` them. Nobody owns an event the way a lock is owned, so a
+//! process dying at any point leaves nothing to recover: a dead setter just never set it,
+//! and a dead waiter never consumed anything.
+
+use std::{
+    io,
+    marker::PhantomData,
+    ops::Deref,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use nix::errno::Errno;
+
+use crate::{
+    futex::{duration_to_timespec, sys},
+    mutex::{DEFAULT_MAX_RETRIES, PiMutex},
+    shared_mem::{self, MemoryBackend, ShmBackend, ShmemWrapper},
+};
+
+pub struct SharedEvent {
+    memory: ShmemWrapper,
+    _quacks_like_a: PhantomData<Arc<(std::sync::Mutex<bool>, std::sync::Condvar)>>,
+}
+
+unsafe impl Send for SharedEvent {}
+unsafe impl Sync for SharedEvent {}
+
+/// What a [`SharedEvent`] does with the waiters once set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetMode {
+    /// Each set releases one waiter, which resets the event on its way out. Sets while
+    /// it's already set don't add up.
+    Auto,
+    /// A set releases every waiter, present and future, until [`SharedEventInner::reset`].
+    Manual,
+}
+
+impl SharedEvent {
+    /// Opens the event `name`, creating it unset if it doesn't exist yet. The first
+    /// process to create it decides `mode`, later callers' values are ignored.
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that `name` is only ever opened as a `SharedEvent`
+    pub unsafe fn new(name: &str, mode: ResetMode) -> Self {
+        unsafe { Self::new_in(&ShmBackend, name, mode) }
+    }
+
+    /// Like [`Self::new`], with the segment from `backend` instead of `/dev/shm`.
+    ///
+    /// # Safety
+    ///
+    /// As for [`Self::new`].
+    pub unsafe fn new_in(
+        backend: &(impl MemoryBackend + ?Sized),
+        name: &str,
+        mode: ResetMode,
+    ) -> Self {
+        let memory =
+            shared_mem::get_memory_in::<SharedEventInner>(backend, name, 0, false).unwrap();
+
+        let inner: *mut SharedEventInner = memory.pointer().cast();
+        unsafe {
+            (*inner)
+                .init_lock
+                .lock_inner(None, false, DEFAULT_MAX_RETRIES)
+                .unwrap();
+            if (*inner).mode.load(Ordering::Relaxed) == UNINIT {
+                (*inner).state.store(0, Ordering::Relaxed);
+                let mode = match mode {
+                    ResetMode::Auto => AUTO,
+                    ResetMode::Manual => MANUAL,
+                };
+                (*inner).mode.store(mode, Ordering::Release);
+            }
+            (*inner).init_lock.unlock();
+        }
+
+        Self {
+            memory,
+            _quacks_like_a: PhantomData,
+        }
+    }
+}
+
+impl Deref for SharedEvent {
+    type Target = SharedEventInner;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.memory.pointer().cast() }
+    }
+}
+
+const UNINIT: u32 = 0;
+const AUTO: u32 = 1;
+const MANUAL: u32 = 2;
+
+#[repr(C)]
+pub struct SharedEventInner {
+    /// Only held while setting up the segment
+    init_lock: PiMutex,
+    /// [`UNINIT`] until initialized, then [`AUTO`] or [`MANUAL`]
+    mode: AtomicU32,
+    /// 1 while set, the futex word waiters sleep on
+    state: AtomicU32,
+}
+
+impl SharedEventInner {
+    pub fn mode(&self) -> ResetMode {
+        match self.mode.load(Ordering::Acquire) {
+            AUTO => ResetMode::Auto,
+            _ => ResetMode::Manual,
+        }
+    }
+
+    /// Sets the event, releasing one waiter in [`ResetMode::Auto`] or all of them in
+    /// [`ResetMode::Manual`].
+    pub fn set(&self) {
+        self.state.store(1, Ordering::Release);
+        let n = match self.mode() {
+            ResetMode::Auto => 1,
+            ResetMode::Manual => i32::MAX,
+        };
+        if let Err(e) = unsafe { sys::wake(&self.state, n) } {
+            debug_assert!(false, "{}", io::Error::from(e));
+        }
+    }
+
+    /// Unsets the event, so later waits block until the next [`Self::set`].
+    pub fn reset(&self) {
+        self.state.store(0, Ordering::Release);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.state.load(Ordering::Acquire) != 0
+    }
+
+    /// Blocks until the event is set, and in [`ResetMode::Auto`] resets it.
+    pub fn wait(&self) {
+        self.wait_until(None);
+    }
+
+    /// Like [`Self::wait`], but gives up after `timeout`. Returns whether the event was
+    /// set in time.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.wait_until(Instant::now().checked_add(timeout))
+    }
+
+    fn wait_until(&self, deadline: Option<Instant>) -> bool {
+        let auto = self.mode() == ResetMode::Auto;
+        loop {
+            let taken = match auto {
+                // of any number of waiters woken by one set, only one gets through
+                true => self
+                    .state
+                    .compare_exchange(1, 0, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok(),
+                false => self.is_set(),
+            };
+            if taken {
+                return true;
+            }
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return false;
+                    }
+                    Some(duration_to_timespec(left))
+                }
+                None => None,
+            };
+            match unsafe { sys::wait(&self.state, 0, timeout) } {
+                Ok(()) | Err(Errno::EAGAIN | Errno::EINTR | Errno::ETIMEDOUT) => {}
+                Err(e) => debug_assert!(false, "{}", io::Error::from(e)),
+            }
+        }
+    }
+}
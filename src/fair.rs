@@ -0,0 +1,190 @@
+//! FIFO hand-off for mutexes created with [`SharedMutexOptions::fair`].
+//!
+//! The PI futex wakes waiters by priority and lets a fresh locker barge past sleeping
+//! ones, so a process that locks rarely can wait indefinitely under load. A fair mutex
+//! puts a ticket queue in front of the futex:
+//!
+//! 1. An acquirer takes `ticket = next_ticket++`.
+//! 2. It waits until `turn` is `(ticket, 0)`, then claims the turn by CASing it to
+//!    `(ticket, tid)`. Only then does it take the PI futex, which is uncontended apart
+//!    from processes attaching to the segment.
+//! 3. Unlocking releases the futex and stores `(ticket + 1, 0)`.
+//!
+//! Every change of `turn` bumps `seq`, which waiters sleep on with `FUTEX_WAIT`. Nothing
+//! in the kernel knows about tickets, so waiters wake every [`CHECK_INTERVAL`] to look
+//! for turns that can't make progress:
+//!
+//! - A claimed turn whose TID no longer exists died holding it (possibly holding the
+//!   futex too, which then reports owner-died to the next owner as usual). The turn is
+//!   passed on by CASing `(ticket, tid)` to `(ticket + 1, 0)`.
+//! - A turn nobody claims for [`UNCLAIMED_GRACE`] belongs to a waiter that died before
+//!   its turn came, and is skipped the same way. Claiming and skipping CAS the same word,
+//!   so exactly one of them wins. A waiter that was merely slow sees its ticket passed
+//!   and queues again at the back.
+//!
+//! A TID reused by a new thread in time keeps a dead claimant's turn alive until that
+//! thread exits too; PID reuse is slow enough on Linux that this isn't handled further.
+//!
+//! [`SharedMutexOptions::fair`]: crate::SharedMutexOptions::fair
+
+use std::{
+    io,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use libc::pid_t;
+use nix::errno::Errno;
+
+use crate::futex::{duration_to_timespec, sys, tid};
+
+/// How often a waiter re-checks the current turn while sleeping.
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(10);
+/// How long a turn may go unclaimed before its ticket is considered abandoned.
+pub const UNCLAIMED_GRACE: Duration = Duration::from_millis(100);
+
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct FairQueue {
+    /// Set once when the segment is initialized
+    pub(crate) enabled: AtomicU32,
+    next_ticket: AtomicU32,
+    /// Ticket being served in the high half, TID that claimed it (or 0) in the low half
+    turn: AtomicU64,
+    seq: AtomicU32,
+}
+
+fn pack(ticket: u32, tid: u32) -> u64 {
+    (u64::from(ticket) << 32) | u64::from(tid)
+}
+
+fn unpack(turn: u64) -> (u32, u32) {
+    ((turn >> 32) as u32, turn as u32)
+}
+
+fn is_dead(tid: u32) -> bool {
+    (unsafe { libc::kill(tid as pid_t, 0) }) == -1 && Errno::last() == Errno::ESRCH
+}
+
+impl FairQueue {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed) != 0
+    }
+
+    /// Blocks until this thread owns the current turn. Gives up at `deadline`, leaving its
+    /// ticket to be skipped as unclaimed.
+    pub(crate) fn wait_turn(&self, deadline: Option<Instant>) -> bool {
+        let me = tid() as u32;
+        let mut ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        // when the current turn was first seen unclaimed
+        let mut unclaimed_since: Option<(u32, Instant)> = None;
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            let turn = self.turn.load(Ordering::Acquire);
+            let (serving, claimant) = unpack(turn);
+
+            if serving == ticket && claimant == 0 {
+                if self.claim(turn, me) {
+                    return true;
+                }
+                continue;
+            }
+            if (serving.wrapping_sub(ticket) as i32) > 0 {
+                // skipped while we weren't looking
+                ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+                unclaimed_since = None;
+                continue;
+            }
+
+            let stuck = match claimant {
+                0 => match unclaimed_since {
+                    Some((since_ticket, since)) if since_ticket == serving => {
+                        since.elapsed() >= UNCLAIMED_GRACE
+                    }
+                    _ => {
+                        unclaimed_since = Some((serving, Instant::now()));
+                        false
+                    }
+                },
+                claimant => is_dead(claimant),
+            };
+            if stuck && self.cas_turn(turn, pack(serving.wrapping_add(1), 0)) {
+                continue;
+            }
+
+            let mut timeout = CHECK_INTERVAL;
+            if let Some(deadline) = deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return false;
+                }
+                timeout = timeout.min(left);
+            }
+            let timeout = duration_to_timespec(timeout);
+            let _ = unsafe { sys::wait(&self.seq, seq, Some(timeout)) };
+        }
+    }
+
+    /// Takes the turn only if nobody is queued. Can't fail for any reason but contention.
+    pub(crate) fn try_take_turn(&self) -> bool {
+        let me = tid() as u32;
+        let ticket = self.next_ticket.load(Ordering::Relaxed);
+        if self.turn.load(Ordering::Acquire) != pack(ticket, 0) {
+            return false;
+        }
+        if self
+            .next_ticket
+            .compare_exchange(
+                ticket,
+                ticket.wrapping_add(1),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return false;
+        }
+        // our ticket is being served and unclaimed, and nothing skips a turn this fresh
+        self.claim(pack(ticket, 0), me)
+    }
+
+    /// Hands the turn to the next ticket. Must be called by the thread that owns the turn,
+    /// after releasing the futex.
+    pub(crate) fn pass_turn(&self) {
+        let (serving, _) = unpack(self.turn.load(Ordering::Relaxed));
+        self.turn
+            .store(pack(serving.wrapping_add(1), 0), Ordering::Release);
+        self.bump_seq();
+    }
+
+    /// Nobody waits for a turn to be claimed, so unlike passing it this wakes no one.
+    fn claim(&self, unclaimed: u64, me: u32) -> bool {
+        let (ticket, _) = unpack(unclaimed);
+        self.turn
+            .compare_exchange(
+                unclaimed,
+                pack(ticket, me),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    fn cas_turn(&self, current: u64, new: u64) -> bool {
+        let swapped = self
+            .turn
+            .compare_exchange(current, new, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok();
+        if swapped {
+            self.bump_seq();
+        }
+        swapped
+    }
+
+    fn bump_seq(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        if let Err(e) = unsafe { sys::wake(&self.seq, i32::MAX) } {
+            debug_assert!(false, "{}", io::Error::from(e));
+        }
+    }
+}
@@ -3,7 +3,14 @@
 //! Public API: [`PiMutex`] and [`PiCondvar`].  Everything else is private
 //! glue that stays close to the original C++ implementation.
 
-use std::{cell::OnceCell, io, mem::offset_of, ptr, sync::atomic::AtomicU32, time::Duration};
+use std::{
+    cell::UnsafeCell,
+    io,
+    mem::offset_of,
+    ptr,
+    sync::atomic::{AtomicI32, AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
 
 #[cfg(feature = "tsan")]
 use std::mem::MaybeUninit;
@@ -14,23 +21,14 @@ use nix::errno::Errno;
 // ---- kernel constants --------------------------------------------------------------------
 pub const FUTEX_LOCK_PI: c_int = libc::FUTEX_LOCK_PI;
 pub const FUTEX_UNLOCK_PI: c_int = libc::FUTEX_UNLOCK_PI;
+pub const FUTEX_TRYLOCK_PI: c_int = libc::FUTEX_TRYLOCK_PI;
 pub const FUTEX_WAIT_REQUEUE_PI: c_int = libc::FUTEX_WAIT_REQUEUE_PI;
 pub const FUTEX_CMP_REQUEUE_PI: c_int = libc::FUTEX_CMP_REQUEUE_PI;
 
 pub const FUTEX_OWNER_DIED: u32 = libc::FUTEX_OWNER_DIED;
 pub const FUTEX_TID_MASK: u32 = libc::FUTEX_TID_MASK;
 
-/// Minimal robust‑list structs (kernel ABI); see linux/futex.h.
-#[repr(C)]
-pub struct RobustList {
-    pub next: *mut RobustList,
-}
-#[repr(C)]
-pub struct RobustListHead {
-    pub list: RobustList,
-    pub futex_offset: isize,
-    pub list_op_pending: *mut RobustList,
-}
+pub use crate::robust_list::{RobustList, RobustListHead};
 
 // ---- C‑layout control blocks --------------------------------------------------------------
 #[repr(C)]
@@ -63,45 +61,179 @@ pub type AosCondition = AtomicU32;
 
 thread_local! {
     static MY_TID: std::cell::Cell<pid_t> = const { std::cell::Cell::new(0) };
-    static ROBUST: OnceCell<RobustListHead> = const { OnceCell::new() };
+    // The kernel keeps a pointer to this head, so it must never move once registered.
+    static ROBUST: UnsafeCell<RobustListHead> = const {
+        UnsafeCell::new(RobustListHead {
+            list: RobustList {
+                next: ptr::null_mut(),
+            },
+            futex_offset: 0,
+            list_op_pending: ptr::null_mut(),
+        })
+    };
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Syscalls issued by this thread, so tests can assert a path stays in user space.
+    pub(crate) static SYSCALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Makes this thread's `set_robust_list` fail with the given errno, to exercise the
+    /// fallback in environments that do support it.
+    pub(crate) static FAIL_SET_ROBUST_LIST: std::cell::Cell<Option<c_int>> =
+        const { std::cell::Cell::new(None) };
+    /// Makes this thread's next `FUTEX_LOCK_PI` and `FUTEX_TRYLOCK_PI` calls fail with `.0` without entering the
+    /// kernel, `.1` times over, to exercise the retry loops.
+    pub(crate) static FAIL_LOCK_PI: std::cell::Cell<(Errno, u32)> =
+        const { std::cell::Cell::new((Errno::UnknownErrno, 0)) };
+    /// Makes this thread's next `robust_add` or `robust_remove` panic halfway through.
+    pub(crate) static PANIC_IN_ROBUST_OP: std::cell::Cell<bool> =
+        const { std::cell::Cell::new(false) };
+}
+
+/// The calling thread's `list_op_pending`.
+#[cfg(test)]
+pub(crate) fn list_op_pending() -> *mut RobustList {
+    ROBUST.with(|cell| unsafe { (*cell.get()).list_op_pending })
+}
+
+#[cfg(test)]
+fn maybe_panic_in_robust_op() {
+    if PANIC_IN_ROBUST_OP.replace(false) {
+        panic!("injected panic in a robust list operation");
+    }
+}
+
+/// errno of the first failed `set_robust_list` in this process, 0 if none has failed
+static ROBUST_LIST_ERROR: AtomicI32 = AtomicI32::new(0);
+
+#[inline]
+fn count_syscall() {
+    #[cfg(test)]
+    SYSCALLS.with(|c| c.set(c.get() + 1));
 }
 
 #[inline]
 fn gettid() -> pid_t {
+    count_syscall();
     unsafe { libc::syscall(libc::SYS_gettid) as pid_t }
 }
 
 fn ensure_registered(offset: isize) {
     ROBUST.with(|cell| {
-        cell.get_or_init(|| {
-            let mut head = RobustListHead {
-                list: RobustList {
-                    next: ptr::null_mut(),
-                },
-                futex_offset: offset,
-                list_op_pending: ptr::null_mut(),
-            };
-            let sentinel = &mut head.list as *mut _;
-            head.list.next = sentinel;
-
-            let r = unsafe {
-                libc::syscall(
-                    libc::SYS_set_robust_list,
-                    &head.list as *const _,
-                    std::mem::size_of::<RobustListHead>(),
-                )
-            };
-            debug_assert_eq!(
-                r,
-                0,
-                "set_robust_list failed: {}",
-                io::Error::last_os_error()
+        let head = cell.get();
+        unsafe {
+            if !(*head).list.next.is_null() {
+                return;
+            }
+            (*head).futex_offset = offset;
+            (*head).list.next = (*head).head_value();
+
+            count_syscall();
+            let r = libc::syscall(
+                libc::SYS_set_robust_list,
+                head as *const RobustListHead,
+                std::mem::size_of::<RobustListHead>(),
             );
-            head
-        });
+            #[cfg(test)]
+            let r = match FAIL_SET_ROBUST_LIST.get() {
+                Some(errno) => {
+                    Errno::from_raw(errno).set();
+                    -1
+                }
+                None => r,
+            };
+            if r != 0 {
+                // e.g. ENOSYS or EPERM under seccomp. Locking still works, but a thread
+                // dying with a lock held leaves it held forever instead of poisoned.
+                let errno = Errno::last_raw();
+                if ROBUST_LIST_ERROR
+                    .compare_exchange(0, errno, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    #[cfg(feature = "metrics")]
+                    crate::metrics::emit(crate::metrics::LockEvent::RobustListUnavailable {
+                        errno,
+                    });
+                }
+            }
+        }
     });
 }
 
+/// Why the kernel refused to register a robust list, if it did for any thread of this
+/// process so far. Such threads keep locking, but without owner-died recovery: a lock
+/// they hold when they die stays held. Reported once through the metrics sink as well.
+pub fn robust_list_error() -> Option<io::Error> {
+    match ROBUST_LIST_ERROR.load(Ordering::Relaxed) {
+        0 => None,
+        errno => Some(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+/// Whether the kernel has the calling thread's robust list registered, read back with
+/// `get_robust_list`, so that locks the thread holds when it dies are recovered. Registers
+/// it first if needed, like [`register_current_thread`]. `false` if the kernel refused,
+/// see [`robust_list_error`], or if something else registered a head of its own since.
+/// For health checks that would rather fail at startup than run without recovery.
+pub fn robust_list_active() -> bool {
+    tid();
+    ROBUST.with(|cell| {
+        let mut head: *mut RobustListHead = ptr::null_mut();
+        let mut len: libc::size_t = 0;
+        count_syscall();
+        // pid 0 is the calling thread
+        let r = unsafe {
+            libc::syscall(
+                libc::SYS_get_robust_list,
+                0,
+                &mut head as *mut *mut RobustListHead,
+                &mut len as *mut libc::size_t,
+            )
+        };
+        r == 0 && head == cell.get() && len == std::mem::size_of::<RobustListHead>()
+    })
+}
+
+/// Diagnostic only: the calling thread's robust list as the kernel would walk it when the
+/// thread dies, newest lock first. Each entry is a node and the futex word it stands for,
+/// found through the head's `futex_offset` like the kernel does. Meant for debugging
+/// owner-died recovery, where the list and what the thread actually holds disagree.
+/// Stops after the kernel's own limit of `ROBUST_LIST_LIMIT` entries, in case of a cycle.
+pub fn debug_robust_list() -> Vec<(*mut RobustList, u32)> {
+    // from kernel/futex/core.c
+    const ROBUST_LIST_LIMIT: usize = 2048;
+    ROBUST.with(|cell| {
+        let head = cell.get();
+        let mut nodes = Vec::new();
+        unsafe {
+            let sentinel = (*head).head_value();
+            let mut node = (*head).list.next;
+            while !node.is_null() && node != sentinel && nodes.len() < ROBUST_LIST_LIMIT {
+                let word = node
+                    .cast::<u8>()
+                    .offset((*head).futex_offset)
+                    .cast::<AtomicU32>();
+                nodes.push((node, (*word).load(Ordering::Relaxed)));
+                node = (*node).next;
+            }
+        }
+        nodes
+    })
+}
+
+/// Registers the calling thread's robust list with the kernel up front.
+///
+/// Otherwise this happens on the thread's first lock, which costs a couple of syscalls
+/// inside the acquisition. Realtime threads can call this during setup so even their
+/// first uncontended lock stays in user space. Calling it again is a no-op.
+pub fn register_current_thread() {
+    tid();
+}
+
 pub fn tid() -> pid_t {
     use std::sync::Once;
     static ONCE: Once = Once::new();
@@ -120,6 +252,14 @@ pub fn tid() -> pid_t {
 
     unsafe extern "C" fn atfork_child() {
         MY_TID.with(|t| t.set(0));
+        // fork() drops the kernel's registration of the list (glibc re-registers its own
+        // head, not ours), and what's on it is the parent's. Starting over empty makes the
+        // child's first lock register its own head, so its locks recover if it dies.
+        ROBUST.with(|cell| unsafe {
+            let head = cell.get();
+            (*head).list.next = ptr::null_mut();
+            (*head).list_op_pending = ptr::null_mut();
+        });
     }
     ONCE.call_once(|| unsafe {
         libc::pthread_atfork(None, None, Some(atfork_child));
@@ -128,6 +268,16 @@ pub fn tid() -> pid_t {
     id
 }
 
+/// Whether thread `tid` of this process is asleep, as `/proc` reports it. For tests that
+/// have to wait until others block in the kernel.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) fn is_sleeping(tid: pid_t) -> io::Result<bool> {
+    let stat = std::fs::read_to_string(format!("/proc/self/task/{tid}/stat"))?;
+    // the command name in parentheses may contain anything, the state follows it
+    let state = stat.rsplit_once(") ").map(|(_, rest)| rest);
+    Ok(state.is_some_and(|state| state.starts_with('S')))
+}
+
 // ---- raw futex syscall --------------------------------------------------------------------
 unsafe fn futex_raw(
     uaddr: *const u32,
@@ -137,6 +287,7 @@ unsafe fn futex_raw(
     uaddr2: *const u32,
     val3: c_int,
 ) -> nix::Result<c_long> {
+    count_syscall();
     let ret = unsafe { libc::syscall(libc::SYS_futex, uaddr, op, val, val2, uaddr2, val3) };
     if ret == -1 {
         Err(Errno::last())
@@ -149,20 +300,56 @@ unsafe fn futex_raw(
 pub mod sys {
     use super::*;
 
+    /// # Safety
+    ///
+    /// `addr` must be a PI futex word: 0 or the owner TID plus kernel flag bits.
     #[inline]
     pub unsafe fn lock_pi(addr: &AtomicU32, timeout: Option<timespec>) -> nix::Result<()> {
+        #[cfg(test)]
+        if let (errno, times @ 1..) = FAIL_LOCK_PI.get() {
+            FAIL_LOCK_PI.set((errno, times - 1));
+            return Err(errno);
+        }
         unsafe {
             futex_raw(
                 addr as *const _ as *const u32,
                 FUTEX_LOCK_PI,
                 1,
-                timeout.map(|t| &t as *const _ as usize).unwrap_or(0),
+                timeout.as_ref().map_or(0, |t| t as *const _ as usize),
+                ptr::null(),
+                0,
+            )
+        }
+        .map(|_| ())
+    }
+    /// Like [`lock_pi`], but fails with `EAGAIN` instead of waiting if the lock is held.
+    /// Takes over a dead owner's lock all the same.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be a PI futex word: 0 or the owner TID plus kernel flag bits.
+    #[inline]
+    pub unsafe fn trylock_pi(addr: &AtomicU32) -> nix::Result<()> {
+        #[cfg(test)]
+        if let (errno, times @ 1..) = FAIL_LOCK_PI.get() {
+            FAIL_LOCK_PI.set((errno, times - 1));
+            return Err(errno);
+        }
+        unsafe {
+            futex_raw(
+                addr as *const _ as *const u32,
+                FUTEX_TRYLOCK_PI,
+                0,
+                0,
                 ptr::null(),
                 0,
             )
         }
         .map(|_| ())
     }
+    /// # Safety
+    ///
+    /// `addr` must be a PI futex word: 0 or the owner TID plus kernel flag bits.
     #[inline]
     pub unsafe fn unlock_pi(addr: &AtomicU32) -> nix::Result<()> {
         unsafe {
@@ -177,6 +364,9 @@ pub mod sys {
         }
         .map(|_| ())
     }
+    /// # Safety
+    ///
+    /// `mtx` must be a PI futex word and every waiter on `cvar` must requeue onto it.
     #[inline]
     pub unsafe fn wait_requeue_pi(
         cvar: &AosCondition,
@@ -189,13 +379,18 @@ pub mod sys {
                 cvar as *const _ as *const u32,
                 FUTEX_WAIT_REQUEUE_PI,
                 start as _,
-                timeout.map(|t| &t as *const _ as usize).unwrap_or(0),
+                timeout.as_ref().map_or(0, |t| t as *const _ as usize),
                 mtx as *const _ as *const u32,
                 0,
             )
         }
         .map(|_| ())
     }
+    /// Returns how many waiters were woken or requeued, together.
+    ///
+    /// # Safety
+    ///
+    /// `mtx` must be the PI futex word the waiters on `cvar` passed to `wait_requeue_pi`.
     #[inline]
     pub unsafe fn cmp_requeue_pi(
         cvar: &AosCondition,
@@ -203,7 +398,7 @@ pub mod sys {
         requeue: i32,
         mtx: &AtomicU32,
         expected: u32,
-    ) -> nix::Result<()> {
+    ) -> nix::Result<i32> {
         unsafe {
             futex_raw(
                 cvar as *const _ as *const u32,
@@ -214,8 +409,11 @@ pub mod sys {
                 expected as _,
             )
         }
-        .map(|_| ())
+        .map(|v| v as i32)
     }
+    /// # Safety
+    ///
+    /// `addr` must be a plain (non‑PI) futex word.
     #[inline]
     pub unsafe fn wait(addr: &AtomicU32, val: u32, timeout: Option<timespec>) -> nix::Result<()> {
         unsafe {
@@ -223,13 +421,16 @@ pub mod sys {
                 addr as *const _ as *const u32,
                 libc::FUTEX_WAIT,
                 val as _,
-                timeout.map(|t| &t as *const _ as usize).unwrap_or(0),
+                timeout.as_ref().map_or(0, |t| t as *const _ as usize),
                 ptr::null(),
                 0,
             )
         }
         .map(|_| ())
     }
+    /// # Safety
+    ///
+    /// `addr` must be a plain (non‑PI) futex word.
     #[inline]
     pub unsafe fn wake(addr: &AtomicU32, n: i32) -> nix::Result<i32> {
         unsafe {
@@ -247,11 +448,102 @@ pub mod sys {
 }
 
 // ---- tiny helpers reused by safe layer -----------------------------------------------------
+/// `FUTEX_LOCK_PI` takes an absolute `CLOCK_REALTIME` time. Converts `deadline` to one,
+/// so retries after `EINTR` can pass the same value without extending the wait.
+pub fn realtime_deadline(deadline: Instant) -> timespec {
+    let mut now = timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut now) };
+    let now = Duration::new(now.tv_sec as u64, now.tv_nsec as u32);
+    duration_to_timespec(now.saturating_add(deadline.saturating_duration_since(Instant::now())))
+}
+
+/// `deadline` as an absolute `CLOCK_MONOTONIC` time, for `FUTEX_WAIT_REQUEUE_PI`.
+pub fn monotonic_deadline(deadline: Instant) -> timespec {
+    let now = Duration::from_nanos(monotonic_ns());
+    duration_to_timespec(now.saturating_add(deadline.saturating_duration_since(Instant::now())))
+}
+
+/// `CLOCK_MONOTONIC` in nanoseconds. It's the same clock in every process on the machine,
+/// so unlike [`Instant`] it can be stored in shared memory and compared by another process.
+pub(crate) fn monotonic_ns() -> u64 {
+    let mut ts = timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    let now = Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32);
+    now.as_nanos() as u64
+}
+
+/// `d` as a `timespec`, or the latest one `time_t` can hold if `d` is longer, rather than
+/// wrapping around to a time in the past. A 32-bit `time_t` ends in 2038, so there a
+/// deadline a few decades out, like one for `Duration::MAX`, needs this to stay far off.
 #[inline]
 pub fn duration_to_timespec(d: Duration) -> timespec {
-    timespec {
-        tv_sec: d.as_secs() as _,
-        tv_nsec: d.subsec_nanos() as _,
+    match libc::time_t::try_from(d.as_secs()) {
+        Ok(secs) => timespec {
+            tv_sec: secs,
+            tv_nsec: d.subsec_nanos() as _,
+        },
+        Err(_) => timespec {
+            tv_sec: libc::time_t::MAX,
+            tv_nsec: 999_999_999,
+        },
+    }
+}
+
+// `previous` is only ours, the kernel walks `next` alone
+const _: () = assert!(
+    std::mem::offset_of!(AosMutex, previous)
+        == std::mem::offset_of!(AosMutex, next) + size_of::<usize>()
+);
+
+// the size `PiMutex::from_raw` documents for C users
+#[cfg(not(feature = "tsan"))]
+const _: () = assert!(size_of::<AosMutex>() == 3 * size_of::<usize>());
+
+/// The `previous` field of the `AosMutex` whose `next` is `node`, pointing at the node
+/// before it in the list, or at the head's `list` for the first node.
+///
+/// Safety: `node` must be the `next` field of an `AosMutex`.
+unsafe fn previous_of(node: *mut RobustList) -> *mut *mut RobustList {
+    unsafe { node.cast::<usize>().add(1).cast() }
+}
+
+/// Announces an operation on a node in `list_op_pending` until dropped, so the kernel
+/// still finds the lock if the thread dies halfway through, when the list may not have it.
+/// Dropping clears it whether the operation finished or unwound, since a live thread
+/// that's left it set would have the kernel act on a lock at exit that may since have
+/// been released, or unmapped and its memory reused.
+struct PendingOp {
+    head: *mut RobustListHead,
+    /// Set by [`robust_add`] to link the node in on drop
+    publish: *mut RobustList,
+}
+
+impl PendingOp {
+    unsafe fn new(head: *mut RobustListHead, node: *mut RobustList) -> Self {
+        unsafe { (*head).list_op_pending = node };
+        std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+        Self {
+            head,
+            publish: ptr::null_mut(),
+        }
+    }
+}
+
+impl Drop for PendingOp {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.publish.is_null() {
+                (*self.head).list.next = self.publish;
+                std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+            }
+            (*self.head).list_op_pending = ptr::null_mut();
+        }
     }
 }
 
@@ -261,31 +553,43 @@ pub fn duration_to_timespec(d: Duration) -> timespec {
 pub(crate) unsafe fn robust_add(next_ptr: *mut RobustList) {
     // head is guaranteed to be initialised by tid()
     ROBUST.with(|cell| unsafe {
-        let head = cell.get().unwrap() as *const _ as *mut RobustListHead;
-        (*next_ptr).next = (*head).list.next;
+        let head = cell.get();
+        let mut pending = PendingOp::new(head, next_ptr);
+        let sentinel = (*head).head_value();
+        let first = (*head).list.next;
+        (*next_ptr).next = first;
+        *previous_of(next_ptr) = sentinel;
+        if !first.is_null() && first != sentinel {
+            *previous_of(first) = next_ptr;
+        }
         std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
-        (*head).list.next = next_ptr;
+        // the lock is held either way, so the node goes in even if this unwinds
+        pending.publish = next_ptr;
+        #[cfg(test)]
+        maybe_panic_in_robust_op();
     });
 }
 
-/// Unlink `next_ptr` from the thread's robust list (O(N) walk, list is tiny).
+/// Unlink `next_ptr` from the thread's robust list in O(1), through its `previous`.
 ///
-/// Safety: caller must hold the mutex that owns `next_ptr`.
+/// Safety: caller must hold the mutex that owns `next_ptr`, and have added it with
+/// [`robust_add`] on this thread.
 pub(crate) unsafe fn robust_remove(next_ptr: *mut RobustList) {
     ROBUST.with(|cell| {
-        let head = cell.get().unwrap() as *const _ as *mut RobustListHead;
+        let head = cell.get();
         unsafe {
-            let mut prev = &mut (*head).list as *mut RobustList;
-            let mut cur = (*prev).next;
-            // TODO: review this null check
-            while !cur.is_null() && cur != &(*head).list as *const _ as *mut RobustList {
-                if cur == next_ptr {
-                    (*prev).next = (*cur).next;
-                    break;
-                }
-                prev = cur;
-                cur = (*cur).next;
+            let _pending = PendingOp::new(head, next_ptr);
+            // unwinding from here leaves the node in, the lock is still held
+            #[cfg(test)]
+            maybe_panic_in_robust_op();
+            let sentinel = (*head).head_value();
+            let previous = *previous_of(next_ptr);
+            let next = (*next_ptr).next;
+            if !next.is_null() && next != sentinel {
+                *previous_of(next) = previous;
             }
+            // the kernel may walk the list at any point, it stays intact either way
+            (*previous).next = next;
         }
     });
 }
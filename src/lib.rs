@@ -1,11 +1,70 @@
+mod array;
+#[cfg(feature = "async")]
+mod async_lock;
+mod barrier;
+mod bits;
+mod buffer;
+#[cfg(feature = "capi")]
+pub mod capi;
+mod condvar;
+mod counter;
+mod event;
+mod fair;
+mod lock_free;
+#[cfg(feature = "debug_lockorder")]
+mod lockorder;
+mod many;
+mod metrics;
 mod mutex;
+mod options;
 pub mod futex;
+mod pod;
+mod poison;
 mod shared_data;
+mod queue;
+mod rate_limit;
 mod robust_list;
+mod rwlock;
 mod shared_mem;
 #[cfg(test)]
 mod test;
+#[cfg(feature = "testing")]
+mod testing;
+mod weak;
 
-pub use shared_data::SharedMutex;
+pub use array::SharedMutexArray;
+#[cfg(feature = "async")]
+pub use async_lock::{AsyncSharedGuard, LockFuture};
+pub use barrier::{BarrierBroken, BarrierWaitResult, SharedBarrier, SharedBarrierInner};
+pub use bits::IntOps;
+pub use buffer::SharedBuffer;
+pub use condvar::{PiCondvar, PiCondvarGroup, WaitTimeoutResult};
+pub use counter::{CounterOverflow, SharedCounter};
+pub use event::{ResetMode, SharedEvent, SharedEventInner};
+pub use lock_free::{LockFree, SharedAtomic};
+pub use many::{LockManyPoisoned, lock_many};
+#[cfg(feature = "metrics")]
+pub use metrics::{LockEvent, set_metrics_sink};
+pub use metrics::{LockMetrics, MetricsSnapshot, export_prometheus, read_metrics};
+pub use mutex::{CeilingGuard, PiMutex, PiMutexGuard};
+pub use options::{LockConfig, SharedMutexOptions};
+pub use pod::Pod;
+pub use poison::PoisonedCopy;
+pub use queue::{Drain, Full, SharedQueue};
+pub use rate_limit::SharedRateLimiter;
+pub use rwlock::{
+    MAX_READERS, ReentrantReadGuard, ReentrantWriteGuard, SharedReadGuard, SharedReentrantRwLock,
+    SharedRwLock, SharedWriteGuard,
+};
+pub use shared_data::{
+    CHECKSUM_MISMATCH, CorruptData, LockError, LockResult, NAME_LEN, ReadGuard, SharedMutex,
+    TryLockFailure, TypeMismatch,
+};
+#[cfg(any(miri, feature = "mock_backend"))]
+pub use shared_mem::MockBackend;
 #[cfg(not(miri))]
 pub use shared_mem::unlink_if_exists;
+pub use shared_mem::{DirBackend, Mapping, MemoryBackend, ShmBackend};
+#[cfg(feature = "testing")]
+pub use testing::{__testing_priority_order, PriorityOrder};
+pub use weak::SharedMutexWeak;
@@ -0,0 +1,95 @@
+//! Atomics next to the mutex, for data that's cheaper to update without the lock.
+
+use std::{
+    ops::Deref,
+    sync::atomic::{
+        AtomicBool, AtomicI8, AtomicI16, AtomicI32, AtomicI64, AtomicIsize, AtomicU8, AtomicU16,
+        AtomicU32, AtomicU64, AtomicUsize,
+    },
+};
+
+use crate::{shared_data::SharedMutexInner, shared_mem::SharedMemorySafe};
+
+/// Types that can be shared between processes and updated through `&self` by any of them
+/// at once: the atomics, and arrays and structs made of nothing else.
+///
+/// # Safety
+///
+/// Implementors must be `#[repr(C)]` or primitive, have no drop glue, no pointers, and
+/// allow every mutation through `&self` to race with any other, i.e. contain only atomics.
+pub unsafe trait SharedAtomic: Sync {}
+
+macro_rules! impl_shared_atomic {
+    ($($t:ty),*) => {
+        $(unsafe impl SharedAtomic for $t {})*
+    };
+}
+
+impl_shared_atomic!(
+    AtomicBool,
+    AtomicU8,
+    AtomicU16,
+    AtomicU32,
+    AtomicU64,
+    AtomicUsize,
+    AtomicI8,
+    AtomicI16,
+    AtomicI32,
+    AtomicI64,
+    AtomicIsize
+);
+
+unsafe impl<T: SharedAtomic, const N: usize> SharedAtomic for [T; N] {}
+
+/// A payload of atomics that can also be used without the lock.
+///
+/// A payload has to be [`Copy`] so that nothing in it needs dropping and it can be placed
+/// into a segment bit for bit, which rules out the atomics themselves. `LockFree` lets
+/// them in anyway: `SharedMutex<LockFree<T>>` is a mutex whose value any process can
+/// also read and update through [`SharedMutexInner::lock_free`] without taking the lock,
+/// e.g. counters bumped on a hot path that the lock holder occasionally resets along with
+/// something else.
+///
+/// # Aliasing
+///
+/// Lock-free users only ever get a `&T`. That's sound next to a lock holder that also
+/// only reads through its guard, which is what calling the atomics' `&self` methods on a
+/// guard does. Taking `&mut` from the guard (`&mut *guard`, `DerefMut`) claims exclusive
+/// access that lock-free users in other threads and processes don't respect, which is why
+/// [`SharedMutexInner::lock_free`] is unsafe.
+/// The lock only orders the lock holders among themselves: a lock-free update can land
+/// between any two of the holder's operations, so a compound update that must look
+/// atomic to everyone has to be done by lock-free users with the lock too.
+#[repr(transparent)]
+#[derive(Debug, Default)]
+pub struct LockFree<T: SharedAtomic>(T);
+
+// only atomics, which have no drop glue either
+unsafe impl<T: SharedAtomic> SharedMemorySafe for LockFree<T> {}
+
+impl<T: SharedAtomic> LockFree<T> {
+    pub const fn new(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T: SharedAtomic> Deref for LockFree<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: SharedAtomic> SharedMutexInner<LockFree<T>> {
+    /// The value, without locking. Fine to use while anyone holds the lock, as long as
+    /// nobody takes a `&mut` to it.
+    ///
+    /// # Safety
+    ///
+    /// No guard of this mutex, in any thread or process, may be dereferenced mutably
+    /// (`&mut *guard`, `DerefMut`) while the returned reference is alive.
+    pub unsafe fn lock_free(&self) -> &T {
+        unsafe { &(*self.data.get()).0 }
+    }
+}
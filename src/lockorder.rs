@@ -0,0 +1,86 @@
+//! Lock order checking for the `debug_lockorder` feature.
+//!
+//! Every time a thread takes a lock while holding others, the pairs are recorded as
+//! "held before" edges in a process-wide graph. Blocking on a lock that can already reach
+//! one of the currently held locks in that graph means two code paths take the same locks
+//! in opposite orders, which deadlocks as soon as they run concurrently, so it panics
+//! before blocking. Locks are identified by the address of their futex word in this
+//! process, so this only sees orders taken within one process, and a segment mapped at
+//! the address of an earlier, unmapped one inherits its edges. It's a debugging aid, not
+//! a proof.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use crate::futex::tid;
+
+thread_local! {
+    static HELD: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// `a -> b` means `b` was taken while `a` was held
+static ORDER: Mutex<Option<HashMap<usize, HashSet<usize>>>> = Mutex::new(None);
+
+/// Call before blocking on `lock`; panics if that could complete a cycle.
+pub(crate) fn check(lock: *const u32) {
+    let lock = lock as usize;
+    HELD.with_borrow(|held| {
+        if held.contains(&lock) {
+            return;
+        }
+        let order = ORDER.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(order) = order.as_ref() else {
+            return;
+        };
+        for &outer in held {
+            if reaches(order, lock, outer) {
+                panic!(
+                    "lock order inversion on thread {}: locking {lock:#x} while holding \
+                     {outer:#x}, but {outer:#x} has been locked while holding {lock:#x}",
+                    tid(),
+                );
+            }
+        }
+    });
+}
+
+/// Call once `lock` is held.
+pub(crate) fn acquired(lock: *const u32) {
+    let lock = lock as usize;
+    HELD.with_borrow_mut(|held| {
+        if !held.is_empty() {
+            let mut order = ORDER.lock().unwrap_or_else(|e| e.into_inner());
+            let order = order.get_or_insert_with(HashMap::new);
+            for &outer in held.iter() {
+                order.entry(outer).or_default().insert(lock);
+            }
+        }
+        held.push(lock);
+    });
+}
+
+pub(crate) fn released(lock: *const u32) {
+    let lock = lock as usize;
+    HELD.with_borrow_mut(|held| {
+        if let Some(i) = held.iter().rposition(|&l| l == lock) {
+            held.remove(i);
+        }
+    });
+}
+
+fn reaches(order: &HashMap<usize, HashSet<usize>>, from: usize, to: usize) -> bool {
+    let mut seen = HashSet::new();
+    let mut stack = vec![from];
+    while let Some(lock) = stack.pop() {
+        if lock == to {
+            return true;
+        }
+        if seen.insert(lock) {
+            stack.extend(order.get(&lock).into_iter().flatten());
+        }
+    }
+    false
+}
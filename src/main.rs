@@ -5,7 +5,6 @@ type Sharedu64 = SharedMutex<u64>;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let _ = unlink_if_exists("test_counter");
 
     if args.len() > 1 && args[1] == "child" {
         child_process();
@@ -16,6 +15,7 @@ fn main() {
 
 fn parent_process() {
     println!("=== Parent Process ===");
+    let _ = unlink_if_exists("test_counter");
 
     let shared = unsafe { Sharedu64::new_with_val("test_counter", 0) };
     println!("Parent: Created shared counter");
@@ -38,16 +38,16 @@ fn parent_process() {
 
     child.wait().expect("Child process failed");
 
-    let guard = shared.lock().unwrap();
-    println!("Parent: Final value: {}", *guard);
+    let final_value = shared.into_inner().unwrap();
+    println!("Parent: Final value: {}", final_value);
 }
 
 fn child_process() {
     println!("  Child: Starting");
 
-    thread::sleep(Duration::from_millis(100));
-
-    let shared = unsafe { Sharedu64::from_name("test_counter") };
+    let wait = Some(Duration::from_secs(5));
+    let shared = unsafe { Sharedu64::attach_existing("test_counter", wait) }
+        .expect("the parent creates the counter");
     println!("  Child: Connected to shared counter");
 
     for i in 1..=5 {
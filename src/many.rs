@@ -0,0 +1,95 @@
+//! Taking several mutexes at once without deadlocking.
+//!
+//! Two lockers that each hold one mutex and wait for the other's deadlock, so
+//! [`lock_many`] always locks in one global order, whatever order it's given the mutexes
+//! in. The order has to be the same in every process, and mapping addresses aren't, so it
+//! goes by the name stored in each segment first and by address only between equal names.
+
+use crate::{
+    shared_data::{LockError, SharedGuard, SharedMutexInner},
+    shared_mem::SharedMemorySafe,
+};
+
+/// Locks all of `mutexes` and returns their guards in the order given. They're acquired
+/// in order of [`SharedMutexInner::name`], so any two calls, in this process or another,
+/// that share some of their mutexes can't deadlock on each other. Mutexes whose names
+/// only differ past [`NAME_LEN`] bytes are ordered by address, which is only consistent
+/// within one process.
+///
+/// Every mutex is locked even if some were poisoned; the `Err` says which.
+///
+/// # Panics
+///
+/// If the same mutex is passed twice, which would deadlock on itself, or if one of them
+/// can't be taken, see [`LockError::Failed`]. Signals don't interrupt the wait.
+///
+/// [`NAME_LEN`]: crate::NAME_LEN
+pub fn lock_many<'a, T: SharedMemorySafe>(
+    mutexes: &[&'a SharedMutexInner<T>],
+) -> Result<Vec<SharedGuard<'a, T>>, LockManyPoisoned<'a, T>> {
+    let mut order: Vec<usize> = (0..mutexes.len()).collect();
+    let key = |&i: &usize| (mutexes[i].name(), std::ptr::from_ref(mutexes[i]));
+    order.sort_unstable_by_key(key);
+    for pair in order.windows(2) {
+        assert!(
+            !std::ptr::eq(mutexes[pair[0]], mutexes[pair[1]]),
+            "lock_many was given `{}` twice",
+            mutexes[pair[0]].name(),
+        );
+    }
+
+    let mut guards: Vec<Option<SharedGuard<'a, T>>> = mutexes.iter().map(|_| None).collect();
+    let mut poisoned = Vec::new();
+    for i in order {
+        let guard = match mutexes[i].lock_uninterruptible() {
+            Ok(guard) => guard,
+            Err(LockError::Poisoned(guard)) => {
+                poisoned.push(i);
+                guard
+            }
+            Err(LockError::Failed(e)) => panic!("locking `{}`: {e}", mutexes[i].name()),
+        };
+        guards[i] = Some(guard);
+    }
+    let guards = guards.into_iter().map(Option::unwrap).collect();
+    match poisoned.is_empty() {
+        true => Ok(guards),
+        false => {
+            poisoned.sort_unstable();
+            Err(LockManyPoisoned { guards, poisoned })
+        }
+    }
+}
+
+/// Returned by [`lock_many`] when some of the mutexes were poisoned. All of them are
+/// locked regardless, so the damage can be repaired before carrying on.
+pub struct LockManyPoisoned<'a, T: SharedMemorySafe> {
+    guards: Vec<SharedGuard<'a, T>>,
+    poisoned: Vec<usize>,
+}
+
+impl<'a, T: SharedMemorySafe> LockManyPoisoned<'a, T> {
+    /// Indices into the slice given to [`lock_many`] of the poisoned mutexes, ascending.
+    pub fn poisoned(&self) -> &[usize] {
+        &self.poisoned
+    }
+
+    /// The guards of every mutex, in the order given to [`lock_many`].
+    pub fn into_guards(self) -> Vec<SharedGuard<'a, T>> {
+        self.guards
+    }
+}
+
+impl<T: SharedMemorySafe> std::fmt::Debug for LockManyPoisoned<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LockManyPoisoned")
+            .field("poisoned", &self.poisoned)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: SharedMemorySafe> std::fmt::Display for LockManyPoisoned<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "shared mutexes {:?} are poisoned", self.poisoned)
+    }
+}
@@ -0,0 +1,198 @@
+//! Lock statistics kept in the segment itself, so any process can read them.
+//!
+//! The counters sit right after the futex in [`SharedMutexInner`], ahead of the data, so
+//! their offset doesn't depend on `T` and they can be read knowing only a segment's name.
+//!
+//! With the `metrics` feature, [`set_metrics_sink`] additionally reports every acquisition
+//! and release in this process to a callback, for exporting to whatever metrics library
+//! the application uses.
+//!
+//! There's no `tracing` integration in the crate itself, to keep it free of dependencies
+//! beyond the platform. The sink is the place for one: it runs on the locking thread, so
+//! a sink that emits a `tracing` event per `LockEvent`, tagged with `gettid()`, lands
+//! inside whatever span the caller is in.
+
+#[cfg(feature = "metrics")]
+use std::sync::atomic::AtomicPtr;
+use std::{
+    fmt::Write,
+    io,
+    mem::{offset_of, size_of},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    mutex::Acquired,
+    shared_data::{Header, SharedMutexInner},
+    shared_mem,
+};
+
+#[repr(C)]
+#[derive(Default)]
+pub struct LockMetrics {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    wait_ns: AtomicU64,
+    poisoned: AtomicU64,
+}
+
+impl LockMetrics {
+    pub(crate) fn record(&self, acquired: &Acquired) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if let Some(waited) = acquired.waited {
+            self.contended.fetch_add(1, Ordering::Relaxed);
+            let waited = u64::try_from(waited.as_nanos()).unwrap_or(u64::MAX);
+            self.wait_ns.fetch_add(waited, Ordering::Relaxed);
+        }
+        if acquired.owner_died {
+            self.poisoned.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            wait: Duration::from_nanos(self.wait_ns.load(Ordering::Relaxed)),
+            poisoned: self.poisoned.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub acquisitions: u64,
+    /// Acquisitions that had to wait for another owner
+    pub contended: u64,
+    /// Total time spent waiting by contended acquisitions
+    pub wait: Duration,
+    /// Acquisitions that found the previous owner dead
+    pub poisoned: u64,
+}
+
+/// Reads the metrics of the existing segment `name` without knowing its `T`. Doesn't
+/// create the segment if it's missing.
+pub fn read_metrics(name: &str) -> io::Result<MetricsSnapshot> {
+    let offset = offset_of!(SharedMutexInner<u8>, metrics);
+    let memory = shared_mem::open_existing(name, offset + size_of::<LockMetrics>())?;
+    let header = unsafe { &*memory.pointer().cast::<Header>() };
+    if !header.is_current() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("`{name}` isn't a shared mutex of this version"),
+        ));
+    }
+    let metrics = unsafe {
+        &*memory
+            .pointer()
+            .cast::<u8>()
+            .add(offset)
+            .cast::<LockMetrics>()
+    };
+    Ok(metrics.snapshot())
+}
+
+/// Formats the metrics of every segment in `segments` as Prometheus exposition text,
+/// labeled by segment name. Segments that can't be opened are left out.
+pub fn export_prometheus(segments: &[&str]) -> String {
+    type Family = (&'static str, &'static str, fn(&MetricsSnapshot) -> String);
+    const FAMILIES: [Family; 4] = [
+        (
+            "shared_mutex_acquisitions_total",
+            "Lock acquisitions.",
+            |m| m.acquisitions.to_string(),
+        ),
+        (
+            "shared_mutex_contended_total",
+            "Acquisitions that had to wait for another owner.",
+            |m| m.contended.to_string(),
+        ),
+        (
+            "shared_mutex_wait_seconds_total",
+            "Time spent waiting for the lock.",
+            |m| m.wait.as_secs_f64().to_string(),
+        ),
+        (
+            "shared_mutex_poisoned_total",
+            "Acquisitions that found the previous owner dead.",
+            |m| m.poisoned.to_string(),
+        ),
+    ];
+
+    let snapshots: Vec<_> = segments
+        .iter()
+        .filter_map(|name| Some((escape_label(name), read_metrics(name).ok()?)))
+        .collect();
+
+    let mut out = String::new();
+    for (metric, help, value) in FAMILIES {
+        let _ = writeln!(out, "# HELP {metric} {help}");
+        let _ = writeln!(out, "# TYPE {metric} counter");
+        for (segment, snapshot) in &snapshots {
+            let _ = writeln!(out, "{metric}{{segment=\"{segment}\"}} {}", value(snapshot));
+        }
+    }
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// An acquisition or release seen by the sink set with [`set_metrics_sink`].
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockEvent {
+    Acquired {
+        /// Time spent waiting for another owner, zero if uncontended
+        waited: Duration,
+        contended: bool,
+        /// The previous owner died holding the lock
+        owner_died: bool,
+    },
+    Released,
+    /// The kernel refused to register a thread's robust list, see
+    /// [`robust_list_error`](crate::futex::robust_list_error). Sent once per process.
+    RobustListUnavailable {
+        errno: i32,
+    },
+}
+
+#[cfg(feature = "metrics")]
+impl From<Acquired> for LockEvent {
+    fn from(acquired: Acquired) -> Self {
+        LockEvent::Acquired {
+            waited: acquired.waited.unwrap_or_default(),
+            contended: acquired.waited.is_some(),
+            owner_died: acquired.owner_died,
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+static SINK: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Calls `sink` on the locking thread for every acquisition and release of any lock in
+/// this process, replacing the previous sink. `None` turns reporting off, which leaves a
+/// single branch per lock operation. `sink` runs while the lock is held, so keep it short
+/// and don't lock from it.
+#[cfg(feature = "metrics")]
+pub fn set_metrics_sink(sink: Option<fn(LockEvent)>) {
+    let sink = sink.map_or(std::ptr::null_mut(), |sink| sink as *mut ());
+    SINK.store(sink, Ordering::Release);
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn emit(event: LockEvent) {
+    let sink = SINK.load(Ordering::Acquire);
+    if sink.is_null() {
+        return;
+    }
+    // SAFETY: only ever set from a `fn(LockEvent)` in `set_metrics_sink`
+    let sink = unsafe { std::mem::transmute::<*mut (), fn(LockEvent)>(sink) };
+    sink(event);
+}
@@ -1,44 +1,181 @@
-use std::{io, sync::atomic::Ordering, time::Duration};
+use std::{
+    io,
+    marker::PhantomData,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
 
 use nix::errno::Errno;
 
+use libc::{pid_t, timespec};
+
 use crate::futex::{
-    self, AosMutex, FUTEX_OWNER_DIED, FUTEX_TID_MASK, RobustList, duration_to_timespec,
-    sys::{lock_pi, unlock_pi},
+    self, AosMutex, FUTEX_OWNER_DIED, FUTEX_TID_MASK, RobustList,
+    sys::{lock_pi, trylock_pi, unlock_pi},
     tid,
 };
+#[cfg(feature = "debug_lockorder")]
+use crate::lockorder;
+#[cfg(feature = "metrics")]
+use crate::metrics::{self, LockEvent};
 
+#[repr(transparent)]
 pub struct PiMutex(pub(crate) AosMutex);
 
+/// How an acquisition went.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Acquired {
+    /// The previous owner died while holding the lock
+    pub(crate) owner_died: bool,
+    /// Time spent waiting in the kernel, `None` if the lock was free
+    pub(crate) waited: Option<Duration>,
+}
+
+/// How many transient `FUTEX_LOCK_PI` failures one acquisition retries before returning
+/// the error, unless configured with [`SharedMutexOptions::max_lock_retries`].
+///
+/// [`SharedMutexOptions::max_lock_retries`]: crate::SharedMutexOptions::max_lock_retries
+pub(crate) const DEFAULT_MAX_RETRIES: u32 = 1000;
+
+impl Default for PiMutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PiMutex {
     pub fn new() -> Self {
         Self(AosMutex::default())
     }
 
+    /// Views an [`AosMutex`] placed in memory managed elsewhere, e.g. a segment laid out
+    /// by a C program, as a `PiMutex`.
+    ///
+    /// The layout is `#[repr(C)]`: a `u32` futex word followed by two pointer-sized
+    /// robust-list fields (`next`, `previous`), aligned like a pointer. That's 12 bytes on
+    /// 32-bit and 24 bytes on 64-bit targets. The `tsan` feature appends a
+    /// `pthread_mutex_t` and a `bool`, so reserve `size_of::<AosMutex>()` as built. All
+    /// zeroes is an unlocked mutex. The futex word is in the PI format: 0 when free,
+    /// otherwise the owner's TID plus the kernel's flag bits.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be non-null, aligned, point to such a layout that every user of the
+    /// memory treats only this way, and stay mapped at this address for `'a`.
+    pub unsafe fn from_raw<'a>(ptr: *mut AosMutex) -> &'a PiMutex {
+        unsafe { &*ptr.cast::<PiMutex>() }
+    }
+
+    /// Fails with [`io::ErrorKind::Deadlock`] if this thread already holds the lock.
     pub fn lock(&self) -> io::Result<PiMutexGuard<'_>> {
-        self.lock_inner(None, true).map(|_| PiMutexGuard(self))
+        self.lock_inner(None, true, DEFAULT_MAX_RETRIES)
+            .map(|_| PiMutexGuard(self))
     }
+    /// Gives up with [`io::ErrorKind::TimedOut`] after `d`. A `d` too long to represent
+    /// as a deadline waits like [`Self::lock`].
     pub fn lock_timeout(&self, d: Duration) -> io::Result<PiMutexGuard<'_>> {
-        self.lock_inner(Some(d), true).map(|_| PiMutexGuard(self))
+        // FUTEX_LOCK_PI wants an absolute CLOCK_REALTIME time, not the relative one
+        let deadline = Instant::now().checked_add(d).map(futex::realtime_deadline);
+        self.lock_inner(deadline, true, DEFAULT_MAX_RETRIES)
+            .map(|_| PiMutexGuard(self))
+    }
+    /// Like [`Self::lock`], but also raises the calling thread to `SCHED_FIFO` at `ceiling`
+    /// for as long as it holds the lock, the immediate priority ceiling protocol. Priority
+    /// inheritance only boosts an owner once a higher priority thread blocks on it; with a
+    /// ceiling at least as high as any locker's priority, no such thread gets to run while
+    /// the lock is held in the first place. A thread already at or above `ceiling` is left
+    /// alone. The previous policy and priority come back when the guard is dropped, after
+    /// unlocking.
+    ///
+    /// Fails with [`io::ErrorKind::PermissionDenied`] without `CAP_SYS_NICE` or a high
+    /// enough `RLIMIT_RTPRIO`, and with [`io::ErrorKind::InvalidInput`] if `ceiling` isn't a
+    /// `SCHED_FIFO` priority. Either way the lock isn't held afterwards.
+    pub fn lock_with_ceiling(&self, ceiling: i32) -> io::Result<CeilingGuard<'_>> {
+        let guard = self.lock()?;
+        let previous = raise_to_ceiling(ceiling)?;
+        // restoring is the ceiling guard's job now, after it unlocks
+        std::mem::forget(guard);
+        Ok(CeilingGuard {
+            mutex: self,
+            previous,
+            _not_send: PhantomData,
+        })
     }
     pub fn try_lock(&self) -> io::Result<Option<PiMutexGuard<'_>>> {
-        match lock_try(&self.0)? {
-            true => Ok(Some(PiMutexGuard(self))),
-            false => Ok(None),
-        }
+        Ok(lock_try(&self.0, DEFAULT_MAX_RETRIES)?.map(|_| PiMutexGuard(self)))
     }
     pub fn is_locked_by_me(&self) -> bool {
-        tid() as u32 & FUTEX_TID_MASK == self.0.futex.load(Ordering::Relaxed)
+        self.0.futex.load(Ordering::Relaxed) & FUTEX_TID_MASK == tid() as u32 & FUTEX_TID_MASK
     }
 
     pub fn is_locked(&self) -> bool {
         self.0.futex.load(Ordering::Relaxed) != 0
     }
 
+    /// TID of the thread currently holding the lock, if any. This is only a snapshot, the
+    /// owner may release right after it's read.
+    pub fn owner_tid(&self) -> Option<pid_t> {
+        match self.0.futex.load(Ordering::Relaxed) & FUTEX_TID_MASK {
+            0 => None,
+            tid => Some(tid as pid_t),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The calling thread must hold the lock.
     pub unsafe fn unlock(&self) {
+        // an error can only mean we weren't the owner, which the caller promised we were
+        let _ = unsafe { self.try_unlock() };
+    }
+
+    /// Like [`Self::unlock`], but returns what `FUTEX_UNLOCK_PI` reports, e.g. `EPERM` if
+    /// the calling thread doesn't hold the lock. That points at a guard dropped, or an
+    /// unlock made, on a thread other than the one that locked.
+    ///
+    /// # Safety
+    ///
+    /// If the calling thread holds the lock, as for [`Self::unlock`]. If it doesn't, this
+    /// changes nothing and returns the error.
+    pub unsafe fn try_unlock(&self) -> io::Result<()> {
+        if !self.is_locked_by_me() {
+            // the kernel checks the owner without touching the word, let it say what's wrong
+            return unsafe { unlock_pi(&self.0.futex) }.map_err(Into::into);
+        }
         let next_ptr = &self.0.next as *const _ as *mut RobustList;
         unsafe { futex::robust_remove(next_ptr) };
+        #[cfg(feature = "debug_lockorder")]
+        lockorder::released(self.0.futex.as_ptr());
+        #[cfg(feature = "metrics")]
+        metrics::emit(LockEvent::Released);
+
+        self.release()
+    }
 
+    /// Unlocks from a signal handler, e.g. to give up a lock on `SIGTERM` before exiting.
+    /// Unlike [`Self::unlock`] it skips the `debug_lockorder` and `metrics` bookkeeping,
+    /// which allocate and take locks, and does nothing but unlink the lock from the
+    /// thread's robust list, clear the futex word and, only if someone waits in the
+    /// kernel, make the `FUTEX_UNLOCK_PI` syscall that hands it to them. Syscalls are as
+    /// async-signal-safe as it gets; clearing the word alone would leave those waiters
+    /// asleep behind a lock the kernel still thinks is held. With `debug_lockorder` the
+    /// thread still counts as holding it afterwards.
+    ///
+    /// # Safety
+    ///
+    /// As for [`Self::unlock`]: the handler must run on the thread holding the lock, and
+    /// whatever guard or code would have unlocked it must not do so again. The signal
+    /// must also not interrupt this thread while it locks or unlocks another robust lock,
+    /// since the robust list isn't reentrant; block it around those or only raise it at
+    /// known points.
+    pub unsafe fn async_signal_safe_unlock(&self) -> io::Result<()> {
+        let next_ptr = &self.0.next as *const _ as *mut RobustList;
+        unsafe { futex::robust_remove(next_ptr) };
+        self.release()
+    }
+
+    /// The futex half of unlocking, after the robust list and bookkeeping are done.
+    fn release(&self) -> io::Result<()> {
         let me = tid() as u32;
         if self
             .0
@@ -46,12 +183,21 @@ impl PiMutex {
             .compare_exchange(me, 0, Ordering::Release, Ordering::Relaxed)
             .is_ok()
         {
-            return;
+            return Ok(());
         }
-        let _ = unsafe { unlock_pi(&self.0.futex) };
+        unsafe { unlock_pi(&self.0.futex) }.map_err(Into::into)
     }
 
-    pub(crate) fn lock_inner(&self, dur: Option<Duration>, signals_fail: bool) -> io::Result<()> {
+    /// `timeout` goes to `FUTEX_LOCK_PI` as is, see [`futex::realtime_deadline`]. Transient
+    /// failures are retried up to `max_retries` times, see [`lock_pi_retry`].
+    pub(crate) fn lock_inner(
+        &self,
+        timeout: Option<timespec>,
+        signals_fail: bool,
+        max_retries: u32,
+    ) -> io::Result<Acquired> {
+        #[cfg(feature = "debug_lockorder")]
+        lockorder::check(self.0.futex.as_ptr());
         let me = tid() as u32;
         if self
             .0
@@ -63,24 +209,40 @@ impl PiMutex {
                 let next_ptr = &self.0.next as *const _ as *mut RobustList;
                 futex::robust_add(next_ptr);
             }
-            return Ok(());
+            #[cfg(feature = "debug_lockorder")]
+            lockorder::acquired(self.0.futex.as_ptr());
+            let acquired = Acquired {
+                owner_died: false,
+                waited: None,
+            };
+            #[cfg(feature = "metrics")]
+            metrics::emit(acquired.into());
+            return Ok(acquired);
         }
 
-        let ts = dur.map(duration_to_timespec);
-        loop {
-            unsafe {
-                match lock_pi(&self.0.futex, ts) {
-                    Ok(_) => break,
-                    Err(Errno::EINTR) if !signals_fail => continue,
-                    Err(Errno::ETIMEDOUT) => {
-                        return Err(io::ErrorKind::TimedOut.into());
-                    }
-                    Err(e) => return Err(e.into()),
-                }
-            }
-        }
+        let start = Instant::now();
+        lock_pi_retry(&self.0.futex, timeout, signals_fail, max_retries)?;
+        let waited = start.elapsed();
+        let owner_died = unsafe { self.adopt() };
 
-        if self.0.futex.load(Ordering::Acquire) & FUTEX_OWNER_DIED != 0 {
+        let acquired = Acquired {
+            owner_died,
+            waited: Some(waited),
+        };
+        #[cfg(feature = "metrics")]
+        metrics::emit(acquired.into());
+        Ok(acquired)
+    }
+
+    /// Bookkeeping for a lock the kernel handed to this thread, by `FUTEX_LOCK_PI` or a
+    /// requeue. Returns whether the previous owner died.
+    ///
+    /// # Safety
+    ///
+    /// The calling thread must have just been made the owner by the kernel.
+    pub(crate) unsafe fn adopt(&self) -> bool {
+        let owner_died = self.0.futex.load(Ordering::Acquire) & FUTEX_OWNER_DIED != 0;
+        if owner_died {
             self.0.futex.fetch_and(!FUTEX_OWNER_DIED, Ordering::Relaxed);
         }
 
@@ -88,16 +250,17 @@ impl PiMutex {
             let next_ptr = &self.0.next as *const _ as *mut RobustList;
             futex::robust_add(next_ptr);
         }
-
-        Ok(())
+        #[cfg(feature = "debug_lockorder")]
+        lockorder::acquired(self.0.futex.as_ptr());
+        owner_died
     }
 }
 
-pub struct PiMutexGuard<'a>(&'a PiMutex);
+pub struct PiMutexGuard<'a>(pub(crate) &'a PiMutex);
 impl<'a> Drop for PiMutexGuard<'a> {
     fn drop(&mut self) {
         // ignore poisoning on unlock – release is best‑effort
-        let _ = unsafe { unlock_pi(&self.0.0.futex) };
+        unsafe { self.0.unlock() };
     }
 }
 
@@ -108,17 +271,178 @@ impl<'a> std::ops::Deref for PiMutexGuard<'a> {
     }
 }
 
-pub(crate) fn lock_try(m: &AosMutex) -> io::Result<bool> {
+/// Guard of [`PiMutex::lock_with_ceiling`]. Dropping it unlocks, then restores the
+/// thread's scheduling.
+pub struct CeilingGuard<'a> {
+    mutex: &'a PiMutex,
+    /// Policy and parameters to go back to, `None` if the thread wasn't raised
+    previous: Option<(libc::c_int, libc::sched_param)>,
+    // both the lock and the raised priority belong to the thread that took it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for CeilingGuard<'_> {
+    fn drop(&mut self) {
+        unsafe { self.mutex.unlock() };
+        if let Some((policy, param)) = self.previous {
+            // lowering our own priority is always permitted
+            unsafe { libc::sched_setscheduler(0, policy, &param) };
+        }
+    }
+}
+
+impl std::ops::Deref for CeilingGuard<'_> {
+    type Target = PiMutex;
+    fn deref(&self) -> &Self::Target {
+        self.mutex
+    }
+}
+
+/// Moves the calling thread to `SCHED_FIFO` at `ceiling` unless it's already realtime at
+/// that priority or above. Returns what to restore.
+fn raise_to_ceiling(ceiling: i32) -> io::Result<Option<(libc::c_int, libc::sched_param)>> {
+    // 0 is the calling thread for all of these on Linux
+    let policy = unsafe { libc::sched_getscheduler(0) };
+    let mut param = libc::sched_param { sched_priority: 0 };
+    if policy == -1 || unsafe { libc::sched_getparam(0, &mut param) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let realtime = matches!(policy, libc::SCHED_FIFO | libc::SCHED_RR);
+    if realtime && param.sched_priority >= ceiling {
+        return Ok(None);
+    }
+    let raised = libc::sched_param {
+        sched_priority: ceiling,
+    };
+    if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &raised) } == 0 {
+        return Ok(Some((policy, param)));
+    }
+    let e = io::Error::last_os_error();
+    Err(match e.raw_os_error() {
+        Some(libc::EPERM) => io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "a priority ceiling needs CAP_SYS_NICE or a high enough RLIMIT_RTPRIO",
+        ),
+        Some(libc::EINVAL) => io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{ceiling} isn't a SCHED_FIFO priority"),
+        ),
+        _ => e,
+    })
+}
+
+/// `None` if the lock is held elsewhere, otherwise whether the previous owner died.
+pub(crate) fn lock_try(m: &AosMutex, max_retries: u32) -> io::Result<Option<bool>> {
+    Ok(lock_try_observed(m, max_retries)?.ok())
+}
+
+/// Like [`lock_try`], but hands back the futex word that was in the way.
+pub(crate) fn lock_try_observed(m: &AosMutex, max_retries: u32) -> io::Result<Result<bool, u32>> {
     let me = tid() as u32;
-    match m
+    let owner_died = match m
         .futex
         .compare_exchange(0, me, Ordering::AcqRel, Ordering::Relaxed)
     {
-        Ok(_) => Ok(true),
+        Ok(_) => false,
         Err(v) if v & FUTEX_OWNER_DIED != 0 => {
-            unsafe { lock_pi(&m.futex, None)? };
-            Ok(true)
+            // the bit stays set after the kernel hands the lock to a waiter, until it
+            // clears it, so this may still be held
+            if !trylock_pi_fallback(&m.futex, max_retries)? {
+                return Ok(Err(v));
+            }
+            m.futex.fetch_and(!FUTEX_OWNER_DIED, Ordering::Relaxed);
+            true
+        }
+        Err(v) => return Ok(Err(v)),
+    };
+
+    unsafe {
+        let next_ptr = &m.next as *const _ as *mut RobustList;
+        futex::robust_add(next_ptr);
+    }
+    #[cfg(feature = "debug_lockorder")]
+    lockorder::acquired(m.futex.as_ptr());
+    #[cfg(feature = "metrics")]
+    metrics::emit(LockEvent::Acquired {
+        waited: Duration::ZERO,
+        contended: false,
+        owner_died,
+    });
+    Ok(Ok(owner_died))
+}
+
+/// `FUTEX_TRYLOCK_PI`, for taking over a dead owner's lock without waiting if it turns out
+/// someone else got there first. `false` if the lock is held. Kernels too old to know the
+/// operation get `FUTEX_LOCK_PI` with a deadline that has already passed instead.
+fn trylock_pi_fallback(futex: &AtomicU32, max_retries: u32) -> io::Result<bool> {
+    match unsafe { trylock_pi(futex) } {
+        Ok(()) => Ok(true),
+        Err(Errno::EAGAIN) => Ok(false),
+        Err(Errno::ENOSYS) => {
+            let expired = timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            };
+            match lock_pi_retry(futex, Some(expired), true, max_retries) {
+                Ok(()) => Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(false),
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `lock_pi` that retries on `EINTR` unless `signals_fail` is set, and on `EAGAIN`, which
+/// the kernel returns while the owner is exiting and its robust list not yet cleaned up.
+/// Both are transient, but a pathological owner or signal storm could keep them coming,
+/// so after `max_retries` of them the last one is returned rather than spinning forever.
+///
+/// `ESRCH` means the TID in the futex word doesn't exist, e.g. a long-lived segment whose
+/// owner went away without robust cleanup. That's handled like the owner dying: the word
+/// is swapped for a bare `FUTEX_OWNER_DIED`, which the kernel lets us take over. This is
+/// retried once; a second `ESRCH` is returned to the caller instead of looping.
+///
+/// `EDEADLK` is the kernel noticing the calling thread already owns the lock, and comes
+/// back as [`io::ErrorKind::Deadlock`] saying so.
+fn lock_pi_retry(
+    futex: &AtomicU32,
+    ts: Option<timespec>,
+    signals_fail: bool,
+    max_retries: u32,
+) -> io::Result<()> {
+    let mut cleared_stale_owner = false;
+    let mut retries = 0;
+    loop {
+        match unsafe { lock_pi(futex, ts) } {
+            Ok(_) => return Ok(()),
+            Err(e @ (Errno::EINTR | Errno::EAGAIN)) if e == Errno::EAGAIN || !signals_fail => {
+                if retries == max_retries {
+                    return Err(e.into());
+                }
+                retries += 1;
+                if e == Errno::EAGAIN {
+                    std::thread::yield_now();
+                }
+            }
+            Err(Errno::ESRCH) if !cleared_stale_owner => {
+                cleared_stale_owner = true;
+                let stale = futex.load(Ordering::Relaxed);
+                let _ = futex.compare_exchange(
+                    stale,
+                    FUTEX_OWNER_DIED,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+            }
+            Err(Errno::ETIMEDOUT) => return Err(io::ErrorKind::TimedOut.into()),
+            Err(Errno::EDEADLK) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Deadlock,
+                    "the calling thread already holds this lock",
+                ));
+            }
+            Err(e) => return Err(e.into()),
         }
-        _ => Ok(false),
     }
 }
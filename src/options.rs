@@ -0,0 +1,198 @@
+use std::path::PathBuf;
+
+use crate::{
+    pod::Pod,
+    shared_data::{SharedMutex, type_fingerprint},
+    shared_mem::{DirBackend, MemoryBackend, SharedMemorySafe, ShmBackend},
+};
+
+/// Settings for creating a [`SharedMutex`]. Settings stored in the segment are decided by
+/// whichever process creates it; later processes attaching with other values get the
+/// creator's behavior.
+#[derive(Debug, Clone, Default)]
+pub struct SharedMutexOptions {
+    pub(crate) fair: bool,
+    /// Bytes after the value, see [`SharedMutex::new_sized`]
+    pub(crate) tail: usize,
+    /// Validate a poisoned value instead of always replacing it, see
+    /// [`SharedMutex::new_keep_valid`]. Only affects this attach.
+    pub(crate) keep_valid_on_poison: bool,
+    pub(crate) max_lock_retries: Option<u32>,
+    /// Fault in the whole mapping when opening, see [`Self::prefault`]. Only affects this
+    /// process.
+    pub(crate) prefault: bool,
+    /// `mlock` this process's mapping, see [`Self::lock_memory`]
+    pub(crate) lock_memory: bool,
+    /// Directory for a [`DirBackend`], see [`Self::path`]
+    pub(crate) path: Option<PathBuf>,
+    /// Keep a checksum of the value, with the fingerprint of the type it was asked for,
+    /// see [`Self::checksum`]
+    pub(crate) checksum: Option<u64>,
+    /// Keep a sequence count for lock-free reads, see [`Self::seqlock`]
+    pub(crate) seqlock: bool,
+}
+
+impl SharedMutexOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hand the lock out in the order it was asked for, instead of by priority with
+    /// barging. Bounds how long any one locker waits, at the cost of a wake-up syscall
+    /// on every unlock. `lock_async` and attaching processes bypass the queue. See
+    /// `fair.rs` for the protocol, including how the turns of dead processes are
+    /// recovered. Stored in the segment.
+    pub fn fair(mut self, fair: bool) -> Self {
+        self.fair = fair;
+        self
+    }
+
+    /// How many times one acquisition retries transient `FUTEX_LOCK_PI` failures (`EAGAIN`
+    /// while an owner exits, `EINTR` where signals don't end the wait) before giving up with
+    /// the error, for services that would rather fail than spin. Defaults to 1000. Stored in
+    /// the segment.
+    pub fn max_lock_retries(mut self, max_lock_retries: u32) -> Self {
+        self.max_lock_retries = Some(max_lock_retries);
+        self
+    }
+
+    /// Fault in every page of the segment while opening it, so that the first writes to a
+    /// large `T` don't take page faults inside the critical section. Useful when lock hold
+    /// times must be bounded. The cost is paid up front instead: opening touches the whole
+    /// segment, which for a large `T` is slower and commits all of its memory at once.
+    /// Only affects this process's mapping.
+    pub fn prefault(mut self, prefault: bool) -> Self {
+        self.prefault = prefault;
+        self
+    }
+
+    /// Lock this process's mapping of the segment into RAM with `mlock`, and unlock it when
+    /// the mutex is dropped. A swapped out futex word stalls whoever touches it next, lock
+    /// holder included, which is exactly the latency spike priority inheritance is there to
+    /// avoid, and it keeps the data off the swap device. Needs `CAP_IPC_LOCK` or a large
+    /// enough `RLIMIT_MEMLOCK`; without them opening fails, see [`Self::try_open`].
+    pub fn lock_memory(mut self, lock_memory: bool) -> Self {
+        self.lock_memory = lock_memory;
+        self
+    }
+
+    /// Keep a CRC-32 of the value, updated whenever a guard unlocks and checked whenever
+    /// the lock is taken. A mismatch means something wrote to the value without holding
+    /// the lock, a stray pointer in some process or bad memory, and is reported like
+    /// poison with [`CHECKSUM_MISMATCH`] as the [`SharedGuard::poison_reason`]; dropping
+    /// that guard accepts the value as it is. Costs hashing the whole value twice per
+    /// critical section, so mostly for long-lived segments under suspicion. Writes that
+    /// bypass the lock on purpose, like [`SharedMutexInner::compare_exchange`], show up as
+    /// mismatches too. Stored in the segment.
+    ///
+    /// Only a [`Pod`] value has no padding to hash, so `T` is the type the mutex is opened
+    /// for, and opening it for any other fails.
+    ///
+    /// [`CHECKSUM_MISMATCH`]: crate::CHECKSUM_MISMATCH
+    /// [`SharedMutexInner::compare_exchange`]: crate::shared_data::SharedMutexInner::compare_exchange
+    /// [`SharedGuard::poison_reason`]: crate::shared_data::SharedGuard::poison_reason
+    pub fn checksum<T: Pod>(mut self, checksum: bool) -> Self {
+        self.checksum = checksum.then(type_fingerprint::<T>);
+        self
+    }
+
+    /// Keep a sequence count that every writing guard makes odd while it's held, so that
+    /// [`SharedMutexInner::read_seqlock`] can copy the value without taking the lock, and
+    /// retry if a writer got in the way. For a large, read-mostly `T` whose readers
+    /// shouldn't queue on the lock or boost its owner. Writers still serialize on the lock
+    /// and pay two atomic updates more per critical section. Stored in the segment.
+    ///
+    /// [`SharedMutexInner::read_seqlock`]: crate::shared_data::SharedMutexInner::read_seqlock
+    pub fn seqlock(mut self, seqlock: bool) -> Self {
+        self.seqlock = seqlock;
+        self
+    }
+
+    /// Keep the segment as a file in `dir` instead of in `/dev/shm`, e.g. a tmpfs mount of
+    /// its own for isolation or a separate quota, see [`DirBackend`]. Every process using
+    /// the mutex has to pass the same `dir`. Ignored by [`Self::try_open_in`].
+    pub fn path(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.path = Some(dir.into());
+        self
+    }
+
+    /// Like [`SharedMutex::new`], with these options.
+    ///
+    /// # Panics
+    ///
+    /// Wherever [`Self::try_open`] would return an error.
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T`
+    pub unsafe fn open<T: SharedMemorySafe>(
+        &self,
+        name: &str,
+        initial: impl FnOnce() -> T,
+    ) -> SharedMutex<T> {
+        unsafe { self.try_open(name, initial) }.unwrap_or_else(|e| panic!("{e:#}"))
+    }
+
+    /// Like [`SharedMutex::try_open`], with these options. Also fails if the memory can't be
+    /// locked as asked for by [`Self::lock_memory`].
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T`
+    pub unsafe fn try_open<T: SharedMemorySafe>(
+        &self,
+        name: &str,
+        initial: impl FnOnce() -> T,
+    ) -> anyhow::Result<SharedMutex<T>> {
+        match &self.path {
+            Some(dir) => unsafe { self.try_open_in(&DirBackend::new(dir), name, initial) },
+            None => unsafe { self.try_open_in(&ShmBackend, name, initial) },
+        }
+    }
+
+    /// Like [`Self::try_open`], with the segment from `backend`, see
+    /// [`SharedMutex::try_open_in`].
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T`
+    pub unsafe fn try_open_in<T: SharedMemorySafe>(
+        &self,
+        backend: &(impl MemoryBackend + ?Sized),
+        name: &str,
+        initial: impl FnOnce() -> T,
+    ) -> anyhow::Result<SharedMutex<T>> {
+        unsafe { SharedMutex::try_open_with(backend, name, initial, self) }
+    }
+}
+
+/// Settings for one acquisition, see [`SharedMutexInner::lock_with`]. Unlike
+/// [`SharedMutexOptions`] nothing is stored in the segment, so every call site can tune its
+/// own.
+///
+/// [`SharedMutexInner::lock_with`]: crate::shared_data::SharedMutexInner::lock_with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LockConfig {
+    pub(crate) spins: u32,
+}
+
+impl LockConfig {
+    pub const fn new() -> Self {
+        Self { spins: 0 }
+    }
+
+    /// How many times to check whether a held lock has been released, and take it if so,
+    /// before sleeping in `FUTEX_LOCK_PI`. Worth it when critical sections are shorter
+    /// than the syscall and the owner runs on another CPU; otherwise it only burns time
+    /// the owner could have used. Defaults to 0, which goes straight to the kernel like
+    /// [`SharedMutexInner::lock`].
+    ///
+    /// [`SharedMutexInner::lock`]: crate::shared_data::SharedMutexInner::lock
+    pub const fn spins(mut self, spins: u32) -> Self {
+        self.spins = spins;
+        self
+    }
+}
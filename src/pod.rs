@@ -0,0 +1,24 @@
+//! Plain old data: types that are nothing but their bits.
+
+use crate::shared_mem::SharedMemorySafe;
+
+/// Types for which every bit pattern is a valid value and that have no padding, so two
+/// values are equal exactly when their bytes are. This is what `bytemuck::Pod` promises;
+/// it's repeated here to keep the crate free of dependencies beyond the platform.
+///
+/// # Safety
+///
+/// Implementors must have no padding bytes, no invalid bit patterns and no pointers.
+pub unsafe trait Pod: SharedMemorySafe + Copy {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => {
+        $(unsafe impl Pod for $t {})*
+    };
+}
+
+impl_pod!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64
+);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
@@ -0,0 +1,87 @@
+//! Looking at a poisoned value without holding on to the lock.
+//!
+//! [`SharedMutexInner::lock`] hands a poisoned value back in [`LockError::Poisoned`], a
+//! guard that holds the lock for as long as anyone looks at it, though often only the
+//! caller's error report needs the value. [`SharedMutexInner::lock_or_copy_poisoned`]
+//! copies it out instead and lets go of the lock, leaving it poisoned for whoever takes on
+//! the repair.
+
+use crate::{
+    shared_data::{LockError, SharedGuard, SharedMutexInner},
+    shared_mem::SharedMemorySafe,
+};
+
+impl<T: SharedMemorySafe + Copy> SharedMutexInner<T> {
+    /// Like [`Self::lock`], but if the lock is poisoned it's released again right away,
+    /// still poisoned and with the same [`SharedGuard::poison_reason`], and
+    /// [`LockError::Poisoned`] carries a copy of the value as the previous owner left it.
+    /// Repair it through [`PoisonedCopy::into_guard`], or leave that to the next locker.
+    pub fn lock_or_copy_poisoned(
+        &self,
+    ) -> Result<SharedGuard<'_, T>, LockError<PoisonedCopy<'_, T>>> {
+        let guard = match self.lock() {
+            Ok(guard) => return Ok(guard),
+            Err(LockError::Poisoned(guard)) => guard,
+            Err(LockError::Failed(e)) => return Err(LockError::Failed(e)),
+        };
+        let (value, reason) = (*guard, guard.poison_reason());
+        // passed on like a dead owner's
+        guard.repoison();
+        drop(guard);
+        Err(LockError::Poisoned(PoisonedCopy {
+            mutex: self,
+            value,
+            reason,
+        }))
+    }
+}
+
+/// A poisoned value, copied out by [`SharedMutexInner::lock_or_copy_poisoned`] without
+/// holding the lock. Derefs to the copy.
+pub struct PoisonedCopy<'a, T: SharedMemorySafe> {
+    mutex: &'a SharedMutexInner<T>,
+    value: T,
+    reason: Option<u32>,
+}
+
+impl<'a, T: SharedMemorySafe> PoisonedCopy<'a, T> {
+    /// See [`SharedGuard::poison_reason`].
+    pub fn poison_reason(&self) -> Option<u32> {
+        self.reason
+    }
+
+    /// Takes the lock, poisoned or not, to repair the value. Someone else may have
+    /// repaired or changed it in between, so look at the guard rather than this copy.
+    ///
+    /// # Panics
+    ///
+    /// Like [`SharedMutexInner::grab`].
+    pub fn into_guard(self) -> SharedGuard<'a, T> {
+        self.mutex.grab()
+    }
+}
+
+impl<T: SharedMemorySafe> std::ops::Deref for PoisonedCopy<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: SharedMemorySafe + std::fmt::Debug> std::fmt::Debug for PoisonedCopy<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PoisonedCopy")
+            .field("value", &self.value)
+            .field("reason", &self.reason)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: SharedMemorySafe> std::fmt::Display for PoisonedCopy<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "shared mutex `{}` is poisoned", self.mutex.name())
+    }
+}
+
+impl<T: SharedMemorySafe + std::fmt::Debug> std::error::Error for PoisonedCopy<'_, T> {}
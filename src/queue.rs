@@ -0,0 +1,190 @@
+//! A bounded queue in a single [`SharedMutex`] segment.
+//!
+//! The ring keeps `head` and `len` rather than head and tail indices, so full and empty
+//! are never ambiguous. Every operation runs under the mutex; an owner dying mid-operation
+//! can lose or repeat the one item it was moving, but never leaves the ring out of bounds.
+//!
+//! [`SharedQueue::pop_blocking`] sleeps on `pushes`, a counter every push bumps under the
+//! lock. Reading it while still holding the lock that found the ring empty means a push in
+//! between changes it, and the futex wait returns straight away instead of missing it.
+
+use std::{
+    io,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use nix::errno::Errno;
+
+use crate::{
+    futex::sys,
+    metrics::MetricsSnapshot,
+    shared_data::{SharedGuard, SharedMutex},
+    shared_mem::{MemoryBackend, SharedMemorySafe, ShmBackend},
+};
+
+#[repr(C)]
+struct Ring<T, const CAP: usize> {
+    head: usize,
+    len: usize,
+    /// Only accessed atomically, see [`Ring::pushes`]
+    pushes: u32,
+    items: [MaybeUninit<T>; CAP],
+}
+
+// derive would only ask for `T: Clone`, which isn't enough for the array
+impl<T: Copy, const CAP: usize> Clone for Ring<T, CAP> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: Copy, const CAP: usize> Copy for Ring<T, CAP> {}
+
+impl<T: Copy, const CAP: usize> Ring<T, CAP> {
+    const fn empty() -> Self {
+        Self {
+            head: 0,
+            len: 0,
+            pushes: 0,
+            items: [MaybeUninit::uninit(); CAP],
+        }
+    }
+
+    fn push_back(&mut self, item: T) -> Result<(), Full<T>> {
+        if self.len == CAP {
+            return Err(Full(item));
+        }
+        self.items[(self.head + self.len) % CAP] = MaybeUninit::new(item);
+        self.len += 1;
+        self.pushes().fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+
+    /// Waiters read this without the lock, so it's atomic even though it's in the data.
+    fn pushes(&mut self) -> &AtomicU32 {
+        unsafe { AtomicU32::from_ptr(&raw mut self.pushes) }
+    }
+
+    fn pop_front(&mut self) -> Option<T> {
+        // a value from a dead owner could be anything, don't index with it
+        if self.len == 0 || self.len > CAP || self.head >= CAP {
+            return None;
+        }
+        let item = unsafe { self.items[self.head].assume_init() };
+        self.head = (self.head + 1) % CAP;
+        self.len -= 1;
+        Some(item)
+    }
+}
+
+/// The queue was at capacity, the rejected item is handed back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Full<T>(pub T);
+
+impl<T> std::fmt::Display for Full<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("shared queue is full")
+    }
+}
+
+impl<T: std::fmt::Debug> std::error::Error for Full<T> {}
+
+pub struct SharedQueue<T: SharedMemorySafe + Copy, const CAP: usize> {
+    mutex: SharedMutex<Ring<T, CAP>>,
+}
+
+impl<T: SharedMemorySafe + Copy, const CAP: usize> SharedQueue<T, CAP> {
+    /// Opens the queue `name`, creating it empty if it doesn't exist yet.
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T` and `CAP`
+    pub unsafe fn new(name: &str) -> Self {
+        unsafe { Self::new_in(&ShmBackend, name) }
+    }
+
+    /// Like [`Self::new`], with the segment from `backend` instead of `/dev/shm`.
+    ///
+    /// # Safety
+    ///
+    /// As for [`Self::new`].
+    pub unsafe fn new_in(backend: &(impl MemoryBackend + ?Sized), name: &str) -> Self {
+        Self {
+            mutex: unsafe { SharedMutex::new_in(backend, name, Ring::empty) },
+        }
+    }
+
+    pub fn push(&self, item: T) -> Result<(), Full<T>> {
+        let mut ring = self.lock();
+        ring.push_back(item)?;
+        if let Err(e) = unsafe { sys::wake(ring.pushes(), 1) } {
+            debug_assert!(false, "{}", io::Error::from(e));
+        }
+        Ok(())
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        self.lock().pop_front()
+    }
+
+    /// Like [`Self::pop`], but waits for an item instead of returning `None`.
+    pub fn pop_blocking(&self) -> T {
+        loop {
+            let mut ring = self.lock();
+            if let Some(item) = ring.pop_front() {
+                return item;
+            }
+            let pushes: *const AtomicU32 = ring.pushes();
+            let seen = unsafe { &*pushes }.load(Ordering::Acquire);
+            drop(ring);
+            // `self` keeps the segment, and with it the counter, mapped
+            match unsafe { sys::wait(&*pushes, seen, None) } {
+                Ok(()) | Err(Errno::EAGAIN | Errno::EINTR) => {}
+                Err(e) => debug_assert!(false, "{}", io::Error::from(e)),
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pops everything that's queued in a single locked pass. The lock is held until the
+    /// iterator is dropped, so producers wait for it.
+    pub fn drain(&self) -> Drain<'_, T, CAP> {
+        Drain { guard: self.lock() }
+    }
+
+    /// Counters of the underlying mutex.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.mutex.metrics()
+    }
+
+    fn lock(&self) -> SharedGuard<'_, Ring<T, CAP>> {
+        // a dead owner leaves at worst one item lost or repeated, keep going
+        self.mutex.grab()
+    }
+}
+
+pub struct Drain<'a, T: SharedMemorySafe + Copy, const CAP: usize> {
+    guard: SharedGuard<'a, Ring<T, CAP>>,
+}
+
+impl<T: SharedMemorySafe + Copy, const CAP: usize> Iterator for Drain<'_, T, CAP> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.guard.pop_front()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.guard.len.min(CAP);
+        (len, Some(len))
+    }
+}
@@ -0,0 +1,91 @@
+//! A token bucket in shared memory, so any number of processes can share one rate limit.
+//!
+//! Time comes from `CLOCK_MONOTONIC`, which is the same clock for every process on the
+//! machine, so the refill timestamp stored by one process means the same to all others.
+
+use crate::{
+    futex::monotonic_ns,
+    shared_data::{SharedGuard, SharedMutex},
+};
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Bucket {
+    capacity: u64,
+    per_second: u64,
+    tokens: u64,
+    /// `CLOCK_MONOTONIC` time up to which refills have been credited
+    refilled_at_ns: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self, now_ns: u64) {
+        let elapsed = u128::from(now_ns.saturating_sub(self.refilled_at_ns));
+        let earned = elapsed * u128::from(self.per_second) / NANOS_PER_SEC;
+        let room = u128::from(self.capacity - self.tokens.min(self.capacity));
+        if earned >= room {
+            self.tokens = self.capacity;
+            self.refilled_at_ns = now_ns;
+        } else if earned > 0 {
+            self.tokens += earned as u64;
+            // only credit the time the whole tokens took, the remainder carries over
+            let spent = earned * NANOS_PER_SEC / u128::from(self.per_second);
+            self.refilled_at_ns += spent as u64;
+        }
+    }
+}
+
+pub struct SharedRateLimiter {
+    mutex: SharedMutex<Bucket>,
+}
+
+impl SharedRateLimiter {
+    /// Opens the limiter `name`, creating it full if it doesn't exist yet. It allows
+    /// bursts of up to `capacity` and refills at `per_second` tokens a second. The first
+    /// process to create it decides both, later callers' values are ignored.
+    ///
+    /// # Panics
+    ///
+    /// If `per_second` is zero.
+    pub fn new(name: &str, capacity: u64, per_second: u64) -> Self {
+        assert!(per_second > 0, "a rate limiter needs a non-zero rate");
+        let bucket = || Bucket {
+            capacity,
+            per_second,
+            tokens: capacity,
+            refilled_at_ns: monotonic_ns(),
+        };
+        // SAFETY: `Bucket` is private, and attaching with any other `T` fails the
+        // fingerprint check
+        let mutex = unsafe { SharedMutex::new(name, bucket) };
+        Self { mutex }
+    }
+
+    /// Takes `n` tokens if that many are available, across every process using the
+    /// limiter. Never blocks beyond the lock itself.
+    pub fn try_acquire(&self, n: u64) -> bool {
+        let mut bucket = self.lock();
+        bucket.refill(monotonic_ns());
+        match bucket.tokens.checked_sub(n) {
+            Some(left) => {
+                bucket.tokens = left;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Tokens available right now.
+    pub fn available(&self) -> u64 {
+        let mut bucket = self.lock();
+        bucket.refill(monotonic_ns());
+        bucket.tokens
+    }
+
+    fn lock(&self) -> SharedGuard<'_, Bucket> {
+        // a dead owner can at worst have lost the tokens it was taking
+        self.mutex.grab()
+    }
+}
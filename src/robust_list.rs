@@ -19,7 +19,7 @@ pub struct RobustListHead {
 impl RobustListHead {
     /// Return the sentinel value `next` should have when the list is empty.
     #[inline]
-    fn head_value(&self) -> *mut RobustList {
+    pub(crate) fn head_value(&self) -> *mut RobustList {
         &self.list as *const _ as *mut RobustList
     }
 }
@@ -0,0 +1,457 @@
+//! A reentrant reader-writer lock that any process can use.
+//!
+//! Writers hold the same kind of PI futex [`SharedMutex`](crate::SharedMutex) uses for the
+//! whole write, so a writer dying mid-write is reported through the robust list like any
+//! other owner. Readers only take that futex long enough to claim a slot (TID + nesting
+//! depth) in a fixed table. The kernel knows nothing about those slots, so a writer
+//! waiting for readers to leave checks whether each slot's TID still exists and reclaims
+//! the slots of dead readers.
+
+use std::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Duration,
+};
+
+use libc::pid_t;
+use nix::errno::Errno;
+
+use crate::{
+    futex::{duration_to_timespec, sys, tid},
+    mutex::{DEFAULT_MAX_RETRIES, PiMutex, lock_try},
+    shared_data::{LockError, LockResult},
+    shared_mem::{self, MemoryBackend, SharedMemorySafe, ShmBackend, ShmemWrapper},
+};
+
+/// Threads that can hold a read lock at the same time, across all processes.
+pub const MAX_READERS: usize = 64;
+
+/// How often a blocked writer re-checks for readers that died without releasing.
+const REAP_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Plain names for the lock and its guards, for code that doesn't rely on reentrancy.
+pub type SharedRwLock<T> = SharedReentrantRwLock<T>;
+pub type SharedReadGuard<'a, T> = ReentrantReadGuard<'a, T>;
+pub type SharedWriteGuard<'a, T> = ReentrantWriteGuard<'a, T>;
+
+pub struct SharedReentrantRwLock<T: SharedMemorySafe> {
+    memory: ShmemWrapper,
+    _quacks_like_a: PhantomData<Arc<std::sync::RwLock<T>>>,
+}
+
+unsafe impl<T: SharedMemorySafe> Send for SharedReentrantRwLock<T> {}
+unsafe impl<T: SharedMemorySafe> Sync for SharedReentrantRwLock<T> {}
+
+impl<T: SharedMemorySafe> SharedReentrantRwLock<T> {
+    /// A new lock with `name` that any process can use. If `name` is not allocated yet
+    /// then this function will allocate. If the lock is uninitialized, or a writer died
+    /// while holding it, `initial` is used as the value.
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T`
+    pub unsafe fn new(name: &str, initial: impl FnOnce() -> T) -> Self {
+        unsafe { Self::new_in(&ShmBackend, name, initial) }
+    }
+
+    /// Like [`Self::new`], with the segment from `backend` instead of `/dev/shm`.
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T`
+    pub unsafe fn new_in(
+        backend: &(impl MemoryBackend + ?Sized),
+        name: &str,
+        initial: impl FnOnce() -> T,
+    ) -> Self {
+        let memory =
+            shared_mem::get_memory_in::<SharedReentrantRwLockInner<T>>(backend, name, 0, false)
+                .unwrap();
+
+        let inner: *mut SharedReentrantRwLockInner<T> = memory.pointer().cast();
+        unsafe {
+            let owner_died = (*inner)
+                .writer
+                .lock_inner(None, false, DEFAULT_MAX_RETRIES)
+                .unwrap()
+                .owner_died;
+            if owner_died || !(*inner).init {
+                let data = &raw mut (*inner).data;
+                data.write(UnsafeCell::new(initial()));
+                (*inner).write_depth.store(0, Ordering::Relaxed);
+                (*inner).init = true;
+            }
+            (*inner).writer.unlock();
+        }
+
+        Self {
+            memory,
+            _quacks_like_a: PhantomData,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T`
+    pub unsafe fn new_with_val(name: &str, initial: T) -> Self {
+        unsafe { Self::new(name, || initial) }
+    }
+}
+
+impl<T: SharedMemorySafe> Deref for SharedReentrantRwLock<T> {
+    type Target = SharedReentrantRwLockInner<T>;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.memory.pointer().cast() }
+    }
+}
+
+#[repr(C)]
+struct ReaderSlot {
+    tid: AtomicU32,
+    depth: AtomicU32,
+}
+
+#[repr(C)]
+pub struct SharedReentrantRwLockInner<T> {
+    writer: PiMutex,
+    /// Nesting depth of the current writer, only touched while holding `writer`
+    write_depth: AtomicU32,
+    /// Bumped whenever a reader gives up its slot, writers futex-wait on it
+    reader_exits: AtomicU32,
+    readers: [ReaderSlot; MAX_READERS],
+    init: bool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: SharedMemorySafe> Send for SharedReentrantRwLockInner<T> {}
+unsafe impl<T: SharedMemorySafe> Sync for SharedReentrantRwLockInner<T> {}
+
+impl<T: SharedMemorySafe> SharedReentrantRwLockInner<T> {
+    /// Takes a read lock. A thread that already reads gets a nested guard without
+    /// blocking. Returns [`LockError::Poisoned`] if a writer died mid-write.
+    ///
+    /// # Panics
+    ///
+    /// If the calling thread holds the write lock, whose guard could write while this
+    /// one reads. See [`Self::read_nested`].
+    pub fn read(&self) -> LockResult<ReentrantReadGuard<'_, T>> {
+        assert!(
+            !self.writer.is_locked_by_me(),
+            "read() while holding the write lock, see read_nested"
+        );
+        self.read_unchecked()
+    }
+
+    /// Like [`Self::read`], but a thread that holds the write lock gets a nested read
+    /// guard under it, which doesn't hold anything of its own.
+    ///
+    /// # Safety
+    ///
+    /// If the calling thread holds the write lock, nothing may be written through the
+    /// write guard while the returned guard is alive, and the returned guard must be
+    /// dropped first.
+    pub unsafe fn read_nested(&self) -> LockResult<ReentrantReadGuard<'_, T>> {
+        if self.writer.is_locked_by_me() {
+            return Ok(self.read_guard(None));
+        }
+        self.read_unchecked()
+    }
+
+    fn read_unchecked(&self) -> LockResult<ReentrantReadGuard<'_, T>> {
+        let me = tid() as u32;
+        if let Some(slot) = self.slot_of(me) {
+            slot.depth.fetch_add(1, Ordering::Relaxed);
+            return Ok(self.read_guard(Some(slot)));
+        }
+
+        let mut poisoned = false;
+        loop {
+            let exits = self.reader_exits.load(Ordering::Acquire);
+            let owner_died = match self.writer.lock_inner(None, false, DEFAULT_MAX_RETRIES) {
+                Ok(acquired) => acquired.owner_died,
+                Err(e) => return Err(LockError::Failed(e)),
+            };
+            if owner_died {
+                self.write_depth.store(0, Ordering::Relaxed);
+            }
+            poisoned |= owner_died;
+
+            let slot = self.claim_slot(me);
+            unsafe { self.writer.unlock() };
+
+            match slot {
+                Some(slot) if poisoned => {
+                    return Err(LockError::Poisoned(self.read_guard(Some(slot))));
+                }
+                Some(slot) => return Ok(self.read_guard(Some(slot))),
+                None => self.wait_for_exit(exits),
+            }
+        }
+    }
+
+    /// Takes the write lock, waiting for every reader to leave. Returns
+    /// [`LockError::Poisoned`] if the previous writer died mid-write.
+    ///
+    /// # Panics
+    ///
+    /// If the calling thread holds a read lock: two readers both waiting to write would
+    /// deadlock. Use [`ReentrantReadGuard::try_upgrade`] instead. Also if it holds the
+    /// write lock already, see [`Self::write_nested`].
+    pub fn write(&self) -> LockResult<ReentrantWriteGuard<'_, T>> {
+        assert!(
+            !self.writer.is_locked_by_me(),
+            "write() while holding the write lock, see write_nested"
+        );
+        self.write_unchecked()
+    }
+
+    /// Like [`Self::write`], but a thread that already writes gets a nested guard, and
+    /// only the outermost guard unlocks.
+    ///
+    /// # Safety
+    ///
+    /// Both guards deref mutably to the same value. If the calling thread already holds
+    /// the write lock, no reference obtained through the outer guard may be used while
+    /// the nested guard is alive.
+    pub unsafe fn write_nested(&self) -> LockResult<ReentrantWriteGuard<'_, T>> {
+        if self.writer.is_locked_by_me() {
+            self.write_depth.fetch_add(1, Ordering::Relaxed);
+            return Ok(self.write_guard());
+        }
+        self.write_unchecked()
+    }
+
+    fn write_unchecked(&self) -> LockResult<ReentrantWriteGuard<'_, T>> {
+        let me = tid() as u32;
+        assert!(
+            self.slot_of(me).is_none(),
+            "write() while holding a read lock would deadlock, use try_upgrade"
+        );
+
+        let owner_died = match self.writer.lock_inner(None, false, DEFAULT_MAX_RETRIES) {
+            Ok(acquired) => acquired.owner_died,
+            Err(e) => return Err(LockError::Failed(e)),
+        };
+        self.write_depth.store(1, Ordering::Relaxed);
+        loop {
+            let exits = self.reader_exits.load(Ordering::Acquire);
+            if self.sole_reader(None) {
+                break;
+            }
+            self.wait_for_exit(exits);
+        }
+
+        match owner_died {
+            false => Ok(self.write_guard()),
+            true => Err(LockError::Poisoned(self.write_guard())),
+        }
+    }
+
+    pub fn is_write_locked(&self) -> bool {
+        self.writer.is_locked()
+    }
+
+    fn slot_of(&self, me: u32) -> Option<&ReaderSlot> {
+        self.readers
+            .iter()
+            .find(|slot| slot.tid.load(Ordering::Relaxed) == me)
+    }
+
+    /// Must hold `writer`.
+    fn claim_slot(&self, me: u32) -> Option<&ReaderSlot> {
+        let free = || {
+            self.readers
+                .iter()
+                .find(|slot| slot.tid.load(Ordering::Acquire) == 0)
+        };
+        let slot = free().or_else(|| {
+            self.reap_dead_readers();
+            free()
+        })?;
+        slot.depth.store(1, Ordering::Relaxed);
+        slot.tid.store(me, Ordering::Release);
+        Some(slot)
+    }
+
+    /// Whether no thread other than the owner of `mine` holds a read lock. Must hold
+    /// `writer` so no new readers can show up.
+    fn sole_reader(&self, mine: Option<&ReaderSlot>) -> bool {
+        self.reap_dead_readers();
+        self.readers.iter().all(|slot| {
+            mine.is_some_and(|mine| std::ptr::eq(mine, slot))
+                || slot.tid.load(Ordering::Acquire) == 0
+        })
+    }
+
+    /// Frees the slots of readers whose thread no longer exists. TIDs can be recycled, so
+    /// a dead reader may go unnoticed for as long as its TID is reused.
+    fn reap_dead_readers(&self) {
+        for slot in &self.readers {
+            let reader = slot.tid.load(Ordering::Acquire);
+            if reader != 0
+                && unsafe { libc::kill(reader as pid_t, 0) } == -1
+                && Errno::last() == Errno::ESRCH
+            {
+                slot.depth.store(0, Ordering::Relaxed);
+                slot.tid.store(0, Ordering::Release);
+                self.reader_exits.fetch_add(1, Ordering::Release);
+            }
+        }
+    }
+
+    fn wait_for_exit(&self, exits: u32) {
+        let timeout = duration_to_timespec(REAP_INTERVAL);
+        let _ = unsafe { sys::wait(&self.reader_exits, exits, Some(timeout)) };
+    }
+
+    fn read_guard(&self, slot: Option<&ReaderSlot>) -> ReentrantReadGuard<'_, T> {
+        ReentrantReadGuard {
+            lock: self,
+            slot: slot.map(|slot| slot as *const ReaderSlot),
+            _not_send: PhantomData,
+        }
+    }
+
+    fn write_guard(&self) -> ReentrantWriteGuard<'_, T> {
+        ReentrantWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+/// Read access to a [`SharedReentrantRwLock`]. Nested guards must be dropped before the
+/// guard they were taken under.
+pub struct ReentrantReadGuard<'a, T: SharedMemorySafe> {
+    lock: &'a SharedReentrantRwLockInner<T>,
+    /// `None` when nested inside this thread's write lock
+    slot: Option<*const ReaderSlot>,
+    // slots are keyed by TID, so the guard has to be dropped on the thread that took it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, T: SharedMemorySafe> ReentrantReadGuard<'a, T> {
+    /// Turns this read lock into the write lock without letting another writer in
+    /// between. Only succeeds if this is the sole reader and this guard isn't nested,
+    /// otherwise the read guard is handed back. That includes a guard nested under this
+    /// thread's write lock, whose write guard is what to write through.
+    pub fn try_upgrade(self) -> Result<ReentrantWriteGuard<'a, T>, Self> {
+        let lock = self.lock;
+        let Some(slot) = self.slot else {
+            return Err(self);
+        };
+        let slot = unsafe { &*slot };
+        if slot.depth.load(Ordering::Relaxed) != 1 {
+            return Err(self);
+        }
+        // a writer waiting on us holds the futex, so this can't block
+        if !matches!(lock_try(&lock.writer.0, DEFAULT_MAX_RETRIES), Ok(Some(_))) {
+            return Err(self);
+        }
+        if !lock.sole_reader(Some(slot)) {
+            unsafe { lock.writer.unlock() };
+            return Err(self);
+        }
+
+        lock.write_depth.store(1, Ordering::Relaxed);
+        slot.depth.store(0, Ordering::Relaxed);
+        slot.tid.store(0, Ordering::Release);
+        std::mem::forget(self);
+        Ok(lock.write_guard())
+    }
+}
+
+impl<T: SharedMemorySafe> Deref for ReentrantReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: SharedMemorySafe> Drop for ReentrantReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let Some(slot) = self.slot else { return };
+        let slot = unsafe { &*slot };
+        if slot.depth.fetch_sub(1, Ordering::AcqRel) == 1 {
+            slot.tid.store(0, Ordering::Release);
+            self.lock.reader_exits.fetch_add(1, Ordering::Release);
+            let _ = unsafe { sys::wake(&self.lock.reader_exits, i32::MAX) };
+        }
+    }
+}
+
+/// Write access to a [`SharedReentrantRwLock`]. Nested guards must be dropped before the
+/// guard they were taken under.
+pub struct ReentrantWriteGuard<'a, T: SharedMemorySafe> {
+    lock: &'a SharedReentrantRwLockInner<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, T: SharedMemorySafe> ReentrantWriteGuard<'a, T> {
+    /// Turns this write lock into a read lock without letting another writer in between.
+    /// Readers waiting for the write lock get in, unless a writer was waiting too: it
+    /// takes the futex next and holds them back until this read lock is gone. A nested
+    /// guard is handed back, since the outer one still writes.
+    ///
+    /// There's no blocking counterpart for going the other way, since two readers waiting
+    /// to become the writer would deadlock; see [`ReentrantReadGuard::try_upgrade`].
+    pub fn downgrade(self) -> Result<ReentrantReadGuard<'a, T>, Self> {
+        let lock = self.lock;
+        if lock.write_depth.load(Ordering::Relaxed) != 1 {
+            return Err(self);
+        }
+        std::mem::forget(self);
+        lock.write_depth.store(0, Ordering::Relaxed);
+        // all slots are free while we write, and the slot is ours before any writer
+        // blocked on the futex can look
+        let slot = lock
+            .claim_slot(tid() as u32)
+            .expect("no readers while writing, so there's a free slot");
+        unsafe { lock.writer.unlock() };
+        Ok(lock.read_guard(Some(slot)))
+    }
+}
+
+impl<T: SharedMemorySafe> Deref for ReentrantWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: SharedMemorySafe> DerefMut for ReentrantWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: SharedMemorySafe> Drop for ReentrantWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.lock.write_depth.fetch_sub(1, Ordering::Relaxed) == 1 {
+            unsafe { self.lock.writer.unlock() };
+        }
+    }
+}
+
+impl<'a, T: SharedMemorySafe + std::fmt::Debug> std::fmt::Debug for ReentrantReadGuard<'a, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        <T as std::fmt::Debug>::fmt(self, f)
+    }
+}
+
+impl<'a, T: SharedMemorySafe + std::fmt::Debug> std::fmt::Debug for ReentrantWriteGuard<'a, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        <T as std::fmt::Debug>::fmt(self, f)
+    }
+}
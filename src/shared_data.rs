@@ -1,13 +1,27 @@
 use std::{
     cell::UnsafeCell,
+    io,
     marker::PhantomData,
-    ops::{Deref, DerefMut},
-    sync::Arc,
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut, Range},
+    sync::{
+        Arc,
+        atomic::{AtomicU8, AtomicU16, AtomicU32, AtomicU64, Ordering, fence},
+    },
+    time::{Duration, Instant},
 };
 
+use anyhow::Context;
+use libc::pid_t;
+
 use crate::{
-    mutex::{PiMutex, lock_try},
-    shared_mem::{self, SharedMemorySafe, ShmemWrapper},
+    fair::FairQueue,
+    futex::{FUTEX_OWNER_DIED, FUTEX_TID_MASK, monotonic_ns, realtime_deadline, tid},
+    metrics::{LockMetrics, MetricsSnapshot},
+    mutex::{Acquired, DEFAULT_MAX_RETRIES, PiMutex, lock_try, lock_try_observed},
+    options::{LockConfig, SharedMutexOptions},
+    pod::Pod,
+    shared_mem::{self, MemoryBackend, SharedMemorySafe, ShmBackend, ShmemWrapper},
 };
 
 pub struct SharedMutex<T: SharedMemorySafe> {
@@ -18,62 +32,502 @@ pub struct SharedMutex<T: SharedMemorySafe> {
 unsafe impl<T: SharedMemorySafe> Send for SharedMutex<T> {}
 unsafe impl<T: SharedMemorySafe> Sync for SharedMutex<T> {}
 
+/// Another handle to the same mutex, sharing this one's mapping, so passing a mutex around
+/// doesn't need an `Arc`. The mapping stays until the last handle is dropped, and it works
+/// for every kind of segment, including anonymous and unlinked ones.
+impl<T: SharedMemorySafe> Clone for SharedMutex<T> {
+    fn clone(&self) -> Self {
+        Self {
+            memory: self.memory.clone(),
+            _quacks_like_a: PhantomData,
+        }
+    }
+}
+
 impl<T> SharedMutex<T>
 where
     T: SharedMemorySafe,
 {
+    /// Another reference to the mapping, keeping it alive as long as it's held.
+    #[cfg(feature = "async")]
+    pub(crate) fn mapping(&self) -> ShmemWrapper {
+        self.memory.clone()
+    }
+
     /// A new mutex with `name` that any process can use. If `name` is not allocated yet
     /// then this function will allocate. In addition, if the mutex is poisoned or unitialized
     /// then `initial` will lazily be used as the init value. If you want to initialize with a
     /// value, then see [`Self::new_with_val`]
     ///
+    /// `initial` runs under the lock, so of any number of processes opening a new `name` at
+    /// once exactly one runs it, and the rest wait and get its value. It only runs again to
+    /// replace a poisoned value, or if the process running it died or panicked before
+    /// finishing, in which case nobody got a value from it.
+    ///
+    /// # Panics
+    ///
+    /// If the shared memory can't be opened or mapped, or `name` was created for a
+    /// different `T`. [`Self::try_open`] returns those as errors instead.
+    ///
     /// # Safety
     ///
     /// The caller should ensure that for a given name all callers of this function
     /// across any process on the same system, specify the same `T`
     pub unsafe fn new(name: &str, initial: impl FnOnce() -> T) -> SharedMutex<T> {
+        unsafe { Self::new_in(&ShmBackend, name, initial) }
+    }
+
+    /// Like [`Self::new`], with the segment from `backend` instead of `/dev/shm`.
+    ///
+    /// # Panics
+    ///
+    /// Wherever [`Self::try_open_in`] would return an error.
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T`
+    pub unsafe fn new_in(
+        backend: &(impl MemoryBackend + ?Sized),
+        name: &str,
+        initial: impl FnOnce() -> T,
+    ) -> SharedMutex<T> {
+        unsafe { Self::try_open_in(backend, name, initial) }.unwrap_or_else(|e| panic!("{e:#}"))
+    }
+
+    /// Like [`Self::new`], but returns an error instead of panicking if the shared memory
+    /// can't be opened or mapped (e.g. a name that's too long, permissions, or `/dev/shm`
+    /// being full), or if `name` was created for a different `T`.
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T`
+    pub unsafe fn try_open(
+        name: &str,
+        initial: impl FnOnce() -> T,
+    ) -> anyhow::Result<SharedMutex<T>> {
+        unsafe { Self::try_open_in(&ShmBackend, name, initial) }
+    }
+
+    /// Like [`Self::try_open`], with the segment from `backend` instead of `/dev/shm`.
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T`
+    pub unsafe fn try_open_in(
+        backend: &(impl MemoryBackend + ?Sized),
+        name: &str,
+        initial: impl FnOnce() -> T,
+    ) -> anyhow::Result<SharedMutex<T>> {
+        let options = SharedMutexOptions::default();
+        unsafe { Self::try_open_with(backend, name, initial, &options) }
+    }
+
+    /// [`Self::try_open_in`] with `options`, see [`SharedMutexOptions::try_open`].
+    pub(crate) unsafe fn try_open_with(
+        backend: &(impl MemoryBackend + ?Sized),
+        name: &str,
+        initial: impl FnOnce() -> T,
+        options: &SharedMutexOptions,
+    ) -> anyhow::Result<SharedMutex<T>> {
+        if options
+            .checksum
+            .is_some_and(|checksummed| checksummed != type_fingerprint::<T>())
+        {
+            anyhow::bail!(
+                "opening shared mutex `{name}`: the checksum was asked for another type than `{}`",
+                std::any::type_name::<T>()
+            );
+        }
+        let mut memory = shared_mem::get_memory_in::<SharedMutexInner<T>>(
+            backend,
+            name,
+            options.tail,
+            options.prefault,
+        )
+        .with_context(|| format!("opening shared mutex `{name}`"))?;
+        if options.lock_memory {
+            memory
+                .lock_memory()
+                .with_context(|| format!("locking shared mutex `{name}` into memory"))?;
+        }
+        let recover_from_poison = true;
+        let attached = unsafe {
+            Self::attach_memory(
+                memory,
+                name,
+                initial,
+                recover_from_poison,
+                |_| true,
+                options,
+            )
+        }
+        .with_context(|| format!("opening shared mutex `{name}`"))?;
+        Ok(attached.mutex)
+    }
+
+    /// A mutex in an anonymous shared mapping (`MAP_SHARED | MAP_ANONYMOUS`) rather than
+    /// under a name, initialized with `initial()`. It's usable only by this process and
+    /// children it forks afterwards, which inherit the mapping and share the value, and
+    /// unlike a named segment there's nothing to unlink: the memory goes away with the last
+    /// process that maps it. Safe, because no other process can open it for another `T`.
+    ///
+    /// [`SharedMutexInner::name`] is `(anonymous)`, which [`lock_many`] orders by address
+    /// within a process; after a fork, parent and child see the mapping at the same address.
+    ///
+    /// # Panics
+    ///
+    /// If the memory can't be mapped.
+    ///
+    /// [`lock_many`]: crate::lock_many
+    pub fn new_anonymous(initial: impl FnOnce() -> T) -> SharedMutex<T> {
+        let name = "(anonymous)";
+        let memory = shared_mem::get_anonymous_memory::<SharedMutexInner<T>>()
+            .unwrap_or_else(|e| panic!("{e:#}"));
         let recover_from_poison = true;
-        match unsafe { Self::try_new_inner(name, initial, recover_from_poison) } {
-            Ok(sm) | Err(sm) => sm,
+        let options = SharedMutexOptions::default();
+        unsafe {
+            Self::attach_memory(
+                memory,
+                name,
+                initial,
+                recover_from_poison,
+                |_| true,
+                &options,
+            )
         }
+        .unwrap_or_else(|e| panic!("{e:#}"))
+        .mutex
     }
 
-    unsafe fn try_new_inner(
+    /// A handle to the mutex in the shared memory open as `fd`, e.g. received over a Unix
+    /// socket with `SCM_RIGHTS`, for processes that are handed a segment instead of its
+    /// name. The segment has to hold a finished mutex for `T` already, e.g. one created by
+    /// name, so nothing is initialized here. Fails with [`io::ErrorKind::InvalidData`] if
+    /// it's too small for one or holds something else.
+    ///
+    /// `fd` is only borrowed: the mapping doesn't need it once it's made, so the caller
+    /// still owns it and may close it right after this returns.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be open for the duration of the call, and every process using the
+    /// segment must use it with the same `T`.
+    #[cfg(all(not(miri), unix))]
+    pub unsafe fn from_fd(fd: std::os::fd::RawFd) -> io::Result<SharedMutex<T>> {
+        let borrowed = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+        let memory = shared_mem::from_fd(borrowed, size_of::<SharedMutexInner<T>>())?;
+        Self::from_existing(memory).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("descriptor {fd} doesn't hold a shared mutex for this type"),
+            )
+        })
+    }
+
+    /// Attaches to the existing mutex `name`, for processes that only use a mutex another
+    /// one created. Fails with [`io::ErrorKind::NotFound`] if there's no such segment, and
+    /// with [`io::ErrorKind::InvalidData`] if it's too small, not initialized yet, or was
+    /// created for another type. Nothing is created or initialized, so `T` needs no
+    /// initial value.
+    ///
+    /// # Safety
+    ///
+    /// As for [`Self::new`], every process using `name` must use it with the same `T`. The
+    /// fingerprint every segment carries is checked like [`Self::new_checked`] checks it,
+    /// with the same blind spots.
+    pub unsafe fn attach(name: &str) -> io::Result<SharedMutex<T>> {
+        Self::attach_named(name).map_err(|(e, _)| e)
+    }
+
+    /// Attaches to the existing mutex `name` like [`Self::attach`], so `T` needs
+    /// no initial value, nothing is created, and the producer must have set the mutex up.
+    /// With `wait`, a segment that doesn't exist yet, or whose creator is still
+    /// initializing it, is polled for until it's ready, giving up with
+    /// [`io::ErrorKind::TimedOut`] after `wait`; without, that's an error right away. A
+    /// finished mutex for another type is always an error right away.
+    ///
+    /// # Safety
+    ///
+    /// As for [`Self::attach`].
+    pub unsafe fn attach_existing(
+        name: &str,
+        wait: Option<Duration>,
+    ) -> io::Result<SharedMutex<T>> {
+        // `Some(None)` for a wait too long to have a deadline
+        let deadline = wait.map(|wait| Instant::now().checked_add(wait));
+        loop {
+            let (e, transient) = match Self::attach_named(name) {
+                Ok(mutex) => return Ok(mutex),
+                Err(failed) => failed,
+            };
+            let Some(deadline) = deadline.filter(|_| transient) else {
+                return Err(e);
+            };
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("`{name}` wasn't ready in time: {e}"),
+                ));
+            }
+            std::thread::sleep(ATTACH_POLL);
+        }
+    }
+
+    /// [`Self::attach`], with whether the error may go away once the segment's creator
+    /// has finished setting it up.
+    fn attach_named(name: &str) -> Result<SharedMutex<T>, (io::Error, bool)> {
+        let memory = shared_mem::open_existing_as::<SharedMutexInner<T>>(name).map_err(|e| {
+            // not created yet, or not grown to size yet, but not mapped as another type
+            let mismatch = e.get_ref().is_some_and(|e| e.is::<TypeMismatch>());
+            let transient = !mismatch
+                && matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::InvalidData
+                );
+            (e, transient)
+        })?;
+        let inner = unsafe { &*memory.pointer().cast::<SharedMutexInner<T>>() };
+        let finished = inner.header.is_current() && inner.init.load(Ordering::Acquire) == INIT_DONE;
+        Self::from_existing(memory).ok_or_else(|| {
+            let e = io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("`{name}` doesn't hold a finished shared mutex for this type"),
+            );
+            (e, !finished)
+        })
+    }
+
+    /// Like [`Self::new`], but returns an error instead of panicking if `name` was created
+    /// with a different `T` (going by size, alignment and type name), which downcasts to
+    /// [`TypeMismatch`], or can't be opened at all, like [`Self::try_open`].
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T`. The check catches
+    /// most mistakes, but two types with the same name and layout, e.g. from different
+    /// versions of a crate, aren't told apart.
+    pub unsafe fn new_checked(
+        name: &str,
+        initial: impl FnOnce() -> T,
+    ) -> anyhow::Result<SharedMutex<T>> {
+        let recover_from_poison = true;
+        let options = SharedMutexOptions::default();
+        unsafe { Self::try_new_inner(name, initial, recover_from_poison, |_| true, &options) }
+            .map(|attached| attached.mutex)
+    }
+
+    unsafe fn new_inner(
         name: &str,
         initial: impl FnOnce() -> T,
         recover_from_poison: bool,
-    ) -> Result<SharedMutex<T>, SharedMutex<T>> {
-        let memory = shared_mem::get_memory::<T>(name).unwrap();
+        validate: impl FnOnce(&T) -> bool,
+    ) -> Attached<T> {
+        let options = SharedMutexOptions::default();
+        unsafe { Self::try_new_inner(name, initial, recover_from_poison, validate, &options) }
+            .unwrap_or_else(|e| panic!("`{name}`: {e:#}"))
+    }
 
-        let shared_mutex: *mut SharedMutexInner<T> = memory.pointer().cast();
-        let owner_died = unsafe {
-            let owner_died = (*shared_mutex).futex.lock().is_err();
-            if (owner_died && recover_from_poison) || !(*shared_mutex).init {
-                let data = &raw mut (*shared_mutex).data;
-                data.write(UnsafeCell::new(initial()));
-                (*shared_mutex).init = true;
-            }
-            (*shared_mutex).futex.unlock();
-            owner_died
+    /// Like [`Self::new`], plus `extra_bytes` of untyped memory after the value, for a
+    /// header-plus-payload layout whose payload size is only known at runtime. The bytes
+    /// start zeroed and are reached through [`SharedGuard::tail`]. The process that
+    /// creates `name` decides the size, later callers get that many bytes regardless of
+    /// `extra_bytes`.
+    ///
+    /// # Panics
+    ///
+    /// Wherever [`Self::try_new_sized`] would return an error.
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T`
+    pub unsafe fn new_sized(
+        name: &str,
+        extra_bytes: usize,
+        initial: impl FnOnce() -> T,
+    ) -> SharedMutex<T> {
+        unsafe { Self::try_new_sized(name, extra_bytes, initial) }
+            .unwrap_or_else(|e| panic!("{e:#}"))
+    }
+
+    /// Like [`Self::new_sized`], but returns an error instead of panicking, like
+    /// [`Self::try_open`] does for [`Self::new`].
+    ///
+    /// # Safety
+    ///
+    /// As for [`Self::new_sized`].
+    pub unsafe fn try_new_sized(
+        name: &str,
+        extra_bytes: usize,
+        initial: impl FnOnce() -> T,
+    ) -> anyhow::Result<SharedMutex<T>> {
+        let memory =
+            shared_mem::get_memory_with_tail::<SharedMutexInner<T>>(name, extra_bytes, false)
+                .with_context(|| format!("opening shared mutex `{name}`"))?;
+        let recover_from_poison = true;
+        let options = SharedMutexOptions {
+            tail: extra_bytes,
+            ..SharedMutexOptions::default()
         };
+        unsafe {
+            Self::attach_memory(
+                memory,
+                name,
+                initial,
+                recover_from_poison,
+                |_| true,
+                &options,
+            )
+        }
+        .with_context(|| format!("opening shared mutex `{name}`"))
+        .map(|attached| attached.mutex)
+    }
 
-        match owner_died {
-            false => Ok(SharedMutex {
+    pub(crate) unsafe fn try_new_inner(
+        name: &str,
+        initial: impl FnOnce() -> T,
+        recover_from_poison: bool,
+        validate: impl FnOnce(&T) -> bool,
+        options: &SharedMutexOptions,
+    ) -> anyhow::Result<Attached<T>> {
+        let memory = shared_mem::get_memory_with_tail::<SharedMutexInner<T>>(
+            name,
+            options.tail,
+            options.prefault,
+        )?;
+        unsafe {
+            Self::attach_memory(
                 memory,
-                _quacks_like_a: PhantomData,
-            }),
-            true => Err(SharedMutex {
+                name,
+                initial,
+                recover_from_poison,
+                validate,
+                options,
+            )
+        }
+    }
+
+    /// The mutex already in `memory`, if that's a finished one for this `T`. Nothing is
+    /// initialized or locked, for handles that can only find a mutex, not create one.
+    pub(crate) fn from_existing(memory: ShmemWrapper) -> Option<SharedMutex<T>> {
+        let inner = unsafe { &*memory.pointer().cast::<SharedMutexInner<T>>() };
+        let ready = inner.header.is_current()
+            && inner.header.fingerprint.load(Ordering::Relaxed) == type_fingerprint::<T>()
+            && inner.init.load(Ordering::Acquire) == INIT_DONE;
+        ready.then_some(SharedMutex {
+            memory,
+            _quacks_like_a: PhantomData,
+        })
+    }
+
+    unsafe fn attach_memory(
+        memory: ShmemWrapper,
+        name: &str,
+        initial: impl FnOnce() -> T,
+        recover_from_poison: bool,
+        validate: impl FnOnce(&T) -> bool,
+        options: &SharedMutexOptions,
+    ) -> anyhow::Result<Attached<T>> {
+        let shared_mutex: *mut SharedMutexInner<T> = memory.pointer().cast();
+        let fingerprint = type_fingerprint::<T>();
+        let (owner_died, valid) = unsafe {
+            SharedMutexInner::attach(
+                shared_mutex,
+                name,
+                fingerprint,
+                initial,
+                recover_from_poison,
+                validate,
+                options,
+            )?
+        };
+
+        Ok(Attached {
+            mutex: SharedMutex {
                 memory,
                 _quacks_like_a: PhantomData,
-            }),
+            },
+            owner_died,
+            valid,
+        })
+    }
+
+    /// Like [`Self::new`], but if `name` already holds an initialized value, `validate` is
+    /// run on it (under the lock) before handing back the mutex. This catches segments left
+    /// in a bad state by a buggy previous version even when the lock wasn't poisoned.
+    ///
+    /// # Panics
+    ///
+    /// Like [`Self::new`], if the shared memory can't be opened or `name` was created for a
+    /// different `T`.
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T`
+    pub unsafe fn new_validated(
+        name: &str,
+        initial: impl FnOnce() -> T,
+        validate: impl FnOnce(&T) -> bool,
+    ) -> Result<SharedMutex<T>, CorruptData> {
+        let recover_from_poison = true;
+        let attached = unsafe { Self::new_inner(name, initial, recover_from_poison, validate) };
+        match attached.valid {
+            true => Ok(attached.mutex),
+            false => Err(CorruptData),
         }
     }
 
+    /// Like [`Self::new`], but if the previous owner died holding the lock, the value it
+    /// left behind is only replaced with `initial()` if `validate` rejects it. An owner
+    /// that crashed outside its critical section, or after finishing its writes, doesn't
+    /// cost everyone the data.
+    ///
+    /// `validate` and any reinitialization run under the lock, right after this process
+    /// acquires it and sees the death, so no other process can observe the rejected value
+    /// in between. It sees every write the dead owner completed, but nothing records how
+    /// far through an update it got, so `validate` has to be able to tell a half-written
+    /// value from a finished one. Once the value is kept or replaced, the poison is
+    /// cleared and later locks succeed normally.
+    ///
+    /// # Panics
+    ///
+    /// Like [`Self::new`], if the shared memory can't be opened or `name` was created for a
+    /// different `T`.
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T`
+    pub unsafe fn new_keep_valid(
+        name: &str,
+        initial: impl FnOnce() -> T,
+        validate: impl FnOnce(&T) -> bool,
+    ) -> SharedMutex<T> {
+        let recover_from_poison = true;
+        let options = SharedMutexOptions {
+            keep_valid_on_poison: true,
+            ..SharedMutexOptions::default()
+        };
+        unsafe { Self::try_new_inner(name, initial, recover_from_poison, validate, &options) }
+            .unwrap_or_else(|e| panic!("`{name}`: {e:#}"))
+            .mutex
+    }
+
     /// A new mutex with `name` that any process can use. If `name` is not allocated yet
     /// then this function will allocate. In addition, if the mutex is unitialized
     /// then `initial` will lazily be used as the init value. If the mutex is poisoned
     /// it'll be returned as an error.
     ///
+    /// # Panics
+    ///
+    /// Like [`Self::new`], if the shared memory can't be opened or `name` was created for a
+    /// different `T`.
+    ///
     /// # Safety
     ///
     /// The caller should ensure that for a given name all callers of this function
@@ -83,9 +537,18 @@ where
         initial: impl FnOnce() -> T,
     ) -> Result<SharedMutex<T>, SharedMutex<T>> {
         let recover_from_poison = false;
-        unsafe { Self::try_new_inner(name, initial, recover_from_poison) }
+        let attached = unsafe { Self::new_inner(name, initial, recover_from_poison, |_| true) };
+        match attached.owner_died {
+            false => Ok(attached.mutex),
+            true => Err(attached.mutex),
+        }
     }
 
+    /// # Panics
+    ///
+    /// Like [`Self::new`], if the shared memory can't be opened or `name` was created for a
+    /// different `T`.
+    ///
     /// # Safety
     ///
     /// The caller should ensure that for a given name all callers of this function
@@ -93,9 +556,184 @@ where
     pub unsafe fn new_with_val(name: &str, initial: T) -> SharedMutex<T> {
         unsafe { Self::new(name, || initial) }
     }
+
+    /// Copies the value out under the lock and drops this handle. If the lock was
+    /// poisoned the value is still returned, inside the error. Other handles to `name`,
+    /// in this process or others, keep working.
+    pub fn into_inner(self) -> LockResult<T>
+    where
+        T: Copy,
+    {
+        self.read_snapshot()
+    }
+
+    /// The value, without locking. `&mut self` only rules out other users of this handle,
+    /// so this is meant for setting up a segment before anything else can see it, e.g.
+    /// right after creating it and before spawning the processes that share it.
+    ///
+    /// # Safety
+    ///
+    /// No other handle to `name`, in this process or any other, may access the value
+    /// while the returned reference is alive.
+    pub unsafe fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    /// [`SharedMutexInner::lock`], which the rest of the locking methods are reached
+    /// through as well, by deref.
+    pub fn lock(&self) -> LockResult<SharedGuard<'_, T>> {
+        (**self).lock()
+    }
+
+    /// [`SharedMutexInner::try_lock`]
+    pub fn try_lock(&self) -> Result<Option<SharedGuard<'_, T>>, LockError<SharedGuard<'_, T>>> {
+        (**self).try_lock()
+    }
+
+    /// [`SharedMutexInner::lock_until`] `timeout` from now, or [`SharedMutexInner::lock`]
+    /// if that's further out than an [`Instant`] goes.
+    pub fn lock_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<Option<SharedGuard<'_, T>>, LockError<SharedGuard<'_, T>>> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.lock_until(deadline),
+            None => self.lock().map(Some),
+        }
+    }
+}
+
+pub(crate) struct Attached<T: SharedMemorySafe> {
+    pub(crate) mutex: SharedMutex<T>,
+    owner_died: bool,
+    /// `false` if the existing value was rejected by the caller's validator
+    valid: bool,
+}
+
+/// The value already stored under a name was rejected by the caller's validator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptData;
+
+impl std::fmt::Display for CorruptData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("shared data failed validation")
+    }
+}
+
+impl std::error::Error for CorruptData {}
+
+/// Why a lock method didn't hand back a plain guard, like [`std::sync::PoisonError`] with
+/// room for a lock that can't be taken at all. `G` is whatever the method returns on
+/// success, usually a [`SharedGuard`].
+pub enum LockError<G> {
+    /// The lock is held, but the previous owner died or panicked holding it, or poisoned
+    /// it on purpose, so the value may be half written. Repair it through the guard.
+    Poisoned(G),
+    /// The lock couldn't be taken, e.g. a signal interrupted the wait or the retry budget
+    /// ran out, see [`SharedMutexOptions::max_lock_retries`]. Nothing is held.
+    Failed(io::Error),
+}
+
+/// What the lock methods return, like [`std::sync::LockResult`].
+pub type LockResult<G> = Result<G, LockError<G>>;
+
+impl<G> LockError<G> {
+    /// The guard of a poisoned lock, `None` if taking it failed.
+    pub fn into_poisoned(self) -> Option<G> {
+        match self {
+            LockError::Poisoned(guard) => Some(guard),
+            LockError::Failed(_) => None,
+        }
+    }
+
+    pub fn map<H>(self, f: impl FnOnce(G) -> H) -> LockError<H> {
+        match self {
+            LockError::Poisoned(guard) => LockError::Poisoned(f(guard)),
+            LockError::Failed(e) => LockError::Failed(e),
+        }
+    }
+}
+
+impl<G> std::fmt::Debug for LockError<G> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Poisoned(_) => f.write_str("Poisoned(..)"),
+            LockError::Failed(e) => f.debug_tuple("Failed").field(e).finish(),
+        }
+    }
+}
+
+impl<G> std::fmt::Display for LockError<G> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Poisoned(_) => f.write_str("shared mutex is poisoned"),
+            LockError::Failed(e) => write!(f, "taking the shared mutex failed: {e}"),
+        }
+    }
+}
+
+impl<G> std::error::Error for LockError<G> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LockError::Poisoned(_) => None,
+            LockError::Failed(e) => Some(e),
+        }
+    }
+}
+
+/// Why [`SharedMutexInner::try_lock_reason`] didn't get the lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryLockFailure {
+    /// Held by the thread with this TID, in whichever process it lives. Worth backing off.
+    HeldByOther(pid_t),
+    /// Lost a race with another locker without a clear owner. Worth retrying right away.
+    Contended,
+}
+
+impl std::fmt::Display for TryLockFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TryLockFailure::HeldByOther(tid) => write!(f, "lock is held by thread {tid}"),
+            TryLockFailure::Contended => f.write_str("lost a race for the lock"),
+        }
+    }
+}
+
+impl std::error::Error for TryLockFailure {}
+
+/// The name is already in use by a mutex over a different type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeMismatch;
+
+impl std::fmt::Display for TypeMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("shared memory was created for a different type")
+    }
+}
+
+impl std::error::Error for TypeMismatch {}
+
+/// FNV-1a over the layout and name of `T`. Unlike `DefaultHasher` this is the same for
+/// every build, so processes compiled separately agree on it.
+pub(crate) fn type_fingerprint<T>() -> u64 {
+    let size = size_of::<T>() as u64;
+    let align = align_of::<T>() as u64;
+    let bytes = size.to_le_bytes().into_iter().chain(align.to_le_bytes());
+    bytes
+        .chain(std::any::type_name::<T>().bytes())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+        })
 }
 
 impl<T: Default + SharedMemorySafe> SharedMutex<T> {
+    /// [`Self::new`] with `T::default()`.
+    ///
+    /// # Panics
+    ///
+    /// Like [`Self::new`], if the shared memory can't be opened or `name` was created for a
+    /// different `T`.
+    ///
     /// # Safety
     ///
     /// The caller should ensure that for a given name all callers of this function
@@ -116,67 +754,948 @@ where
     }
 }
 
+/// How many times [`SharedMutexInner::read_seqlock`] tries to read between writes before
+/// taking the lock.
+const SEQLOCK_SPINS: u32 = 1000;
+
+/// How often [`SharedMutexInner::lock_with_liveness`] checks on the owner.
+const LIVENESS_POLL: Duration = Duration::from_millis(10);
+
+/// The longest [`SharedMutexInner::wait_until_free`] sleeps between looks at the lock.
+const FREE_POLL: Duration = Duration::from_millis(1);
+
+/// How often [`SharedMutex::attach_existing`] looks for a segment that isn't ready yet.
+const ATTACH_POLL: Duration = Duration::from_millis(10);
+
+/// Identifies a segment as a `SharedMutexInner` of this layout version. Anything else,
+/// e.g. a stale segment from an older build under a reused name, is reinitialized
+/// instead of being interpreted as valid.
+#[repr(C)]
+pub(crate) struct Header {
+    pub(crate) magic: AtomicU32,
+    pub(crate) version: AtomicU32,
+    /// See `type_fingerprint`, written before `init` is set
+    pub(crate) fingerprint: AtomicU64,
+}
+
+impl Header {
+    pub(crate) const MAGIC: u32 = u32::from_be_bytes(*b"SMTX");
+    /// Bump whenever the layout of `SharedMutexInner` changes. C code built against the
+    /// `capi` feature relies on it, see `capi.rs`.
+    pub(crate) const VERSION: u32 = 12;
+
+    pub(crate) fn is_current(&self) -> bool {
+        self.magic.load(Ordering::Relaxed) == Self::MAGIC
+            && self.version.load(Ordering::Relaxed) == Self::VERSION
+    }
+
+    fn stamp(&self, fingerprint: u64) {
+        self.fingerprint.store(fingerprint, Ordering::Relaxed);
+        self.magic.store(Self::MAGIC, Ordering::Relaxed);
+        self.version.store(Self::VERSION, Ordering::Relaxed);
+    }
+}
+
 #[repr(C)]
 pub struct SharedMutexInner<T> {
-    futex: PiMutex,
-    init: bool,
-    data: UnsafeCell<T>,
+    pub(crate) header: Header,
+    pub(crate) futex: PiMutex,
+    pub(crate) metrics: LockMetrics,
+    /// TID of the most recent owner. The kernel wipes the TID from the futex word when it
+    /// cleans up after a dead owner, so this is where `last_dead_owner` comes from.
+    last_owner: AtomicU32,
+    last_dead_owner: AtomicU32,
+    /// Set by a guard dropped during a panic. Unlocking can't leave `FUTEX_OWNER_DIED` in
+    /// the futex word for the next owner, so the poison is passed on here instead.
+    panicked: AtomicU32,
+    /// Code passed to [`SharedGuard::poison_with`], 0 for none. Outlives the poisoning
+    /// owner until someone acquires the lock without it being poisoned.
+    poison_reason: AtomicU32,
+    /// `CLOCK_MONOTONIC` time of the most recent acquisition, see
+    /// [`SharedMutexInner::lock_with_liveness`]
+    acquired_at_ns: AtomicU64,
+    /// Bytes after the struct, see [`SharedMutex::new_sized`]. Set by the creator.
+    tail_len: AtomicU64,
+    /// See [`SharedMutexOptions::max_lock_retries`]. Set by the creator.
+    max_lock_retries: AtomicU32,
+    /// The name the creator opened the segment by, NUL padded, see [`Self::name`]
+    name: [u8; NAME_LEN],
+    /// Non-zero if `checksum` is kept, see [`SharedMutexOptions::checksum`]. Set by the
+    /// creator.
+    checksummed: AtomicU32,
+    /// CRC-32 of `data` as the last owner left it
+    checksum: AtomicU32,
+    /// Non-zero if `sequence` is kept, see [`SharedMutexOptions::seqlock`]. Set by the
+    /// creator.
+    seqlocked: AtomicU32,
+    /// Odd while an owner may be writing `data`, see [`Self::read_seqlock`]
+    sequence: AtomicU32,
+    pub(crate) fair: FairQueue,
+    /// 0 in a new segment, then [`INIT_RUNNING`] and [`INIT_DONE`]
+    init: AtomicU32,
+    pub(crate) data: UnsafeCell<T>,
+}
+
+/// The [`SharedGuard::poison_reason`] of a lock whose value doesn't match the checksum
+/// its last owner left, see [`SharedMutexOptions::checksum`]. Reserved, don't pass it to
+/// [`SharedGuard::poison_with`].
+pub const CHECKSUM_MISMATCH: u32 = u32::MAX;
+
+/// CRC-32 (IEEE) lookup table, one entry per byte value.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(bytes: impl Iterator<Item = u8>) -> u32 {
+    !bytes.fold(!0, |crc, byte| {
+        CRC32_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// The bytes of `*data`, read one at a time with atomic loads, so writes racing them
+/// make for a wrong value instead of undefined behavior. `T` must have no padding.
+fn atomic_bytes<T>(data: &UnsafeCell<T>) -> impl Iterator<Item = u8> + '_ {
+    let start = data.get().cast::<u8>();
+    (0..size_of::<T>())
+        .map(move |i| unsafe { AtomicU8::from_ptr(start.add(i)) }.load(Ordering::Relaxed))
+}
+
+/// Checksum of the bytes of `*data`, which is [`Pod`] if it's kept at all, see
+/// [`SharedMutexOptions::checksum`]. Read with atomic loads, because the whole point is to
+/// catch writes that race the lock.
+fn checksum_of<T>(data: &UnsafeCell<T>) -> u32 {
+    crc32(atomic_bytes(data))
+}
+
+/// Longest name kept in the segment, longer ones are cut at a character boundary.
+pub const NAME_LEN: usize = 64;
+
+/// `name` cut to at most [`NAME_LEN`] bytes without splitting a character.
+fn truncate_name(name: &str) -> &str {
+    let mut end = name.len().min(NAME_LEN);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/// Someone holding the lock is running `initial`. Seen by the next owner only if they died
+/// or panicked doing so, leaving `data` unfinished.
+const INIT_RUNNING: u32 = 1;
+const INIT_DONE: u32 = 2;
+
+#[cfg(test)]
+thread_local! {
+    /// Makes `attach` exit the process right after writing a new value, before marking it
+    /// done, like a crash at the worst moment.
+    pub(crate) static CRASH_BEFORE_INIT_DONE: std::cell::Cell<bool> =
+        const { std::cell::Cell::new(false) };
+}
+
+/// Releases the lock `attach` holds if `initial` panics, instead of leaving it held by a
+/// thread that's still alive.
+struct UnlockOnUnwind<'a>(&'a PiMutex);
+
+impl Drop for UnlockOnUnwind<'_> {
+    fn drop(&mut self) {
+        unsafe { self.0.unlock() };
+    }
 }
 
 unsafe impl<T: SharedMemorySafe> Send for SharedMutexInner<T> {}
 unsafe impl<T: SharedMemorySafe> Sync for SharedMutexInner<T> {}
 
 impl<T: SharedMemorySafe> SharedMutexInner<T> {
-    pub fn lock(&self) -> Result<SharedGuard<'_, T>, SharedGuard<'_, T>> {
-        match self.futex.lock_inner(None, true) {
-            Ok(()) => Ok(SharedGuard {
-                data: &self.data,
-                futex: &self.futex,
-            }),
-            Err(_) => Err(SharedGuard {
-                data: &self.data,
-                futex: &self.futex,
-            }),
+    /// Initializes `*this` under its lock if it's new, poisoned (and `recover_from_poison`)
+    /// or unrecognized, and reports `(owner_died, valid)`. Everything that maps a
+    /// `SharedMutexInner` into shared memory goes through here. Fails if the lock can't be
+    /// taken, or with a [`TypeMismatch`] if `*this` was set up for another type.
+    pub(crate) unsafe fn attach(
+        this: *mut Self,
+        name: &str,
+        fingerprint: u64,
+        initial: impl FnOnce() -> T,
+        recover_from_poison: bool,
+        validate: impl FnOnce(&T) -> bool,
+        options: &SharedMutexOptions,
+    ) -> anyhow::Result<(bool, bool)> {
+        unsafe {
+            // e.g. this thread holding it already, or the retries running out
+            let mut acquired = (*this)
+                .futex
+                .lock_inner(None, false, DEFAULT_MAX_RETRIES)
+                .context("taking the lock to initialize")?;
+            let recognized = (*this).header.is_current();
+            if recognized && (*this).header.fingerprint.load(Ordering::Relaxed) != fingerprint {
+                (*this).record_owner(&acquired);
+                (*this).futex.unlock();
+                return Err(TypeMismatch.into());
+            }
+            acquired.owner_died |= (*this).panicked.swap(0, Ordering::Relaxed) != 0;
+            let owner_died = acquired.owner_died;
+            let fresh = (*this).init.load(Ordering::Acquire) != INIT_DONE || !recognized;
+            let poisoned = owner_died && recover_from_poison;
+            let (reinit, valid) = if fresh || (poisoned && !options.keep_valid_on_poison) {
+                (true, true)
+            } else {
+                // a poisoned value that passes is kept, one that fails is replaced
+                let valid = validate(&*(*this).data.get());
+                (poisoned && !valid, valid || poisoned)
+            };
+            if reinit {
+                // A process can die between any two of these stores, and the next owner
+                // goes by `init` alone to tell a finished value from a torn one. The
+                // fences keep the value's stores strictly between the two `init` stores.
+                (*this).init.store(INIT_RUNNING, Ordering::Relaxed);
+                fence(Ordering::SeqCst);
+                let unlock_on_unwind = UnlockOnUnwind(&(*this).futex);
+                let value = initial();
+                std::mem::forget(unlock_on_unwind);
+                // only a recognized segment can have readers already
+                let sequenced = recognized && (*this).seqlocked.load(Ordering::Relaxed) != 0;
+                if sequenced {
+                    (*this).begin_write();
+                }
+                let data = &raw mut (*this).data;
+                data.write(UnsafeCell::new(value));
+                if sequenced {
+                    (*this).sequence.fetch_add(1, Ordering::Release);
+                }
+                (*this)
+                    .checksum
+                    .store(checksum_of(&*data), Ordering::Relaxed);
+                if !recognized {
+                    // nothing else in the segment can be trusted either
+                    (&raw mut (*this).metrics).write(LockMetrics::default());
+                    (*this).last_owner.store(0, Ordering::Relaxed);
+                    (*this).last_dead_owner.store(0, Ordering::Relaxed);
+                    (*this).poison_reason.store(0, Ordering::Relaxed);
+                    let name = truncate_name(name).as_bytes();
+                    let mut stored = [0; NAME_LEN];
+                    stored[..name.len()].copy_from_slice(name);
+                    (&raw mut (*this).name).write(stored);
+                    (*this)
+                        .tail_len
+                        .store(options.tail as u64, Ordering::Relaxed);
+                    let max_retries = options.max_lock_retries.unwrap_or(DEFAULT_MAX_RETRIES);
+                    (*this)
+                        .max_lock_retries
+                        .store(max_retries, Ordering::Relaxed);
+                    (*this)
+                        .checksummed
+                        .store(options.checksum.is_some().into(), Ordering::Relaxed);
+                    (*this)
+                        .seqlocked
+                        .store(options.seqlock.into(), Ordering::Relaxed);
+                    (*this).sequence.store(0, Ordering::Relaxed);
+                    let fair = &raw mut (*this).fair;
+                    fair.write(FairQueue::default());
+                    (*fair)
+                        .enabled
+                        .store(options.fair.into(), Ordering::Relaxed);
+                    (*this).header.stamp(fingerprint);
+                }
+                #[cfg(test)]
+                if CRASH_BEFORE_INIT_DONE.get() {
+                    libc::_exit(1);
+                }
+                fence(Ordering::SeqCst);
+                (*this).init.store(INIT_DONE, Ordering::Release);
+            }
+            // the init lock isn't taken for the user, so it stays out of the metrics
+            (*this).record_owner(&acquired);
+            (*this).futex.unlock();
+            Ok((owner_died, valid))
+        }
+    }
+
+    /// The name the creating process opened this mutex by, cut to [`NAME_LEN`] bytes, for
+    /// telling locks apart in diagnostics. Elements of a [`SharedMutexArray`] are named
+    /// `name[i]`.
+    ///
+    /// [`SharedMutexArray`]: crate::SharedMutexArray
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+        // written whole before the header is stamped, so it's only ever what we put there
+        std::str::from_utf8(&self.name[..len]).unwrap_or_default()
+    }
+
+    /// Blocks until the lock is free and takes it. [`LockError::Poisoned`] means the
+    /// previous owner died or panicked holding it, and [`LockError::Failed`] that it
+    /// couldn't be taken, e.g. because a signal interrupted the wait, or with
+    /// [`io::ErrorKind::Deadlock`] because this thread already holds it, as for the other
+    /// blocking lock methods. See [`Self::lock_nested`] for locking from code that may.
+    pub fn lock(&self) -> LockResult<SharedGuard<'_, T>> {
+        match self.acquire(true) {
+            Ok(acquired) => match acquired.owner_died {
+                false => Ok(self.guard(true)),
+                true => Err(LockError::Poisoned(self.guard(true))),
+            },
+            Err(e) => Err(LockError::Failed(e)),
+        }
+    }
+
+    /// Like [`Self::lock`], configured for this call, see [`LockConfig`]. Spinning tries
+    /// the lock like [`Self::try_lock`] whenever it sees it free, and poison found that way
+    /// is reported just the same.
+    pub fn lock_with(&self, config: LockConfig) -> LockResult<SharedGuard<'_, T>> {
+        for _ in 0..config.spins {
+            // only the load while it's held, the compare-exchange would bounce the line
+            if !self.futex.is_locked() {
+                match self.try_lock() {
+                    Ok(Some(guard)) => return Ok(guard),
+                    Ok(None) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            std::hint::spin_loop();
+        }
+        self.lock()
+    }
+
+    /// Like [`Self::lock`], but signals never make it give up waiting, it just resumes.
+    /// Retrying is what most callers would do with an interrupted lock anyway.
+    pub fn lock_uninterruptible(&self) -> LockResult<SharedGuard<'_, T>> {
+        match self.acquire(false) {
+            Ok(acquired) => match acquired.owner_died {
+                false => Ok(self.guard(true)),
+                true => Err(LockError::Poisoned(self.guard(true))),
+            },
+            Err(e) => Err(LockError::Failed(e)),
+        }
+    }
+
+    /// Like [`Self::lock`], but if the current thread already holds the lock the returned
+    /// guard is a no-op that doesn't release on drop; only the outermost guard unlocks.
+    ///
+    /// This is meant for strictly nested use, e.g. a helper that locks a mutex its caller
+    /// may already hold.
+    ///
+    /// # Safety
+    ///
+    /// Both guards deref mutably to the same value. If the current thread already holds
+    /// the lock, no reference obtained through the outer guard may be used while the
+    /// nested guard is alive, nor one obtained through the nested guard afterwards.
+    pub unsafe fn lock_nested(&self) -> LockResult<SharedGuard<'_, T>> {
+        if self.futex.is_locked_by_me() {
+            return Ok(self.guard(false));
         }
+        self.lock()
     }
 
-    /// Locks and ignores if the lock was poisoned or not
+    /// Like [`Self::lock`], for a critical section that only reads: the lock is just as
+    /// exclusive, but the guard only hands out `&T`, so an accidental write doesn't
+    /// compile. For readers that shouldn't exclude each other see [`SharedRwLock`].
+    /// Since nothing is written, a panic while the guard is held doesn't poison.
+    ///
+    /// [`SharedRwLock`]: crate::SharedRwLock
+    pub fn lock_shared_read(&self) -> LockResult<ReadGuard<'_, T>> {
+        match self.acquire(true) {
+            Ok(acquired) => match acquired.owner_died {
+                false => Ok(ReadGuard(self.read_guard())),
+                true => Err(LockError::Poisoned(ReadGuard(self.read_guard()))),
+            },
+            Err(e) => Err(LockError::Failed(e)),
+        }
+    }
+
+    /// Locks, and if the previous owner died holding the lock, hands the possibly half
+    /// written data to `repair` before returning the guard. `repair` runs with the lock
+    /// held. The owner-died state is cleared either way, so the next locker sees a clean
+    /// lock. Fails, without calling `repair`, if the lock couldn't be taken, see
+    /// [`LockError::Failed`].
+    pub fn lock_or_repair<F: FnOnce(&mut T)>(&self, repair: F) -> io::Result<SharedGuard<'_, T>> {
+        let acquired = self.acquire(false)?;
+        let mut guard = self.guard(true);
+        if acquired.owner_died {
+            repair(&mut guard);
+        }
+        Ok(guard)
+    }
+
+    /// Runs `f` with the lock held and unlocks when it returns, early or by panicking, so
+    /// the guard can't be forgotten or held across an `.await`. If the lock was poisoned
+    /// `f` still runs, and its result comes back inside [`LockError::Poisoned`].
+    pub fn with_lock<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> LockResult<R> {
+        match self.lock() {
+            Ok(mut guard) => Ok(f(&mut guard)),
+            Err(e) => Err(e.map(|mut guard| f(&mut guard))),
+        }
+    }
+
+    /// A copy of the value, taken under the lock and released right away, for work that
+    /// doesn't need to hold the lock while it runs. If the lock was poisoned the copy
+    /// comes back inside [`LockError::Poisoned`].
+    pub fn read_snapshot(&self) -> LockResult<T>
+    where
+        T: Copy,
+    {
+        self.with_lock(|value| *value)
+    }
+
+    /// A copy of the value read without taking the lock, for a mutex created with
+    /// [`SharedMutexOptions::seqlock`]. Readers never block writers or each other: the
+    /// copy is retried until no owner wrote to the value while it was taken. It's taken
+    /// with atomic loads, since the writes it races are plain ones, which is also why `T`
+    /// has to be [`Pod`]: a torn copy is still a value, just one that gets thrown away.
+    ///
+    /// An owner that's been writing for a while, or that died or poisoned the lock while
+    /// writing, leaves the sequence odd for good, and after a short spin the reader falls
+    /// back to [`Self::read_snapshot`], which waits for a live owner and reports a dead or
+    /// poisoning one as poison. That's also all this does without the option. Writes that
+    /// bypass the lock, like [`Self::compare_exchange`], aren't seen as writes.
+    pub fn read_seqlock(&self) -> LockResult<T>
+    where
+        T: Pod,
+    {
+        if self.seqlocked.load(Ordering::Relaxed) != 0 {
+            for _ in 0..SEQLOCK_SPINS {
+                let before = self.sequence.load(Ordering::Acquire);
+                if before & 1 == 0 {
+                    let mut copy = MaybeUninit::<T>::uninit();
+                    let bytes = copy.as_mut_ptr().cast::<u8>();
+                    for (i, byte) in atomic_bytes(&self.data).enumerate() {
+                        unsafe { bytes.add(i).write(byte) };
+                    }
+                    fence(Ordering::Acquire);
+                    if self.sequence.load(Ordering::Relaxed) == before {
+                        return Ok(unsafe { copy.assume_init() });
+                    }
+                }
+                std::hint::spin_loop();
+            }
+        }
+        self.read_snapshot()
+    }
+
+    /// Like [`Self::try_lock`], but waits for the lock until `deadline`. `Ok(None)` means
+    /// the deadline passed. Signals don't interrupt the wait or push the deadline back.
+    pub fn lock_until(
+        &self,
+        deadline: Instant,
+    ) -> Result<Option<SharedGuard<'_, T>>, LockError<SharedGuard<'_, T>>> {
+        match self.acquire_until(Some(deadline), false) {
+            Ok(acquired) => match acquired.owner_died {
+                false => Ok(Some(self.guard(true))),
+                true => Err(LockError::Poisoned(self.guard(true))),
+            },
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(LockError::Failed(e)),
+        }
+    }
+
+    /// Like [`Self::lock`], but if the lock has been held for longer than `max_held`, the
+    /// owner is assumed to be hung and the lock is taken from it as if it had died, which
+    /// returns [`LockError::Poisoned`]. Polls instead of blocking in the kernel, since a
+    /// waiter there would keep the lock from being taken over.
+    ///
+    /// The owner can't be asked whether it's hung. One that's merely slow loses the lock
+    /// while it still thinks it holds it. Only use this when the owner is certain to be
+    /// killed or to never touch a lock again, e.g. a supervisor about to restart it.
+    /// Owners with threads waiting in the kernel (from [`Self::lock`]) can't be taken
+    /// over, and neither can a stuck turn of a fair mutex.
+    ///
+    /// # Safety
+    ///
+    /// If the owner is still running, anything it does with the data from then on races
+    /// with the new owner. Its robust list also ends up pointing into the new owner's, so
+    /// it must not lock or unlock any mutex again.
+    pub unsafe fn lock_with_liveness(&self, max_held: Duration) -> LockResult<SharedGuard<'_, T>> {
+        loop {
+            match self.try_lock() {
+                Ok(Some(guard)) => return Ok(guard),
+                Ok(None) => {}
+                Err(e) => return Err(e),
+            }
+            let acquired_at = self.acquired_at_ns.load(Ordering::Relaxed);
+            let held = Duration::from_nanos(monotonic_ns().saturating_sub(acquired_at));
+            if held > max_held {
+                self.take_over_hung_owner();
+            }
+            std::thread::sleep(LIVENESS_POLL.min(max_held));
+        }
+    }
+
+    /// Blocks until nobody holds the lock, without taking it, e.g. for an observer of a
+    /// leader election that acts once the leader lets go. Someone may well have taken it
+    /// again by the time this returns. A lock whose owner died counts as free. Fails with
+    /// [`io::ErrorKind::TimedOut`] after `timeout`, if any, and with
+    /// [`io::ErrorKind::Deadlock`] if the calling thread holds the lock.
+    ///
+    /// This polls, sleeping for up to a millisecond in between, rather than `FUTEX_WAIT` on
+    /// the futex word: `FUTEX_UNLOCK_PI` never wakes such waiters, and while any are queued
+    /// the kernel refuses `FUTEX_LOCK_PI` on the word with `EINVAL`.
+    pub fn wait_until_free(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if self.futex.is_locked_by_me() {
+            return Err(io::Error::new(
+                io::ErrorKind::Deadlock,
+                "the calling thread holds the lock it waits to be free",
+            ));
+        }
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        let mut sleep = Duration::from_micros(10);
+        while self.futex.owner_tid().is_some() {
+            let nap = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(io::ErrorKind::TimedOut.into());
+                    }
+                    sleep.min(left)
+                }
+                None => sleep,
+            };
+            std::thread::sleep(nap);
+            sleep = (sleep * 2).min(FREE_POLL);
+        }
+        Ok(())
+    }
+
+    /// Replaces the value with `new` if it's bitwise equal to `current`, as one atomic
+    /// instruction instead of a lock round trip. Returns the previous value, in `Ok` if
+    /// it was replaced. Poisoning is left alone: the next [`Self::lock`] still reports it.
+    ///
+    /// Only for `T` of 1, 2, 4 or 8 bytes aligned to their size, which is checked at
+    /// compile time.
+    ///
+    /// # Safety
+    ///
+    /// This bypasses the futex, and with it priority inheritance and mutual exclusion. It
+    /// must not run while any thread, in any process, holds the lock and accesses the
+    /// value through a guard.
+    pub unsafe fn compare_exchange(&self, current: T, new: T) -> Result<T, T>
+    where
+        T: Pod,
+    {
+        const {
+            let size = size_of::<T>();
+            assert!(
+                matches!(size, 1 | 2 | 4 | 8) && align_of::<T>() >= size,
+                "compare_exchange needs a T of 1, 2, 4 or 8 bytes aligned to its size"
+            );
+        }
+        macro_rules! cas {
+            ($atomic:ty, $int:ty) => {{
+                let word = unsafe { <$atomic>::from_ptr(self.data.get().cast()) };
+                let current = unsafe { std::mem::transmute_copy::<T, $int>(&current) };
+                let new = unsafe { std::mem::transmute_copy::<T, $int>(&new) };
+                word.compare_exchange(current, new, Ordering::AcqRel, Ordering::Acquire)
+                    .map(|v| unsafe { std::mem::transmute_copy::<$int, T>(&v) })
+                    .map_err(|v| unsafe { std::mem::transmute_copy::<$int, T>(&v) })
+            }};
+        }
+        match size_of::<T>() {
+            1 => cas!(AtomicU8, u8),
+            2 => cas!(AtomicU16, u16),
+            4 => cas!(AtomicU32, u32),
+            _ => cas!(AtomicU64, u64),
+        }
+    }
+
+    /// A copy of the value read straight from the segment, without waiting for the lock or
+    /// caring who holds it, for monitoring that must never block, e.g. on an owner stuck
+    /// in a long call. A dead owner's leftovers are returned as they are, and poisoning
+    /// isn't reported. `T` has to be [`Pod`] because a dead owner may have left a write
+    /// half done, and any mix of bytes is still a `T`, if a meaningless one.
+    ///
+    /// A `T` of 1, 2, 4 or 8 bytes aligned to its size is read with one atomic load, so
+    /// it may race with [`Self::compare_exchange`]. For consistent copies without blocking
+    /// writers see [`Self::read_seqlock`].
+    ///
+    /// # Safety
+    ///
+    /// No thread, in any process, may write the value through a guard while this reads
+    /// it. That's a data race, undefined behavior even though any bytes make a `T`. Owners
+    /// that hold the lock without writing don't matter.
+    #[cfg(feature = "unsafe_peek")]
+    pub unsafe fn peek(&self) -> T
+    where
+        T: Pod,
+    {
+        macro_rules! load {
+            ($atomic:ty, $int:ty) => {{
+                let word = unsafe { <$atomic>::from_ptr(self.data.get().cast()) };
+                unsafe { std::mem::transmute_copy::<$int, T>(&word.load(Ordering::Relaxed)) }
+            }};
+        }
+        match (size_of::<T>(), align_of::<T>()) {
+            (1, _) => load!(AtomicU8, u8),
+            (2, 2..) => load!(AtomicU16, u16),
+            (4, 4..) => load!(AtomicU32, u32),
+            (8, 8..) => load!(AtomicU64, u64),
+            _ => unsafe { self.data.get().read_volatile() },
+        }
+    }
+
+    /// Locks and ignores if the lock was poisoned or not. Signals don't interrupt the wait,
+    /// like [`Self::lock_uninterruptible`].
+    ///
+    /// # Panics
+    ///
+    /// If the lock can't be taken, see [`LockError::Failed`].
     pub fn grab(&self) -> SharedGuard<'_, T> {
-        let _ = self.futex.lock();
+        match self.lock_uninterruptible() {
+            Ok(guard) | Err(LockError::Poisoned(guard)) => guard,
+            Err(LockError::Failed(e)) => panic!("locking `{}`: {e}", self.name()),
+        }
+    }
 
-        SharedGuard {
-            data: &self.data,
-            futex: &self.futex,
+    /// Takes the lock if it's free, without waiting. `Ok(None)` means someone holds it.
+    /// Poison is reported exactly like [`Self::lock`] reports it: a lock taken over from a
+    /// dead or panicked owner comes back as [`LockError::Poisoned`], never as a silent
+    /// `Ok`.
+    pub fn try_lock(&self) -> Result<Option<SharedGuard<'_, T>>, LockError<SharedGuard<'_, T>>> {
+        let fair = self.fair.is_enabled();
+        if fair && !self.fair.try_take_turn() {
+            return Ok(None);
+        }
+        match lock_try(&self.futex.0, self.max_retries()) {
+            Ok(Some(owner_died)) => {
+                let mut acquired = Acquired {
+                    owner_died,
+                    waited: None,
+                };
+                self.record(&mut acquired);
+                match acquired.owner_died {
+                    false => Ok(Some(self.guard(true))),
+                    true => Err(LockError::Poisoned(self.guard(true))),
+                }
+            }
+            Ok(None) => {
+                // only an attaching process can hold the futex outside the queue
+                if fair {
+                    self.fair.pass_turn();
+                }
+                Ok(None)
+            }
+            Err(e) => {
+                if fair {
+                    self.fair.pass_turn();
+                }
+                Err(LockError::Failed(e))
+            }
         }
     }
 
-    pub fn try_lock(&self) -> Result<Option<SharedGuard<'_, T>>, SharedGuard<'_, T>> {
-        match lock_try(&self.futex.0) {
-            Ok(true) => Ok(Some(SharedGuard {
-                data: &self.data,
-                futex: &self.futex,
-            })),
-            Ok(false) => Ok(None),
-            Err(_) => Err(SharedGuard {
-                data: &self.data,
-                futex: &self.futex,
-            }),
+    /// Like [`Self::try_lock`], for functions returning `io::Result`: a held lock is
+    /// [`io::ErrorKind::WouldBlock`]. A poisoned lock is [`io::ErrorKind::InvalidData`] and
+    /// stays poisoned, reason included, for a [`Self::lock`] to find and repair. Failing
+    /// to take the lock at all returns that error and leaves the poison state alone.
+    pub fn try_lock_io(&self) -> io::Result<SharedGuard<'_, T>> {
+        match self.try_lock() {
+            Ok(Some(guard)) => Ok(guard),
+            Ok(None) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(LockError::Poisoned(guard)) => {
+                guard.repoison();
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "shared mutex is poisoned",
+                ))
+            }
+            Err(LockError::Failed(e)) => Err(e),
         }
     }
 
+    /// Like [`Self::try_lock`], but says why the lock couldn't be taken. The inner result
+    /// is the same as [`Self::lock`]'s.
+    pub fn try_lock_reason(&self) -> Result<LockResult<SharedGuard<'_, T>>, TryLockFailure> {
+        let fair = self.fair.is_enabled();
+        if fair && !self.fair.try_take_turn() {
+            // someone is queued, who may or may not have the futex yet
+            return Err(match self.futex.owner_tid() {
+                Some(tid) => TryLockFailure::HeldByOther(tid),
+                None => TryLockFailure::Contended,
+            });
+        }
+        let failure = match lock_try_observed(&self.futex.0, self.max_retries()) {
+            Ok(Ok(owner_died)) => {
+                let mut acquired = Acquired {
+                    owner_died,
+                    waited: None,
+                };
+                self.record(&mut acquired);
+                return Ok(match acquired.owner_died {
+                    false => Ok(self.guard(true)),
+                    true => Err(LockError::Poisoned(self.guard(true))),
+                });
+            }
+            Ok(Err(word)) => match word & FUTEX_TID_MASK {
+                // released, or only the waiters bit left, between our load and the CAS
+                0 => TryLockFailure::Contended,
+                tid => TryLockFailure::HeldByOther(tid as pid_t),
+            },
+            Err(e) => {
+                if fair {
+                    self.fair.pass_turn();
+                }
+                return Ok(Err(LockError::Failed(e)));
+            }
+        };
+        if fair {
+            self.fair.pass_turn();
+        }
+        Err(failure)
+    }
+
+    /// Poisons the lock on purpose: the next acquirer gets `Err` as if this owner had died,
+    /// and [`SharedMutex::new`] in any process replaces the value with its `initial()`.
+    /// Meant for a coordinating process that decides the data is invalid and everyone has
+    /// to reinitialize, e.g. across a version upgrade, and for tests of poison handling
+    /// that would otherwise have to kill a thread. The same as
+    /// [`SharedGuard::poison_with`] without a reason, for code that has no guard at hand.
+    ///
+    /// `FUTEX_OWNER_DIED` doesn't survive an unlock, the kernel clears the word, so this
+    /// sets the flag a panicking guard sets instead.
+    ///
+    /// # Panics
+    ///
+    /// If the calling thread doesn't hold the lock.
+    pub fn poison(&self) {
+        assert!(
+            self.futex.is_locked_by_me(),
+            "poisoning `{}` without holding it",
+            self.name()
+        );
+        self.poison_reason.store(0, Ordering::Relaxed);
+        self.panicked.store(1, Ordering::Relaxed);
+    }
+
     pub fn is_locked(&self) -> bool {
         self.futex.is_locked()
     }
+
+    /// Whether the calling thread holds the lock. Ownership is by TID, so another thread
+    /// of this process holding it doesn't count. Locking again from the owner fails with
+    /// [`io::ErrorKind::Deadlock`], so check this first where that may happen, or use
+    /// [`Self::lock_nested`].
+    pub fn held_by_current_thread(&self) -> bool {
+        self.futex.is_locked_by_me()
+    }
+
+    /// Counters for this lock across every process using it.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// TID of the thread holding the lock, in whichever process it lives (see
+    /// `/proc/<tid>`). This is only a snapshot, the owner may release right after.
+    pub fn owner_tid(&self) -> Option<pid_t> {
+        self.futex.owner_tid()
+    }
+
+    /// TID of the last owner found to have died holding the lock, if any has. Owners are
+    /// noted right after they acquire, so one that dies before that is attributed to its
+    /// predecessor.
+    pub fn last_dead_owner(&self) -> Option<pid_t> {
+        match self.last_dead_owner.load(Ordering::Relaxed) {
+            0 => None,
+            tid => Some(tid as pid_t),
+        }
+    }
+
+    /// Marks the futex word as owner-died, so the next `lock_try` takes it over the same way
+    /// it would after a crash. Only possible while no one waits in the kernel: then the
+    /// kernel keeps its own record of the owner.
+    fn take_over_hung_owner(&self) {
+        let word = self.futex.0.futex.load(Ordering::Relaxed);
+        if word & FUTEX_TID_MASK == 0 || word & !FUTEX_TID_MASK != 0 {
+            return;
+        }
+        let _ = self.futex.0.futex.compare_exchange(
+            word,
+            FUTEX_OWNER_DIED,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Waits for our turn if the mutex is fair, then takes the futex.
+    pub(crate) fn acquire(&self, signals_fail: bool) -> io::Result<Acquired> {
+        self.acquire_until(None, signals_fail)
+    }
+
+    fn acquire_until(&self, deadline: Option<Instant>, signals_fail: bool) -> io::Result<Acquired> {
+        // A second guard for the same lock would alias the first. A fair mutex would wait
+        // for its own turn forever instead.
+        if self.futex.is_locked_by_me() {
+            return Err(io::Error::new(
+                io::ErrorKind::Deadlock,
+                format!(
+                    "shared mutex `{}` is already held by this thread, see lock_nested",
+                    self.name()
+                ),
+            ));
+        }
+        if self.fair.is_enabled() && !self.fair.wait_turn(deadline) {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let timeout = deadline.map(realtime_deadline);
+        let mut acquired = match self
+            .futex
+            .lock_inner(timeout, signals_fail, self.max_retries())
+        {
+            Ok(acquired) => acquired,
+            Err(e) => {
+                // nothing is held, so no guard will pass the turn on
+                if self.fair.is_enabled() {
+                    self.fair.pass_turn();
+                }
+                return Err(e);
+            }
+        };
+        self.record(&mut acquired);
+        Ok(acquired)
+    }
+
+    /// Unlocks like dropping a guard does, after [`SharedGuard::finish`], for a thread
+    /// that took the lock without one. With `poisoned`, a poisoned lock nobody got a
+    /// guard for stays that way.
+    ///
+    /// # Safety
+    ///
+    /// The calling thread must hold the lock.
+    #[cfg(feature = "async")]
+    pub(crate) unsafe fn unlock(&self, poisoned: bool) {
+        if poisoned {
+            self.panicked.store(1, Ordering::Relaxed);
+        }
+        unsafe { self.futex.unlock() };
+        if self.fair.is_enabled() {
+            self.fair.pass_turn();
+        }
+    }
+
+    fn max_retries(&self) -> u32 {
+        self.max_lock_retries.load(Ordering::Relaxed)
+    }
+
+    /// Bookkeeping for a fresh acquisition, must be called while holding the lock. A guard
+    /// dropped by a panic counts as a dead owner from here on.
+    /// A value found not to match its checksum is reported like a dead owner, with
+    /// [`CHECKSUM_MISMATCH`] as the reason.
+    fn record(&self, acquired: &mut Acquired) {
+        acquired.owner_died |= self.panicked.swap(0, Ordering::Relaxed) != 0;
+        // a dead owner's value is suspect anyway, and its checksum likely stale
+        let corrupted = !acquired.owner_died
+            && self.checksummed.load(Ordering::Relaxed) != 0
+            && self.checksum.load(Ordering::Relaxed) != checksum_of(&self.data);
+        if !acquired.owner_died {
+            let reason = if corrupted { CHECKSUM_MISMATCH } else { 0 };
+            self.poison_reason.store(reason, Ordering::Relaxed);
+        }
+        self.metrics.record(acquired);
+        self.acquired_at_ns.store(monotonic_ns(), Ordering::Relaxed);
+        self.record_owner(acquired);
+        acquired.owner_died |= corrupted;
+    }
+
+    /// The part of [`Self::record`] that keeps `last_owner` and `last_dead_owner`.
+    fn record_owner(&self, acquired: &Acquired) {
+        let previous = self.last_owner.swap(tid() as u32, Ordering::Relaxed);
+        if acquired.owner_died {
+            self.last_dead_owner.store(previous, Ordering::Relaxed);
+        }
+    }
+
+    /// Makes the sequence odd for the writes that follow, if it isn't already because the
+    /// previous writer never finished. Must be called while holding the lock.
+    fn begin_write(&self) {
+        self.sequence.fetch_or(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+    }
+
+    /// [`Self::begin_write`] if the mutex keeps a sequence, which the guard then makes
+    /// even again when it's done.
+    fn begin_sequenced_write(&self) -> Option<&AtomicU32> {
+        (self.seqlocked.load(Ordering::Relaxed) != 0).then(|| {
+            self.begin_write();
+            &self.sequence
+        })
+    }
+
+    /// The bytes after this struct. Only valid to access while holding the lock.
+    pub(crate) fn tail(&self) -> *mut [u8] {
+        let start = (self as *const Self).cast::<u8>().cast_mut();
+        let len = self.tail_len.load(Ordering::Relaxed) as usize;
+        std::ptr::slice_from_raw_parts_mut(start.wrapping_add(size_of::<Self>()), len)
+    }
+
+    /// A guard that unlocks but leaves the sequence alone, since nothing is written
+    /// through it and [`Self::read_seqlock`] readers needn't wait for it. Nor does a panic
+    /// while it's held poison the value.
+    fn read_guard(&self) -> SharedGuard<'_, T> {
+        let mut guard = self.guard(false);
+        guard.release = true;
+        guard.poison_on_panic = false;
+        guard
+    }
+
+    pub(crate) fn guard(&self, release: bool) -> SharedGuard<'_, T> {
+        // any guard may write, a nested one is covered by the outer guard
+        let sequence = match release {
+            true => self.begin_sequenced_write(),
+            false => None,
+        };
+        SharedGuard {
+            data: &self.data,
+            futex: &self.futex,
+            panicked: &self.panicked,
+            poison_reason: &self.poison_reason,
+            tail: self.tail(),
+            fair: self.fair.is_enabled().then_some(&self.fair),
+            checksum: (self.checksummed.load(Ordering::Relaxed) != 0).then_some(&self.checksum),
+            sequence,
+            release,
+            poison_on_panic: !std::thread::panicking(),
+        }
+    }
 }
 
 pub struct SharedGuard<'a, T: SharedMemorySafe> {
     data: &'a UnsafeCell<T>,
     futex: &'a PiMutex,
+    panicked: &'a AtomicU32,
+    poison_reason: &'a AtomicU32,
+    tail: *mut [u8],
+    fair: Option<&'a FairQueue>,
+    /// Updated on unlock if the mutex keeps one
+    checksum: Option<&'a AtomicU32>,
+    /// Made even again on unlock if the mutex keeps one and the value isn't poisoned
+    sequence: Option<&'a AtomicU32>,
+    /// `false` for guards handed out by [`SharedMutexInner::lock_nested`] while already held
+    release: bool,
+    /// `false` for read guards, and for guards taken while already unwinding, e.g. in
+    /// another value's `Drop`, which that panic didn't interrupt
+    poison_on_panic: bool,
 }
 
 impl<'a, T: SharedMemorySafe + std::fmt::Debug> std::fmt::Debug for SharedGuard<'a, T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        <T as std::fmt::Debug>::fmt(self, f)
+        f.debug_struct("SharedGuard")
+            .field("data", &**self)
+            .field("owner", &self.futex.owner_tid())
+            .finish()
+    }
+}
+
+impl<'a, T: SharedMemorySafe + std::fmt::Display> std::fmt::Display for SharedGuard<'a, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        <T as std::fmt::Display>::fmt(self, f)
     }
 }
 
@@ -190,14 +1709,132 @@ impl<T: SharedMemorySafe> Deref for SharedGuard<'_, T> {
     }
 }
 
+impl<T: SharedMemorySafe> SharedGuard<'_, T> {
+    /// The extra bytes of a mutex created with [`SharedMutex::new_sized`], empty for any
+    /// other mutex.
+    pub fn tail(&mut self) -> &mut [u8] {
+        unsafe { &mut *self.tail }
+    }
+
+    /// Poisons the lock like a panic would, recording `code` for the next owner to read
+    /// with [`Self::poison_reason`]. Takes effect immediately, so the code also reaches the
+    /// next owner if this process dies before dropping the guard. A `code` of 0 poisons
+    /// without a reason.
+    ///
+    /// # Panics
+    ///
+    /// If `code` is [`CHECKSUM_MISMATCH`], which is reserved.
+    pub fn poison_with(&self, code: u32) {
+        assert_ne!(code, CHECKSUM_MISMATCH, "`CHECKSUM_MISMATCH` is reserved");
+        self.poison_reason.store(code, Ordering::Relaxed);
+        self.repoison();
+    }
+
+    /// Leaves the lock poisoned as it was found, [`CHECKSUM_MISMATCH`] included, for the
+    /// next owner to repair.
+    pub(crate) fn repoison(&self) {
+        self.panicked.store(1, Ordering::Relaxed);
+    }
+
+    /// The code the previous owner passed to [`Self::poison_with`], if this guard came
+    /// from acquiring a lock it poisoned.
+    pub fn poison_reason(&self) -> Option<u32> {
+        match self.poison_reason.load(Ordering::Relaxed) {
+            0 => None,
+            code => Some(code),
+        }
+    }
+}
+
+impl<T: SharedMemorySafe + AsRef<[u8]>> SharedGuard<'_, T> {
+    /// The bytes of the value in `range`, borrowed straight from the segment for as long
+    /// as the guard lives, e.g. a window into a large `[u8; N]`.
+    ///
+    /// # Panics
+    ///
+    /// If `range` is out of bounds.
+    pub fn slice(&self, range: Range<usize>) -> &[u8] {
+        let bytes = (**self).as_ref();
+        match bytes.get(range.clone()) {
+            Some(window) => window,
+            None => panic!(
+                "range {range:?} is out of bounds for {} shared bytes",
+                bytes.len()
+            ),
+        }
+    }
+}
+
 impl<T: SharedMemorySafe> DerefMut for SharedGuard<'_, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         unsafe { &mut *(*self.data).get() }
     }
 }
 
+impl<T: SharedMemorySafe> SharedGuard<'_, T> {
+    /// What dropping the guard does before unlocking: poisoning if a panic that started
+    /// while it was held is dropping it, like `std::sync::Mutex`, and updating the checksum and the sequence. Also for an `AsyncSharedGuard`, whose
+    /// lock is held by another thread.
+    pub(crate) fn finish(&self) {
+        // the data may be half written; a guard that's leaked instead can't be caught
+        // here and keeps the lock held until the thread exits
+        if self.poison_on_panic && std::thread::panicking() {
+            self.panicked.store(1, Ordering::Relaxed);
+        } else if self.panicked.load(Ordering::Relaxed) == 0 {
+            self.poison_reason.store(0, Ordering::Relaxed);
+        }
+        if let Some(checksum) = self.checksum {
+            checksum.store(checksum_of(self.data), Ordering::Relaxed);
+        }
+        // a poisoned value stays odd, sending readers to the lock to find out
+        if let Some(sequence) = self.sequence
+            && self.panicked.load(Ordering::Relaxed) == 0
+        {
+            sequence.fetch_add(1, Ordering::Release);
+        }
+    }
+}
+
 impl<T: SharedMemorySafe> Drop for SharedGuard<'_, T> {
     fn drop(&mut self) {
-        unsafe { self.futex.unlock() };
+        if self.release {
+            self.finish();
+            unsafe { self.futex.unlock() };
+            if let Some(fair) = self.fair {
+                fair.pass_turn();
+            }
+        }
+    }
+}
+
+/// Guard handed out by [`SharedMutexInner::lock_shared_read`], a [`SharedGuard`] without
+/// `DerefMut`. Dropping it unlocks.
+///
+/// ```compile_fail
+/// # use shared_mutex::SharedMutex;
+/// let mutex = unsafe { SharedMutex::new_with_val("read_guard_doc", 0u32) };
+/// let guard = mutex.lock_shared_read().unwrap();
+/// *guard += 1;
+/// ```
+pub struct ReadGuard<'a, T: SharedMemorySafe>(SharedGuard<'a, T>);
+
+impl<T: SharedMemorySafe + std::fmt::Debug> std::fmt::Debug for ReadGuard<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ReadGuard").field(&self.0).finish()
+    }
+}
+
+impl<T: SharedMemorySafe> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T: SharedMemorySafe> ReadGuard<'_, T> {
+    /// See [`SharedGuard::poison_reason`].
+    pub fn poison_reason(&self) -> Option<u32> {
+        self.0.poison_reason()
     }
 }
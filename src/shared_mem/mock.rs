@@ -1,36 +1,105 @@
+//! Segments on the heap of this process. Miri can't map anything, so [`ShmBackend`] is
+//! this under miri; the `mock_backend` feature makes it available to tests that shouldn't
+//! touch `/dev/shm`. Only threads share these segments, a forked child gets a copy.
+//!
+//! [`ShmBackend`]: super::ShmBackend
+
 use std::{
     alloc::Layout,
     collections::HashMap,
-    path::Path,
-    sync::{Mutex, OnceLock},
+    io,
+    sync::{Arc, Mutex, PoisonError},
 };
 
-use anyhow::Result;
+use crate::shared_mem::{Mapping, MemoryBackend, PageAligned, page_size};
 
-use crate::{
-    shared_data::SharedMutexInner,
-    shared_mem::{PageAligned, SharedMemorySafe, ShmemWrapper},
-};
+/// An allocation standing in for a segment, freed once it's unlinked and unmapped.
+struct Segment {
+    pointer: *mut PageAligned,
+    layout: Layout,
+}
+
+unsafe impl Send for Segment {}
+unsafe impl Sync for Segment {}
 
-pub(super) fn get_memory<T: SharedMemorySafe>(name: &str) -> Result<ShmemWrapper> {
-    #[repr(transparent)]
-    struct SendPtr(*mut PageAligned);
+impl Segment {
+    fn allocate(length: usize) -> io::Result<Self> {
+        // whole pages like a real mapping
+        let size = length.next_multiple_of(page_size());
+        let layout = Layout::from_size_align(size, align_of::<PageAligned>())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let pointer: *mut PageAligned = unsafe { std::alloc::alloc_zeroed(layout) }.cast();
+        if pointer.is_null() {
+            return Err(io::ErrorKind::OutOfMemory.into());
+        }
+        Ok(Self { pointer, layout })
+    }
+}
 
-    unsafe impl Send for SendPtr {}
-    unsafe impl Sync for SendPtr {}
+impl Drop for Segment {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.pointer.cast(), self.layout) };
+    }
+}
 
-    static TEST_MEMORY: OnceLock<Mutex<HashMap<String, SendPtr>>> = OnceLock::new();
+static SEGMENTS: Mutex<Option<HashMap<String, Arc<Segment>>>> = Mutex::new(None);
 
-    let memory_map = TEST_MEMORY.get_or_init(|| Mutex::new(HashMap::new()));
-    let mut map = memory_map.lock().unwrap();
+/// Segments in this process's memory, for hermetic tests. Names are process-wide like
+/// shared memory names, so two `MockBackend`s opening the same name share the segment.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MockBackend;
 
-    if let Some(ptr) = map.get(&name) {
-        return Ok(ShmemWrapper { pointer: ptr.0 });
+impl MemoryBackend for MockBackend {
+    fn get_memory(&self, name: &str, length: usize) -> io::Result<Mapping> {
+        let mut segments = SEGMENTS.lock().unwrap_or_else(PoisonError::into_inner);
+        let segments = segments.get_or_insert_with(HashMap::new);
+        let segment = match segments.get(name) {
+            Some(segment) => segment.clone(),
+            None => {
+                let segment = Arc::new(Segment::allocate(length)?);
+                segments.insert(name.to_owned(), segment.clone());
+                segment
+            }
+        };
+        // other mappings point into the allocation, so it can't be moved to grow it
+        if segment.layout.size() < length {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("mock segment `{name}` is too short and can't grow"),
+            ));
+        }
+        Ok(mapping(segment))
     }
 
-    let layout = Layout::new::<SharedMutexInner<T>>();
-    let raw_ptr = unsafe { std::alloc::alloc_zeroed(layout) as *mut PageAligned };
-    map.insert(name.to_string(), SendPtr(raw_ptr));
+    fn unlink(&self, name: &str) -> io::Result<()> {
+        let mut segments = SEGMENTS.lock().unwrap_or_else(PoisonError::into_inner);
+        match segments.as_mut().and_then(|segments| segments.remove(name)) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+}
+
+#[cfg(miri)]
+pub(super) fn open_existing(name: &str, min_length: usize) -> io::Result<Mapping> {
+    let segments = SEGMENTS.lock().unwrap_or_else(PoisonError::into_inner);
+    match segments.as_ref().and_then(|segments| segments.get(name)) {
+        Some(segment) if segment.layout.size() < min_length => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("mock segment `{name}` is smaller than {min_length} bytes"),
+        )),
+        Some(segment) => Ok(mapping(segment.clone())),
+        None => Err(io::ErrorKind::NotFound.into()),
+    }
+}
+
+/// An allocation of its own, the closest miri gets to an anonymous mapping.
+#[cfg(miri)]
+pub(super) fn get_anonymous_memory(length: usize) -> io::Result<Mapping> {
+    Ok(mapping(Arc::new(Segment::allocate(length)?)))
+}
 
-    Ok(ShmemWrapper { pointer: raw_ptr })
+fn mapping(segment: Arc<Segment>) -> Mapping {
+    let (pointer, length) = (segment.pointer.cast(), segment.layout.size());
+    unsafe { Mapping::new(pointer, length, segment) }
 }
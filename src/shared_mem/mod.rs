@@ -1,61 +1,468 @@
-use std::alloc::Layout;
+use std::{
+    alloc::Layout,
+    any::Any,
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicU8, Ordering},
+    },
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
+use crate::shared_data::{TypeMismatch, type_fingerprint};
+
+#[cfg(any(miri, feature = "mock_backend"))]
+pub use mock::MockBackend;
+#[cfg(all(test, not(miri)))]
+pub(crate) use shmlink::TRUNCATE_BEFORE_MAP;
 #[cfg(not(miri))]
 pub use shmlink::unlink_if_exists;
-#[cfg(not(miri))]
-use shmlink::SharedMem;
-
-use crate::shared_data::SharedMutexInner;
 
-#[cfg(miri)]
+#[cfg(any(miri, feature = "mock_backend"))]
 mod mock;
 #[cfg(not(miri))]
 mod shmlink;
 
-const PAGE_SIZE: usize = 4096;
-const _: () = assert!(std::mem::align_of::<PageAligned>() == PAGE_SIZE);
+/// The smallest page size of any supported platform, which `PageAligned` is aligned to
+/// at compile time. Mappings start on a runtime page boundary, and every page size in use
+/// (16K and 64K on some arm64 kernels) is a multiple of this, so the alignment holds
+/// everywhere. Segment lengths are rounded to [`page_size`] instead.
+const MIN_PAGE_SIZE: usize = 4096;
+const _: () = assert!(std::mem::align_of::<PageAligned>() == MIN_PAGE_SIZE);
 
 #[repr(align(4096))]
 #[expect(dead_code)]
-pub struct PageAligned([u8; PAGE_SIZE]);
+pub struct PageAligned([u8; MIN_PAGE_SIZE]);
 
-pub(crate) struct ShmemWrapper {
-    #[cfg(not(miri))]
-    shmem: SharedMem,
-    #[cfg(miri)]
-    pointer: *mut PageAligned,
+/// The page size of this system, from `sysconf(_SC_PAGESIZE)`.
+pub(crate) fn page_size() -> usize {
+    #[cfg(unix)]
+    {
+        match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+            size if size > 0 => size as usize,
+            _ => MIN_PAGE_SIZE,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        MIN_PAGE_SIZE
+    }
 }
 
-impl ShmemWrapper {
-    pub(crate) fn pointer(&self) -> *mut PageAligned {
+/// Where segments live. Everything maps through [`ShmBackend`] unless told otherwise, e.g.
+/// by [`SharedMutex::try_open_in`] or the `new_in` constructors. Implement it to keep segments somewhere else, such as
+/// hugetlbfs or a particular tmpfs mount, or in process for tests like [`MockBackend`].
+///
+/// [`SharedMutex::try_open_in`]: crate::SharedMutex::try_open_in
+/// [`MockBackend`]: crate::MockBackend
+pub trait MemoryBackend {
+    /// Maps the segment `name`, creating it zeroed if it doesn't exist, and growing it to
+    /// at least `length` bytes if it's shorter. Never shrinks it.
+    fn get_memory(&self, name: &str, length: usize) -> io::Result<Mapping>;
+
+    /// Like [`Self::get_memory`], with every page faulted in, see
+    /// [`SharedMutexOptions::prefault`]. By default this touches every page after mapping.
+    ///
+    /// [`SharedMutexOptions::prefault`]: crate::SharedMutexOptions::prefault
+    fn get_memory_prefaulted(&self, name: &str, length: usize) -> io::Result<Mapping> {
+        let mapping = self.get_memory(name, length)?;
+        touch_pages(mapping.as_ptr(), mapping.len());
+        Ok(mapping)
+    }
+
+    /// Removes the segment `name`. Mappings of it stay valid, and the next
+    /// [`Self::get_memory`] creates a new one.
+    fn unlink(&self, name: &str) -> io::Result<()>;
+
+    /// Where the segment `name` lives, the same for two backends and names exactly when
+    /// they map the same segment. This process remembers which type each location is
+    /// mapped for, so that it can't map one as two types. By default `name` qualified by
+    /// the backend's type, for backends whose instances all share one namespace.
+    fn location(&self, name: &str) -> String {
+        format!("{}:{name}", std::any::type_name::<Self>())
+    }
+}
+
+/// POSIX shared memory in `/dev/shm`. The default backend. Under miri, which can't map
+/// anything, it's [`MockBackend`] instead.
+///
+/// [`MockBackend`]: crate::MockBackend
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ShmBackend;
+
+impl MemoryBackend for ShmBackend {
+    fn get_memory(&self, name: &str, length: usize) -> io::Result<Mapping> {
+        #[cfg(miri)]
+        {
+            MockBackend.get_memory(name, length)
+        }
+        #[cfg(not(miri))]
+        {
+            shmlink::get_memory(name, length, false)
+        }
+    }
+
+    fn get_memory_prefaulted(&self, name: &str, length: usize) -> io::Result<Mapping> {
+        #[cfg(miri)]
+        {
+            MockBackend.get_memory_prefaulted(name, length)
+        }
         #[cfg(not(miri))]
         {
-            self.shmem.as_ptr()
+            shmlink::get_memory(name, length, true)
         }
+    }
+
+    fn unlink(&self, name: &str) -> io::Result<()> {
         #[cfg(miri)]
         {
-            self.pointer
+            MockBackend.unlink(name)
+        }
+        #[cfg(not(miri))]
+        {
+            unlink_if_exists(name)
+        }
+    }
+
+    /// Where Linux keeps `shm_open` names, so a [`DirBackend`] for `/dev/shm` agrees.
+    fn location(&self, name: &str) -> String {
+        format!("/dev/shm/{name}")
+    }
+}
+
+/// Segments as files in a directory, typically a dedicated tmpfs mount, instead of the
+/// system-wide `/dev/shm` namespace. Each segment is the file named after it, created with
+/// `open` and mapped with `mmap`, and unlinking removes the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirBackend {
+    dir: PathBuf,
+}
+
+impl DirBackend {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The file backing segment `name`.
+    pub fn path_of(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn get_memory_inner(&self, name: &str, length: usize, prefault: bool) -> io::Result<Mapping> {
+        let path = self.path_of(name);
+        #[cfg(miri)]
+        {
+            let _ = prefault;
+            MockBackend.get_memory(&path.to_string_lossy(), length)
+        }
+        #[cfg(not(miri))]
+        {
+            shmlink::get_memory_at(&path, length, prefault)
+        }
+    }
+}
+
+impl MemoryBackend for DirBackend {
+    fn get_memory(&self, name: &str, length: usize) -> io::Result<Mapping> {
+        self.get_memory_inner(name, length, false)
+    }
+
+    fn get_memory_prefaulted(&self, name: &str, length: usize) -> io::Result<Mapping> {
+        self.get_memory_inner(name, length, true)
+    }
+
+    fn unlink(&self, name: &str) -> io::Result<()> {
+        #[cfg(miri)]
+        {
+            MockBackend.unlink(&self.path_of(name).to_string_lossy())
+        }
+        #[cfg(not(miri))]
+        {
+            std::fs::remove_file(self.path_of(name))
+        }
+    }
+
+    fn location(&self, name: &str) -> String {
+        self.path_of(name).to_string_lossy().into_owned()
+    }
+}
+
+/// One process's view of a segment, from a [`MemoryBackend`].
+pub struct Mapping {
+    pointer: *mut PageAligned,
+    length: usize,
+    /// Whether [`Self::lock_memory`] succeeded, to undo it on drop
+    locked: bool,
+    /// Keeps the memory mapped, dropped after unlocking
+    _owner: Box<dyn Any + Send + Sync>,
+}
+
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// # Panics
+    ///
+    /// If `pointer` isn't aligned to 4096 bytes.
+    ///
+    /// # Safety
+    ///
+    /// `pointer` must be valid for reads and writes of `length` bytes until `owner` is
+    /// dropped. Those bytes must start out zeroed when the segment is created, and be the
+    /// same memory for every mapping of the segment, in any process that can open it.
+    pub unsafe fn new(pointer: *mut u8, length: usize, owner: impl Any + Send + Sync) -> Self {
+        assert!(pointer.cast::<PageAligned>().is_aligned());
+        Self {
+            pointer: pointer.cast(),
+            length,
+            locked: false,
+            _owner: Box::new(owner),
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.pointer.cast()
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Keeps the whole mapping in RAM until it's dropped, see
+    /// [`SharedMutexOptions::lock_memory`]. A no-op under miri.
+    ///
+    /// [`SharedMutexOptions::lock_memory`]: crate::SharedMutexOptions::lock_memory
+    fn lock_memory(&mut self) -> io::Result<()> {
+        #[cfg(not(miri))]
+        shmlink::lock_pages(self.as_ptr(), self.length)?;
+        self.locked = true;
+        Ok(())
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        #[cfg(not(miri))]
+        if self.locked {
+            // unmapping unlocks too, but only once every mapping of the pages is gone
+            shmlink::unlock_pages(self.as_ptr(), self.length);
         }
     }
 }
 
-pub(crate) fn get_memory<T: SharedMemorySafe>(name: &str) -> Result<ShmemWrapper> {
+/// Clones share the mapping, which stays until the last of them is dropped.
+#[derive(Clone)]
+pub(crate) struct ShmemWrapper {
+    mapping: Arc<Mapping>,
+    /// `None` for mappings that don't check the type, see [`open_existing`], or have no
+    /// name, see [`get_anonymous_memory`]
+    _registered: Option<Registered>,
+}
+
+impl ShmemWrapper {
+    fn new(mapping: Mapping, registered: Option<Registered>) -> Self {
+        Self {
+            mapping: Arc::new(mapping),
+            _registered: registered,
+        }
+    }
+
+    pub(crate) fn pointer(&self) -> *mut PageAligned {
+        self.mapping.pointer
+    }
+
+    /// # Panics
+    ///
+    /// If the wrapper has been cloned, since the clones' memory would be locked too.
+    pub(crate) fn lock_memory(&mut self) -> io::Result<()> {
+        Arc::get_mut(&mut self.mapping)
+            .expect("memory is locked before the mapping is shared")
+            .lock_memory()
+    }
+}
+
+/// Writes to every page of `length` bytes at `start` without changing them, so none of
+/// them faults later. For backends without a populating mmap flag.
+pub(crate) fn touch_pages(start: *mut u8, length: usize) {
+    for offset in (0..length).step_by(page_size()) {
+        // an atomic no-op rather than a read and write back, which could undo another
+        // process's store in between
+        unsafe { AtomicU8::from_ptr(start.add(offset)) }.fetch_or(0, Ordering::Relaxed);
+    }
+}
+
+/// Maps the segment `name`, sized for a `L` (e.g. `SharedMutexInner<T>`).
+pub(crate) fn get_memory<L>(name: &str) -> Result<ShmemWrapper> {
+    get_memory_with_tail::<L>(name, 0, false)
+}
+
+/// Like [`get_memory`], with `tail` more bytes after the `L`. An existing segment is grown
+/// to that size if it's smaller, never shrunk. With `prefault` every page is faulted in
+/// before returning, see [`SharedMutexOptions::prefault`].
+///
+/// [`SharedMutexOptions::prefault`]: crate::SharedMutexOptions::prefault
+pub(crate) fn get_memory_with_tail<L>(
+    name: &str,
+    tail: usize,
+    prefault: bool,
+) -> Result<ShmemWrapper> {
+    get_memory_in::<L>(&ShmBackend, name, tail, prefault)
+}
+
+/// [`get_memory_with_tail`] from `backend`.
+pub(crate) fn get_memory_in<L>(
+    backend: &(impl MemoryBackend + ?Sized),
+    name: &str,
+    tail: usize,
+    prefault: bool,
+) -> Result<ShmemWrapper> {
+    const {
+        let layout = Layout::new::<L>();
+        let page_layout = Layout::new::<PageAligned>();
+        assert!(layout.align() <= page_layout.align());
+    }
+    let layout = Layout::new::<L>();
+    let layout = Layout::from_size_align(layout.size() + tail, layout.align())?;
+    let registered = Registered::new(backend.location(name), name, type_fingerprint::<L>())?;
+    let mapping = match prefault {
+        true => backend.get_memory_prefaulted(name, layout.size()),
+        false => backend.get_memory(name, layout.size()),
+    }
+    .context("Failed to create shared memory")?;
+    if mapping.len() < layout.size() {
+        anyhow::bail!(
+            "the backend mapped {} bytes of `{name}`, {} were asked for",
+            mapping.len(),
+            layout.size()
+        );
+    }
+    Ok(ShmemWrapper::new(mapping, Some(registered)))
+}
+
+/// Maps a segment sized for a `L` that has no name, see [`SharedMutex::new_anonymous`].
+///
+/// [`SharedMutex::new_anonymous`]: crate::SharedMutex::new_anonymous
+pub(crate) fn get_anonymous_memory<L>() -> Result<ShmemWrapper> {
     const {
-        let layout = Layout::new::<SharedMutexInner<T>>();
+        let layout = Layout::new::<L>();
         let page_layout = Layout::new::<PageAligned>();
         assert!(layout.align() <= page_layout.align());
     }
+    #[cfg(miri)]
+    let mapping = mock::get_anonymous_memory(size_of::<L>());
+    #[cfg(not(miri))]
+    let mapping = shmlink::get_anonymous_memory(size_of::<L>());
+    let mapping = mapping.context("Failed to create anonymous shared memory")?;
+    // nothing else can map it, so there's no name to register
+    Ok(ShmemWrapper::new(mapping, None))
+}
+
+/// Segments this process has mapped, by [`MemoryBackend::location`], with the fingerprint
+/// of the layout they were mapped for and the number of live mappings. Catches one process
+/// using a segment for two types before anything is mapped, which the header only can
+/// once a mutex is in place, and which the miri mock couldn't catch at all.
+static MAPPED: Mutex<Option<HashMap<String, (u64, usize)>>> = Mutex::new(None);
+
+/// A live mapping's entry in [`MAPPED`], released on drop.
+struct Registered {
+    location: String,
+}
+
+impl Registered {
+    fn new(location: String, name: &str, fingerprint: u64) -> Result<Self> {
+        let mut mapped = MAPPED.lock().unwrap_or_else(PoisonError::into_inner);
+        let (registered, count) = mapped
+            .get_or_insert_with(HashMap::new)
+            .entry(location.clone())
+            .or_insert((fingerprint, 0));
+        if *registered != fingerprint {
+            return Err(anyhow::Error::new(TypeMismatch).context(format!(
+                "`{name}` is already mapped in this process for a different type"
+            )));
+        }
+        *count += 1;
+        Ok(Self { location })
+    }
+}
+
+impl Clone for Registered {
+    fn clone(&self) -> Self {
+        let mut mapped = MAPPED.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, count)) = mapped.as_mut().and_then(|m| m.get_mut(&self.location)) {
+            *count += 1;
+        }
+        Self {
+            location: self.location.clone(),
+        }
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        let mut mapped = MAPPED.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(mapped) = mapped.as_mut() else {
+            return;
+        };
+        if let Some((_, count)) = mapped.get_mut(&self.location) {
+            *count -= 1;
+            if *count == 0 {
+                mapped.remove(&self.location);
+            }
+        }
+    }
+}
+
+/// Like [`open_existing`], for a segment holding a `L`, and registered in [`MAPPED`] like
+/// [`get_memory_in`] registers it, so this process can't map it as two types either way.
+/// That fails with [`io::ErrorKind::InvalidData`] wrapping a [`TypeMismatch`].
+pub(crate) fn open_existing_as<L>(name: &str) -> io::Result<ShmemWrapper> {
+    let registered = Registered::new(ShmBackend.location(name), name, type_fingerprint::<L>())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, TypeMismatch))?;
+    let mapping = map_existing(name, size_of::<L>())?;
+    Ok(ShmemWrapper::new(mapping, Some(registered)))
+}
+
+/// Maps the existing segment `name` without creating or resizing it. Fails if it's
+/// shorter than `min_length`.
+pub(crate) fn open_existing(name: &str, min_length: usize) -> io::Result<ShmemWrapper> {
+    Ok(ShmemWrapper::new(map_existing(name, min_length)?, None))
+}
+
+fn map_existing(name: &str, min_length: usize) -> io::Result<Mapping> {
     #[cfg(miri)]
     {
-        mock::get_memory::<T>(name)
+        mock::open_existing(name, min_length)
     }
     #[cfg(not(miri))]
     {
-        shmlink::get_memory::<T>(name)
+        shmlink::open_existing(name, min_length)
     }
 }
 
-pub trait SharedMemorySafe: Copy + Sync {}
-impl<T: Copy + Sync> SharedMemorySafe for T {}
+/// Maps the existing segment open as `fd` without resizing it, like [`open_existing`].
+#[cfg(all(not(miri), unix))]
+pub(crate) fn from_fd(
+    fd: std::os::fd::BorrowedFd<'_>,
+    min_length: usize,
+) -> io::Result<ShmemWrapper> {
+    let mapping = shmlink::from_fd(fd, min_length)?;
+    Ok(ShmemWrapper::new(mapping, None))
+}
+
+/// Values that can be placed into a segment as they are and shared between processes.
+/// Any `Copy + Sync` type, which guarantees there's nothing to drop, plus
+/// [`LockFree`](crate::LockFree) atomics.
+///
+/// # Safety
+///
+/// Implementors must have no drop glue and no pointers, and be valid when copied bit for
+/// bit into another process's mapping.
+pub unsafe trait SharedMemorySafe: Sync {}
+unsafe impl<T: Copy + Sync> SharedMemorySafe for T {}
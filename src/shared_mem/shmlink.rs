@@ -1,18 +1,25 @@
 use std::{
-    alloc::Layout,
     ffi::{CStr, CString},
-    fs::File,
+    fs::{File, OpenOptions},
     io,
-    os::fd::FromRawFd,
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::Path,
 };
 
-use anyhow::Result;
-use memmap2::MmapMut;
+use memmap2::{MmapMut, MmapOptions};
 
-use crate::{
-    shared_data::SharedMutexInner,
-    shared_mem::{PageAligned, SharedMemorySafe, ShmemWrapper},
-};
+use crate::shared_mem::{Mapping, PageAligned, page_size};
+
+#[cfg(test)]
+thread_local! {
+    /// Makes `map_file` shrink the file back to one page right before mapping it, like
+    /// another process truncating it at the worst moment.
+    pub(crate) static TRUNCATE_BEFORE_MAP: std::cell::Cell<bool> =
+        const { std::cell::Cell::new(false) };
+}
 
 pub fn shm_open(name: &CStr) -> io::Result<File> {
     let mode = 0o666;
@@ -24,6 +31,13 @@ pub fn shm_open(name: &CStr) -> io::Result<File> {
     }
 }
 
+pub fn shm_open_existing(name: &CStr) -> io::Result<File> {
+    match unsafe { libc::shm_open(name.as_ptr(), libc::O_RDWR, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { File::from_raw_fd(fd) }),
+    }
+}
+
 pub fn shm_unlink(name: &CStr) -> io::Result<()> {
     match unsafe { libc::shm_unlink(name.as_ptr()) } {
         0 => Ok(()),
@@ -32,12 +46,12 @@ pub fn shm_unlink(name: &CStr) -> io::Result<()> {
 }
 
 pub fn unlink_if_exists(name: &str) -> io::Result<()> {
-    shm_unlink(&into_shm_name(name))
+    shm_unlink(&into_shm_name(name)?)
 }
 
-fn into_shm_name(path: &str) -> CString {
+fn into_shm_name(path: &str) -> io::Result<CString> {
     let shm_name = format!("/{path}");
-    CString::new(shm_name).unwrap()
+    CString::new(shm_name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
 }
 
 pub struct SharedMem {
@@ -45,11 +59,82 @@ pub struct SharedMem {
 }
 
 impl SharedMem {
-    pub unsafe fn new(path: &str, length: usize) -> io::Result<Self> {
-        let name = into_shm_name(path);
+    /// With `prefault`, every page is faulted in now instead of on first access.
+    pub unsafe fn new(path: &str, length: usize, prefault: bool) -> io::Result<Self> {
+        let name = into_shm_name(path)?;
         let file = shm_open(&name)?;
-        file.set_len(u64::try_from(length).unwrap())?;
-        let map = unsafe { MmapMut::map_mut(&file) }?;
+        unsafe { Self::map_file(file, length, prefault) }
+    }
+
+    /// Like [`Self::new`], with a regular file at `path` instead of a `shm_open` name.
+    pub unsafe fn at(path: &Path, length: usize, prefault: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // an existing segment may be in use
+            .truncate(false)
+            .mode(0o666)
+            .open(path)?;
+        unsafe { Self::map_file(file, length, prefault) }
+    }
+
+    unsafe fn map_file(file: File, length: usize, prefault: bool) -> io::Result<Self> {
+        // whole pages, the last one is mapped in full anyway
+        let length = length.next_multiple_of(page_size());
+        // never shrink, someone attached with a larger layout may still be using the tail
+        let length = u64::try_from(length).unwrap();
+        if file.metadata()?.len() < length {
+            file.set_len(length)?;
+        }
+        let mut options = MmapOptions::new();
+        if prefault {
+            // MAP_POPULATE, only honored on Linux
+            options.populate();
+        }
+        #[cfg(test)]
+        if TRUNCATE_BEFORE_MAP.take() {
+            file.set_len(page_size() as u64)?;
+        }
+        let map = unsafe { options.map_mut(&file) }?;
+        // whoever shrank it in between may have been an older binary that never checked
+        if (map.len() as u64) < length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "shared memory was truncated to {} bytes while mapping",
+                    map.len()
+                ),
+            ));
+        }
+        #[cfg(not(target_os = "linux"))]
+        if prefault {
+            super::touch_pages(map.as_ptr().cast_mut(), map.len());
+        }
+        Ok(Self { map })
+    }
+
+    /// Maps an existing segment as-is, without creating or resizing it.
+    pub fn open_existing(path: &str, min_length: usize) -> io::Result<Self> {
+        let file = shm_open_existing(&into_shm_name(path)?)?;
+        Self::map_existing(&file, &format!("shared memory `{path}`"), min_length)
+    }
+
+    /// Like [`Self::open_existing`], with the segment open as `fd`, which isn't needed
+    /// any more once it's mapped.
+    pub fn from_fd(fd: BorrowedFd<'_>, min_length: usize) -> io::Result<Self> {
+        let file = File::from(fd.try_clone_to_owned()?);
+        Self::map_existing(&file, &format!("descriptor {}", fd.as_raw_fd()), min_length)
+    }
+
+    fn map_existing(file: &File, what: &str, min_length: usize) -> io::Result<Self> {
+        if file.metadata()?.len() < u64::try_from(min_length).unwrap() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{what} is smaller than {min_length} bytes"),
+            ));
+        }
+        let map = unsafe { MmapMut::map_mut(file) }?;
         Ok(Self { map })
     }
 
@@ -58,11 +143,85 @@ impl SharedMem {
     }
 }
 
-pub fn get_memory<T: SharedMemorySafe>(name: &str) -> Result<ShmemWrapper> {
-    let layout = Layout::new::<SharedMutexInner<T>>();
+/// `mlock`s `length` bytes at `start`, so they're never swapped out.
+pub fn lock_pages(start: *mut u8, length: usize) -> io::Result<()> {
+    if unsafe { libc::mlock(start.cast(), length) } == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    Err(match e.raw_os_error() {
+        Some(libc::EPERM) => io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "locking memory needs CAP_IPC_LOCK or a nonzero RLIMIT_MEMLOCK",
+        ),
+        Some(libc::ENOMEM) => io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            format!("locking {length} bytes would exceed RLIMIT_MEMLOCK"),
+        ),
+        _ => e,
+    })
+}
+
+pub fn unlock_pages(start: *mut u8, length: usize) {
+    unsafe { libc::munlock(start.cast(), length) };
+}
+
+pub fn get_memory(name: &str, length: usize, prefault: bool) -> io::Result<Mapping> {
+    let shmem = unsafe { SharedMem::new(name, length, prefault) }?;
+    let (pointer, length) = (shmem.as_ptr().cast(), shmem.map.len());
+    Ok(unsafe { Mapping::new(pointer, length, shmem) })
+}
+
+pub fn get_memory_at(path: &Path, length: usize, prefault: bool) -> io::Result<Mapping> {
+    let shmem = unsafe { SharedMem::at(path, length, prefault) }?;
+    let (pointer, length) = (shmem.as_ptr().cast(), shmem.map.len());
+    Ok(unsafe { Mapping::new(pointer, length, shmem) })
+}
+
+pub fn open_existing(name: &str, min_length: usize) -> io::Result<Mapping> {
+    let shmem = SharedMem::open_existing(name, min_length)?;
+    let (pointer, length) = (shmem.as_ptr().cast(), shmem.map.len());
+    Ok(unsafe { Mapping::new(pointer, length, shmem) })
+}
+
+pub fn from_fd(fd: BorrowedFd<'_>, min_length: usize) -> io::Result<Mapping> {
+    let shmem = SharedMem::from_fd(fd, min_length)?;
+    let (pointer, length) = (shmem.as_ptr().cast(), shmem.map.len());
+    Ok(unsafe { Mapping::new(pointer, length, shmem) })
+}
+
+/// A `MAP_SHARED | MAP_ANONYMOUS` mapping, unmapped on drop.
+struct Anonymous {
+    pointer: *mut libc::c_void,
+    length: usize,
+}
 
-    let shmem = unsafe { SharedMem::new(name, layout.size()) }
-        .map_err(|e| anyhow::anyhow!("Failed to create shared memory: {}", e))?;
+unsafe impl Send for Anonymous {}
+unsafe impl Sync for Anonymous {}
 
-    Ok(ShmemWrapper { shmem })
+impl Drop for Anonymous {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.pointer, self.length) };
+    }
+}
+
+/// Maps `length` zeroed bytes that belong to no name. Children forked afterwards inherit
+/// the mapping as shared memory, not as a copy, and nobody else can ever open it.
+pub fn get_anonymous_memory(length: usize) -> io::Result<Mapping> {
+    let length = length.next_multiple_of(page_size());
+    let pointer = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            length,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_ANON,
+            -1,
+            0,
+        )
+    };
+    if pointer == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    let anonymous = Anonymous { pointer, length };
+    Ok(unsafe { Mapping::new(pointer.cast(), length, anonymous) })
 }
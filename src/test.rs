@@ -1,10 +1,33 @@
 use libc::gettid;
 
-use crate::shared_data::SharedMutex;
 #[cfg(not(miri))]
 use crate::unlink_if_exists;
+use crate::{
+    array::SharedMutexArray,
+    barrier::{BarrierBroken, SharedBarrier},
+    buffer::SharedBuffer,
+    condvar::{PiCondvar, PiCondvarGroup},
+    event::{ResetMode, SharedEvent},
+    futex,
+    mutex::PiMutex,
+    options::{LockConfig, SharedMutexOptions},
+    queue::{Full, SharedQueue},
+    robust_list::{RobustList, RobustListHead},
+    rwlock::{SharedReentrantRwLock, SharedRwLock},
+    shared_data::{CorruptData, LockError, SharedMutex, TryLockFailure, TypeMismatch},
+    shared_mem::{Mapping, MemoryBackend, ShmBackend},
+};
 
-use std::{sync::Arc, thread, time::Duration};
+use std::{
+    cell::Cell,
+    os::unix::thread::JoinHandleExt,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
 
 macro_rules! function {
     () => {{
@@ -26,7 +49,11 @@ macro_rules! maybe_cleanup {
 #[test]
 fn test_basic_mutex_operations() {
     maybe_cleanup!();
-    let mutex = unsafe { SharedMutex::new_with_val(function!(), 42) };
+    basic_mutex_operations(&ShmBackend, function!());
+}
+
+fn basic_mutex_operations(backend: &impl MemoryBackend, name: &str) {
+    let mutex = unsafe { SharedMutex::new_in(backend, name, || 42) };
 
     {
         let guard = mutex.lock().unwrap();
@@ -276,7 +303,7 @@ fn test_arc() {
         thread::spawn({
             let mutex = mutex.clone();
             move || {
-                let mut guard = mutex.lock().unwrap_err();
+                let mut guard = mutex.lock().unwrap_err().into_poisoned().unwrap();
                 *guard += 5;
                 std::mem::forget(guard);
             }
@@ -284,7 +311,7 @@ fn test_arc() {
         .join()
         .unwrap()
     }
-    let final_value = *mutex.lock().unwrap_err();
+    let final_value = *mutex.lock().unwrap_err().into_poisoned().unwrap();
     assert_eq!(final_value, 15);
 }
 
@@ -321,10 +348,11 @@ fn test_panic_poisoning() {
         Ok(None) => {
             panic!("try_lock returned None, but lock should be available (though possibly poisoned)");
         }
-        Err(e) => {
+        Err(LockError::Poisoned(e)) => {
             assert_eq!(*e, 999);
             println!("Lock is poisoned as expected: {e:?}");
         }
+        Err(LockError::Failed(e)) => panic!("try_lock failed: {e}"),
     }
 
     let mutex = unsafe { SharedMutex::new_with_val(function!(), 42) };
@@ -332,6 +360,3307 @@ fn test_panic_poisoning() {
     assert_eq!(*guard, 999, "Mutex should've been reset because it had been poisoned");
 }
 
+#[test]
+fn test_lock_nested_keeps_outer_lock() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+
+    fn bump(mutex: &SharedMutex<u64>) {
+        // the caller's guard isn't borrowed from in between
+        let mut guard = unsafe { mutex.lock_nested() }.unwrap();
+        *guard += 1;
+    }
+
+    let mut guard = mutex.lock().unwrap();
+    *guard = 1;
+    bump(&mutex);
+    bump(&mutex);
+
+    assert!(mutex.is_locked());
+    thread::scope(|s| {
+        s.spawn(|| assert!(mutex.try_lock().unwrap().is_none()));
+    });
+    assert_eq!(*guard, 3);
+
+    drop(guard);
+    assert!(!mutex.is_locked());
+
+    bump(&mutex);
+    assert!(!mutex.is_locked());
+    assert_eq!(*mutex.lock().unwrap(), 4);
+}
+
+#[test]
+fn test_try_lock_recovers_poison_under_signals() {
+    maybe_cleanup!();
+    let _handlers = install_noop_handler(libc::SIGUSR1);
+    let mutex = Arc::new(unsafe { SharedMutex::new_with_val(function!(), 0u64) });
+    let done = Arc::new(AtomicBool::new(false));
+
+    let worker = thread::spawn({
+        let mutex = mutex.clone();
+        let done = done.clone();
+        move || {
+            for _ in 0..50 {
+                thread::spawn({
+                    let mutex = mutex.clone();
+                    move || std::mem::forget(mutex.lock().unwrap())
+                })
+                .join()
+                .unwrap();
+
+                let guard = mutex.try_lock().unwrap_err().into_poisoned().unwrap();
+                assert!(mutex.is_locked());
+                drop(guard);
+                assert!(!mutex.is_locked());
+            }
+            done.store(true, Ordering::Release);
+        }
+    });
+
+    let pthread = worker.as_pthread_t();
+    while !done.load(Ordering::Acquire) {
+        unsafe { libc::pthread_kill(pthread, libc::SIGUSR1) };
+        thread::yield_now();
+    }
+    worker.join().unwrap();
+}
+
+#[test]
+fn test_registered_thread_locks_without_syscalls() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            futex::register_current_thread();
+            let before = futex::SYSCALLS.with(Cell::get);
+
+            *mutex.lock().unwrap() += 1;
+
+            assert_eq!(futex::SYSCALLS.with(Cell::get), before);
+        });
+    });
+    assert_eq!(*mutex.lock().unwrap(), 1);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_lock_async() {
+    use std::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Wake, Waker},
+    };
+
+    struct ThreadWaker(thread::Thread);
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = pin!(fut);
+        loop {
+            match fut.as_mut().poll(&mut cx) {
+                Poll::Ready(out) => return out,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+
+    block_on(async {
+        let mut guard = mutex.lock_async().await.unwrap();
+        assert_send(&guard);
+        *guard += 1;
+        assert!(mutex.is_locked());
+        drop(guard);
+        assert!(!mutex.is_locked());
+    });
+
+    let guard = mutex.lock().unwrap();
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = pin!(mutex.lock_async());
+    assert!(fut.as_mut().poll(&mut cx).is_pending());
+    drop(guard);
+
+    let mut guard = block_on(fut).unwrap();
+    *guard += 1;
+    drop(guard);
+    assert_eq!(*mutex.lock().unwrap(), 2);
+
+    // dropping a waiting future doesn't wait for the lock, which this thread holds
+    let guard = mutex.lock().unwrap();
+    let mut fut = Box::pin(mutex.lock_async());
+    assert!(fut.as_mut().poll(&mut cx).is_pending());
+    drop(fut);
+    drop(guard);
+    // the helper still gets it, and lets go again
+    assert_eq!(*mutex.lock().unwrap(), 2);
+
+    // poison is seen, and left, like with a blocking lock
+    thread::scope(|s| {
+        s.spawn(|| mutex.lock().unwrap().poison_with(5));
+    });
+    let guard = block_on(mutex.lock_async())
+        .unwrap_err()
+        .into_poisoned()
+        .unwrap();
+    assert_eq!(guard.poison_reason(), Some(5));
+    drop(guard);
+    assert_eq!(mutex.lock().unwrap().poison_reason(), None);
+    let unwound = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _guard = block_on(mutex.lock_async()).unwrap();
+        panic!("mid-write");
+    }));
+    assert!(unwound.is_err());
+    assert!(mutex.lock().is_err(), "dropped by a panic");
+
+    // an async write keeps the checksum up to date
+    let name = format!("{}_checked", function!());
+    let options = SharedMutexOptions::new().checksum::<u64>(true);
+    let checked = unsafe { options.open(&name, || 0u64) };
+    *block_on(checked.lock_async()).unwrap() = 3;
+    assert_eq!(*checked.lock().unwrap(), 3);
+    ShmBackend.unlink(&name).unwrap();
+}
+
+#[test]
+fn test_new_validated_rejects_bad_data() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 7u64) };
+
+    let in_range = |v: &u64| *v < 5;
+    let opened = unsafe { SharedMutex::new_validated(function!(), || 0u64, in_range) };
+    assert_eq!(opened.err(), Some(CorruptData));
+
+    *mutex.lock().unwrap() = 3;
+    let opened = unsafe { SharedMutex::new_validated(function!(), || 0u64, in_range) };
+    assert_eq!(*opened.unwrap().lock().unwrap(), 3);
+}
+
+#[test]
+fn test_owner_tid() {
+    maybe_cleanup!();
+    let mutex = &unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+    assert_eq!(mutex.owner_tid(), None);
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    thread::scope(|s| {
+        s.spawn(move || {
+            let _guard = mutex.lock().unwrap();
+            tx.send(unsafe { gettid() }).unwrap();
+            release_rx.recv().unwrap();
+        });
+
+        let holder = rx.recv().unwrap();
+        assert_eq!(mutex.owner_tid(), Some(holder));
+        release_tx.send(()).unwrap();
+    });
+    assert_eq!(mutex.owner_tid(), None);
+}
+
+#[test]
+fn test_reentrant_rwlock_nesting() {
+    maybe_cleanup!();
+    reentrant_rwlock_nesting(&ShmBackend, function!());
+}
+
+fn reentrant_rwlock_nesting(backend: &impl MemoryBackend, name: &str) {
+    let lock = &unsafe { SharedReentrantRwLock::new_in(backend, name, || 1u64) };
+
+    let outer = lock.read().unwrap();
+    let inner = lock.read().unwrap();
+    assert_eq!(*outer + *inner, 2);
+    thread::scope(|s| {
+        s.spawn(|| assert_eq!(*lock.read().unwrap(), 1));
+    });
+    drop(inner);
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    thread::scope(|s| {
+        s.spawn(move || {
+            *lock.write().unwrap() = 2;
+            tx.send(()).unwrap();
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert!(rx.try_recv().is_err(), "writer got in during a read");
+        drop(outer);
+        rx.recv().unwrap();
+    });
+
+    let mut write = lock.write().unwrap();
+    *write = 3;
+    // `write` isn't written through while the nested guards are alive
+    let read = unsafe { lock.read_nested() }.unwrap();
+    assert_eq!(*read, 3);
+    let read = read.try_upgrade().unwrap_err();
+    drop(read);
+    *unsafe { lock.write_nested() }.unwrap() += 1;
+    assert!(lock.is_write_locked());
+    for nested in [
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(lock.read()))),
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(lock.write()))),
+    ] {
+        assert!(nested.is_err(), "nested in a write without unsafe");
+    }
+    assert_eq!(*write, 4);
+    drop(write);
+    assert!(!lock.is_write_locked());
+    assert_eq!(*lock.read().unwrap(), 4);
+}
+
+#[test]
+fn test_reentrant_rwlock_upgrade() {
+    maybe_cleanup!();
+    let lock = &unsafe { SharedReentrantRwLock::new_with_val(function!(), 0u64) };
+
+    let read = lock.read().unwrap();
+    let mut write = read.try_upgrade().unwrap();
+    *write = 1;
+    drop(write);
+
+    let outer = lock.read().unwrap();
+    let nested = lock.read().unwrap();
+    let nested = nested.try_upgrade().unwrap_err();
+    drop(nested);
+    drop(outer);
+
+    let read = lock.read().unwrap();
+    let (held_tx, held_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    thread::scope(|s| {
+        s.spawn(move || {
+            let _read = lock.read().unwrap();
+            held_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        held_rx.recv().unwrap();
+
+        let read = read.try_upgrade().unwrap_err();
+        assert_eq!(*read, 1);
+        release_tx.send(()).unwrap();
+        drop(read);
+    });
+}
+
+#[test]
+fn test_reentrant_rwlock_recovers_dead_holders() {
+    maybe_cleanup!();
+    let lock = Arc::new(unsafe { SharedReentrantRwLock::new_with_val(function!(), 0u64) });
+
+    thread::spawn({
+        let lock = lock.clone();
+        move || {
+            let mut write = lock.write().unwrap();
+            *write = 10;
+            std::mem::forget(write);
+        }
+    })
+    .join()
+    .unwrap();
+    let read = lock.read().unwrap_err().into_poisoned().unwrap();
+    assert_eq!(*read, 10);
+    drop(read);
+    *lock.write().unwrap() = 1;
+
+    thread::spawn({
+        let lock = lock.clone();
+        move || std::mem::forget(lock.read().unwrap())
+    })
+    .join()
+    .unwrap();
+    *lock.write().unwrap() += 1;
+    assert_eq!(*lock.read().unwrap(), 2);
+}
+
+#[test]
+fn test_lock_recovers_from_stale_owner_tid() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 7u64) };
+
+    // pid_max tops out at 2^22, so no thread can have this TID
+    let stale_tid = 0x3fff_0000;
+    mutex
+        .futex
+        .0
+        .futex
+        .store(stale_tid, std::sync::atomic::Ordering::Relaxed);
+
+    let guard = mutex.lock().unwrap_err().into_poisoned().unwrap();
+    assert_eq!(*guard, 7);
+    assert_eq!(mutex.owner_tid(), Some(unsafe { gettid() }));
+    drop(guard);
+    assert!(!mutex.is_locked());
+    assert_eq!(*mutex.lock().unwrap(), 7);
+}
+
+#[test]
+fn test_export_prometheus() {
+    maybe_cleanup!();
+    let name = function!();
+    let mutex = unsafe { SharedMutex::new_with_val(name, 0u64) };
+
+    let (held_tx, held_rx) = std::sync::mpsc::channel();
+    thread::scope(|s| {
+        let mutex = &mutex;
+        s.spawn(move || {
+            let _guard = mutex.lock().unwrap();
+            held_tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        });
+        held_rx.recv().unwrap();
+        *mutex.lock().unwrap() += 1;
+    });
+
+    let metrics = crate::read_metrics(name).unwrap();
+    assert_eq!(metrics.acquisitions, 2);
+    assert_eq!(metrics.contended, 1);
+    assert!(metrics.wait > Duration::ZERO);
+
+    let text = crate::export_prometheus(&[name, "definitely_missing_segment"]);
+    let label = format!("{{segment=\"{name}\"}}");
+    assert!(text.contains("# TYPE shared_mutex_acquisitions_total counter"));
+    assert!(text.contains(&format!("shared_mutex_acquisitions_total{label} 2\n")));
+    assert!(text.contains(&format!("shared_mutex_contended_total{label} 1\n")));
+    assert!(text.contains(&format!("shared_mutex_poisoned_total{label} 0\n")));
+    assert!(!text.contains("definitely_missing_segment"));
+}
+
+#[test]
+fn test_unrecognized_header_reinitializes() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 5u64) };
+    *mutex.lock().unwrap() = 9;
+    let again = unsafe { SharedMutex::new_with_val(function!(), 1u64) };
+    assert_eq!(*again.lock().unwrap(), 9);
+
+    mutex.header.magic.store(0xdead_beef, Ordering::Relaxed);
+    let again = unsafe { SharedMutex::new_with_val(function!(), 1u64) };
+    assert_eq!(*again.lock().unwrap(), 1);
+    assert!(mutex.header.is_current());
+    assert_eq!(*mutex.lock().unwrap(), 1);
+}
+
+#[test]
+fn test_queue_drain_in_one_pass() {
+    maybe_cleanup!();
+    let queue = unsafe { SharedQueue::<u32, 8>::new(function!()) };
+    for i in 0..5 {
+        queue.push(i).unwrap();
+    }
+
+    let before = queue.metrics().acquisitions;
+    let drained: Vec<_> = queue.drain().collect();
+    assert_eq!(drained, [0, 1, 2, 3, 4]);
+    assert_eq!(queue.metrics().acquisitions, before + 1);
+    assert!(queue.is_empty());
+    assert_eq!(queue.pop(), None);
+}
+
+#[test]
+fn test_queue_producer_consumer() {
+    maybe_cleanup!();
+    queue_producer_consumer(&ShmBackend, function!());
+}
+
+fn queue_producer_consumer(backend: &(impl MemoryBackend + Sync), name: &str) {
+    const ITEMS: u32 = 1000;
+    let queue = unsafe { SharedQueue::<u32, 4>::new_in(backend, name) };
+
+    thread::scope(|s| {
+        s.spawn(move || {
+            // its own handle, like a producer in another process
+            let queue = unsafe { SharedQueue::<u32, 4>::new_in(backend, name) };
+            for mut item in 0..ITEMS {
+                while let Err(Full(rejected)) = queue.push(item) {
+                    item = rejected;
+                    thread::yield_now();
+                }
+            }
+        });
+        // wraps around the ring many times, in order and with nothing lost
+        for expected in 0..ITEMS {
+            assert_eq!(queue.pop_blocking(), expected);
+        }
+    });
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn test_attach_with_different_type_errors() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_checked(function!(), || 3u64) }.unwrap();
+    *mutex.lock().unwrap() = 4;
+
+    let same_layout = unsafe { SharedMutex::new_checked(function!(), || 0i64) };
+    assert!(same_layout.err().unwrap().is::<TypeMismatch>());
+    let smaller = unsafe { SharedMutex::new_checked(function!(), || 0u32) };
+    assert!(smaller.err().unwrap().is::<TypeMismatch>());
+
+    let again = unsafe { SharedMutex::new_checked(function!(), || 0u64) }.unwrap();
+    assert_eq!(*again.lock().unwrap(), 4);
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_rate_limiter_across_processes() {
+    use crate::rate_limit::SharedRateLimiter;
+
+    maybe_cleanup!();
+    const CAPACITY: u64 = 5;
+    const PER_SECOND: u64 = 100;
+    const CHILDREN: usize = 3;
+    let window = Duration::from_millis(300);
+
+    let start = std::time::Instant::now();
+    let limiter = SharedRateLimiter::new(function!(), CAPACITY, PER_SECOND);
+    let children: Vec<_> = (0..CHILDREN)
+        .map(|_| {
+            fork(|| {
+                let child_start = std::time::Instant::now();
+                let mut acquired = 0;
+                while child_start.elapsed() < window {
+                    if limiter.try_acquire(1) {
+                        acquired += 1;
+                    }
+                    thread::sleep(Duration::from_micros(200));
+                }
+                unsafe { libc::_exit(acquired) };
+            })
+        })
+        .collect();
+
+    let mut total = 0;
+    for child in children {
+        let status = wait(child);
+        assert!(libc::WIFEXITED(status));
+        total += libc::WEXITSTATUS(status) as u64;
+    }
+    let elapsed = start.elapsed();
+
+    let allowed = CAPACITY + (elapsed.as_secs_f64() * PER_SECOND as f64) as u64;
+    assert!(
+        total <= allowed,
+        "{total} acquisitions in {elapsed:?}, limit {allowed}"
+    );
+    // the children kept asking the whole window, so they should have used up the rate
+    let expected = CAPACITY + (window.as_secs_f64() * PER_SECOND as f64) as u64;
+    assert!(
+        total >= expected * 3 / 4,
+        "only {total} acquisitions, expected ~{expected}"
+    );
+}
+
+#[test]
+fn test_lock_or_repair() {
+    maybe_cleanup!();
+    // invariant: both halves are equal
+    let mutex = Arc::new(unsafe { SharedMutex::new_with_val(function!(), (1u64, 1u64)) });
+
+    thread::spawn({
+        let mutex = mutex.clone();
+        move || {
+            let mut guard = mutex.lock().unwrap();
+            guard.0 = 5;
+            // dies before updating the second half
+            std::mem::forget(guard);
+        }
+    })
+    .join()
+    .unwrap();
+
+    let guard = mutex.lock_or_repair(|pair| pair.1 = pair.0).unwrap();
+    assert_eq!(*guard, (5, 5));
+    drop(guard);
+    assert_eq!(*mutex.lock().unwrap(), (5, 5));
+
+    let guard = mutex.lock_or_repair(|_| panic!("nothing to repair"));
+    assert_eq!(*guard.unwrap(), (5, 5));
+
+    // a failed acquire repairs nothing and holds nothing
+    let mutex = &mutex;
+    let (held_tx, held_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    thread::scope(|s| {
+        s.spawn(move || {
+            let _guard = mutex.lock().unwrap();
+            held_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        held_rx.recv().unwrap();
+        futex::FAIL_LOCK_PI.set((nix::errno::Errno::EAGAIN, u32::MAX));
+        let failed = mutex.lock_or_repair(|_| panic!("nothing was acquired"));
+        futex::FAIL_LOCK_PI.set((nix::errno::Errno::UnknownErrno, 0));
+        assert_eq!(failed.err().unwrap().raw_os_error(), Some(libc::EAGAIN));
+        assert!(!mutex.held_by_current_thread());
+        release_tx.send(()).unwrap();
+    });
+}
+
+#[cfg(feature = "debug_lockorder")]
+#[test]
+fn test_lockorder_detects_inversion() {
+    maybe_cleanup!();
+    let second_name = "test_lockorder_detects_inversion_second";
+    let _cleanup = CleanupGuard::new(second_name);
+    let first = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+    let second = unsafe { SharedMutex::new_with_val(second_name, 0u64) };
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            let _first = first.lock().unwrap();
+            let _second = second.lock().unwrap();
+        })
+        .join()
+        .unwrap();
+
+        let inverted = s.spawn(|| {
+            let _second = second.lock().unwrap();
+            let _first = first.lock().unwrap();
+        });
+        let panic = inverted.join().unwrap_err();
+        let message = panic.downcast_ref::<String>().unwrap();
+        assert!(message.contains("lock order inversion"), "{message}");
+    });
+
+    // same order as before is still fine, the panic above poisoned `second` though
+    let _first = first.lock().unwrap();
+    let _second = second.lock().unwrap_err();
+}
+
+#[test]
+fn test_fair_mutex_bounds_wait() {
+    maybe_cleanup!();
+    let options = SharedMutexOptions::new().fair(true);
+    let mutex = unsafe { options.open(function!(), || 0u64) };
+    let stop = AtomicBool::new(false);
+
+    let max_wait = thread::scope(|s| {
+        for _ in 0..3 {
+            s.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    let mut guard = mutex.lock().unwrap();
+                    *guard += 1;
+                    thread::sleep(Duration::from_millis(1));
+                }
+            });
+        }
+
+        let mut max_wait = Duration::ZERO;
+        for _ in 0..10 {
+            thread::sleep(Duration::from_millis(20));
+            let start = std::time::Instant::now();
+            let guard = mutex.lock().unwrap();
+            max_wait = max_wait.max(start.elapsed());
+            drop(guard);
+        }
+        stop.store(true, Ordering::Relaxed);
+        max_wait
+    });
+
+    // at most the three hammering threads go first, each holding for a sleep of 1ms, which
+    // on a coarse timer with a single CPU can be several times that
+    assert!(max_wait < Duration::from_millis(200), "waited {max_wait:?}");
+    assert!(*mutex.lock().unwrap() > 0);
+}
+
+#[test]
+fn test_fair_mutex_skips_dead_owner_turn() {
+    maybe_cleanup!();
+    let options = SharedMutexOptions::new().fair(true);
+    let mutex = Arc::new(unsafe { options.open(function!(), || 0u64) });
+
+    thread::spawn({
+        let mutex = mutex.clone();
+        move || std::mem::forget(mutex.lock().unwrap())
+    })
+    .join()
+    .unwrap();
+
+    let guard = mutex.lock().unwrap_err().into_poisoned().unwrap();
+    drop(guard);
+    assert_eq!(mutex.try_lock().unwrap().map(|guard| *guard), Some(0));
+}
+
+#[test]
+fn test_lock_until_deadline_under_signals() {
+    maybe_cleanup!();
+    let _handlers = install_noop_handler(libc::SIGUSR2);
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+    let (held_tx, held_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let (pthread_tx, pthread_rx) = std::sync::mpsc::channel();
+    let done = AtomicBool::new(false);
+
+    thread::scope(|s| {
+        let mutex = &mutex;
+        s.spawn(move || {
+            let _guard = mutex.lock().unwrap();
+            held_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        held_rx.recv().unwrap();
+
+        let waiter = s.spawn(|| {
+            pthread_tx.send(unsafe { libc::pthread_self() }).unwrap();
+            let deadline = std::time::Instant::now() + Duration::from_millis(100);
+            let locked = mutex.lock_until(deadline).unwrap();
+            let late = std::time::Instant::now().saturating_duration_since(deadline);
+            done.store(true, Ordering::Relaxed);
+            (locked.is_some(), late)
+        });
+        let pthread = pthread_rx.recv().unwrap();
+        while !done.load(Ordering::Relaxed) {
+            unsafe { libc::pthread_kill(pthread, libc::SIGUSR2) };
+            thread::sleep(Duration::from_millis(1));
+        }
+        let (locked, late) = waiter.join().unwrap();
+        release_tx.send(()).unwrap();
+
+        assert!(!locked);
+        assert!(late < Duration::from_millis(50), "returned {late:?} late");
+    });
+
+    let deadline = std::time::Instant::now() + Duration::from_millis(100);
+    assert!(mutex.lock_until(deadline).unwrap().is_some());
+}
+
+#[test]
+fn test_with_lock_releases_on_early_return() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+
+    let stopped_at = mutex
+        .with_lock(|value| {
+            for i in 0..10 {
+                if i == 3 {
+                    return i;
+                }
+                *value += 1;
+            }
+            unreachable!()
+        })
+        .unwrap();
+    assert_eq!(stopped_at, 3);
+    assert!(!mutex.is_locked());
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            let guard = mutex.try_lock().unwrap();
+            assert_eq!(guard.as_deref(), Some(&3));
+        });
+    });
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_metrics_sink_counts_contention() {
+    use crate::metrics::{LockEvent, set_metrics_sink};
+    use std::cell::Cell;
+
+    // the sink is process wide and other tests lock concurrently, count per thread
+    thread_local! {
+        static COUNTS: Cell<(u32, u32, u32)> = const { Cell::new((0, 0, 0)) };
+    }
+    fn sink(event: LockEvent) {
+        COUNTS.with(|counts| {
+            let (mut uncontended, mut contended, mut released) = counts.get();
+            match event {
+                LockEvent::Acquired {
+                    contended: true, ..
+                } => contended += 1,
+                LockEvent::Acquired { .. } => uncontended += 1,
+                LockEvent::Released => released += 1,
+                LockEvent::RobustListUnavailable { .. } => {}
+            }
+            counts.set((uncontended, contended, released));
+        });
+    }
+
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+    set_metrics_sink(Some(sink));
+
+    let (held_tx, held_rx) = std::sync::mpsc::channel();
+    let counts = thread::scope(|s| {
+        let guard = mutex.lock().unwrap();
+        let waiter = s.spawn(|| {
+            held_tx.send(()).unwrap();
+            // contended, the main thread holds the lock
+            drop(mutex.lock().unwrap());
+            // uncontended
+            drop(mutex.lock().unwrap());
+            COUNTS.get()
+        });
+        held_rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        waiter.join().unwrap()
+    });
+    set_metrics_sink(None);
+
+    assert_eq!(counts, (1, 1, 2));
+    assert_eq!(COUNTS.get(), (1, 0, 1));
+}
+
+#[test]
+fn test_mutex_array_indices_are_independent() {
+    maybe_cleanup!();
+    let array = unsafe { SharedMutexArray::<u64, 4>::new(function!(), |i| i as u64 * 1000) };
+    assert_eq!(array.len(), 4);
+
+    // holding one element doesn't block the others
+    let held = array.get(0).lock().unwrap();
+    thread::scope(|s| {
+        for i in 1..4 {
+            let array = &array;
+            s.spawn(move || {
+                for _ in 0..100 {
+                    *array.get(i).lock().unwrap() += 1;
+                }
+            });
+        }
+    });
+    drop(held);
+
+    let values: Vec<u64> = array.iter().map(|m| *m.lock().unwrap()).collect();
+    assert_eq!(values, [0, 1100, 2100, 3100]);
+
+    // attaching again keeps the existing values
+    let again = unsafe { SharedMutexArray::<u64, 4>::new(function!(), |_| 0) };
+    assert_eq!(*again.get(3).lock().unwrap(), 3100);
+}
+
+#[test]
+fn test_caught_panic_poisons() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+
+    let caught = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let mut guard = mutex.lock().unwrap();
+        *guard = 10;
+        panic!("half way through");
+    }));
+    assert!(caught.is_err());
+
+    // same thread, still alive, and the lock is free but poisoned
+    assert!(!mutex.is_locked());
+    let Err(LockError::Poisoned(guard)) = mutex.lock() else {
+        panic!("a guard dropped by a panic should poison the lock");
+    };
+    assert_eq!(*guard, 10);
+    drop(guard);
+    assert!(mutex.lock().is_ok());
+}
+
+#[test]
+fn test_only_a_panic_while_held_poisons() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+
+    // a guard taken and dropped while unwinding wrote its value in full
+    struct WriteOnDrop<'a>(&'a SharedMutex<u64>);
+    impl Drop for WriteOnDrop<'_> {
+        fn drop(&mut self) {
+            *self.0.lock().unwrap() = 3;
+        }
+    }
+    let caught = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _cleanup = WriteOnDrop(&mutex);
+        panic!("unwinding through the cleanup");
+    }));
+    assert!(caught.is_err());
+    assert_eq!(*mutex.lock().expect("not poisoned"), 3);
+
+    // nor can a read guard leave anything half written
+    let caught = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _guard = mutex.lock_shared_read().unwrap();
+        panic!("while reading");
+    }));
+    assert!(caught.is_err());
+    assert!(!mutex.is_locked());
+    assert_eq!(*mutex.lock().expect("not poisoned"), 3);
+}
+
+#[test]
+fn test_try_lock_reason_reports_holder() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+    let (held_tx, held_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+
+    thread::scope(|s| {
+        let mutex = &mutex;
+        s.spawn(move || {
+            let _guard = mutex.lock().unwrap();
+            held_tx.send(unsafe { gettid() }).unwrap();
+            release_rx.recv().unwrap();
+        });
+        let holder = held_rx.recv().unwrap();
+        assert_eq!(
+            mutex.try_lock_reason().err(),
+            Some(TryLockFailure::HeldByOther(holder))
+        );
+        release_tx.send(()).unwrap();
+    });
+
+    assert!(matches!(mutex.try_lock_reason(), Ok(Ok(_))));
+}
+
+#[test]
+fn test_buffer_truncates_long_writes() {
+    let mut buffer = SharedBuffer::<4>::new();
+    assert!(buffer.is_empty());
+    assert_eq!(buffer.write(b"hello"), 4);
+    assert_eq!(buffer.read(), b"hell");
+    assert_eq!(buffer.write(b"hi"), 2);
+    assert_eq!(buffer.read(), b"hi");
+    buffer.clear();
+    assert_eq!(buffer.read(), b"");
+}
+
+#[test]
+fn test_buffer_round_trip_through_mutex() {
+    maybe_cleanup!();
+    let name = function!();
+    let mutex = unsafe { SharedMutex::new_with_val(name, SharedBuffer::<64>::new()) };
+    thread::spawn(move || {
+        let mutex = unsafe { SharedMutex::<SharedBuffer<64>>::from_name(name) };
+        assert_eq!(mutex.lock().unwrap().write(b"from another thread"), 19);
+    })
+    .join()
+    .unwrap();
+    assert_eq!(mutex.lock().unwrap().read(), b"from another thread");
+}
+
+#[test]
+fn test_robust_list_refused_falls_back() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+    thread::scope(|s| {
+        s.spawn(|| {
+            futex::FAIL_SET_ROBUST_LIST.set(Some(libc::ENOSYS));
+            futex::register_current_thread();
+            *mutex.lock().unwrap() += 1;
+        });
+    });
+    let error = futex::robust_list_error().expect("the refusal should be recorded");
+    assert_eq!(error.raw_os_error(), Some(libc::ENOSYS));
+    assert_eq!(*mutex.lock().unwrap(), 1);
+}
+
+#[test]
+fn test_barrier_rounds() {
+    maybe_cleanup!();
+    barrier_rounds(&ShmBackend, function!());
+}
+
+fn barrier_rounds(backend: &impl MemoryBackend, name: &str) {
+    const PARTIES: usize = 4;
+    const ROUNDS: usize = 3;
+    let barrier = unsafe { SharedBarrier::new_in(backend, name, PARTIES as u32) };
+    let arrived = AtomicUsize::new(0);
+    let leaders = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        for _ in 0..PARTIES {
+            s.spawn(|| {
+                for round in 1..=ROUNDS {
+                    arrived.fetch_add(1, Ordering::Relaxed);
+                    let result = barrier.wait().unwrap();
+                    // nobody gets through before everyone arrived for this round
+                    assert!(arrived.load(Ordering::Relaxed) >= round * PARTIES);
+                    if result.is_leader() {
+                        leaders.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(arrived.load(Ordering::Relaxed), PARTIES * ROUNDS);
+    assert_eq!(leaders.load(Ordering::Relaxed), ROUNDS);
+}
+
+#[test]
+fn test_barrier_timeout_breaks_it() {
+    maybe_cleanup!();
+    // the third participant never shows up
+    let barrier = unsafe { SharedBarrier::new(function!(), 3) };
+    thread::scope(|s| {
+        let waiter = s.spawn(|| barrier.wait());
+        let timed_out = barrier.wait_timeout(Duration::from_millis(50));
+        assert_eq!(timed_out, Err(BarrierBroken));
+        assert_eq!(waiter.join().unwrap(), Err(BarrierBroken));
+    });
+    assert!(barrier.is_broken());
+    assert_eq!(barrier.wait(), Err(BarrierBroken));
+}
+
+#[test]
+fn test_auto_reset_event_releases_one_per_set() {
+    maybe_cleanup!();
+    const WAITERS: usize = 3;
+    let event = unsafe { SharedEvent::new(function!(), ResetMode::Auto) };
+    let released = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        for _ in 0..WAITERS {
+            s.spawn(|| {
+                event.wait();
+                released.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        for n in 1..=WAITERS {
+            event.set();
+            while released.load(Ordering::SeqCst) < n {
+                thread::yield_now();
+            }
+            // the one woken waiter reset it on its way out
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(released.load(Ordering::SeqCst), n);
+            assert!(!event.is_set());
+        }
+    });
+    assert!(!event.wait_timeout(Duration::from_millis(20)));
+}
+
+#[test]
+fn test_manual_reset_event_releases_everyone() {
+    maybe_cleanup!();
+    manual_reset_event_releases_everyone(&ShmBackend, function!());
+}
+
+fn manual_reset_event_releases_everyone(backend: &impl MemoryBackend, name: &str) {
+    const WAITERS: usize = 3;
+    let event = unsafe { SharedEvent::new_in(backend, name, ResetMode::Manual) };
+    assert!(!event.wait_timeout(Duration::from_millis(20)));
+
+    thread::scope(|s| {
+        for _ in 0..WAITERS {
+            s.spawn(|| event.wait());
+        }
+        event.set();
+    });
+    // stays set for later waiters until reset
+    assert!(event.wait_timeout(Duration::ZERO));
+    event.reset();
+    assert!(!event.wait_timeout(Duration::from_millis(20)));
+
+    // a second opener gets the creator's mode
+    let other = unsafe { SharedEvent::new_in(backend, name, ResetMode::Auto) };
+    assert_eq!(other.mode(), ResetMode::Manual);
+}
+
+#[test]
+fn test_lock_uninterruptible_under_signals() {
+    maybe_cleanup!();
+    let _handlers = install_noop_handler(libc::SIGUSR2);
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+    let (held_tx, held_rx) = std::sync::mpsc::channel();
+    let (pthread_tx, pthread_rx) = std::sync::mpsc::channel();
+    let done = AtomicBool::new(false);
+
+    thread::scope(|s| {
+        let mutex = &mutex;
+        s.spawn(move || {
+            let _guard = mutex.lock().unwrap();
+            held_tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(100));
+        });
+        held_rx.recv().unwrap();
+
+        let waiter = s.spawn(|| {
+            pthread_tx.send(unsafe { libc::pthread_self() }).unwrap();
+            let guard = mutex.lock_uninterruptible();
+            done.store(true, Ordering::Relaxed);
+            let held = mutex.owner_tid() == Some(unsafe { gettid() });
+            (guard.is_ok(), held)
+        });
+        let pthread = pthread_rx.recv().unwrap();
+        while !done.load(Ordering::Relaxed) {
+            unsafe { libc::pthread_kill(pthread, libc::SIGUSR2) };
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(waiter.join().unwrap(), (true, true));
+    });
+}
+
+#[test]
+fn test_rwlock_downgrade_keeps_writers_out() {
+    maybe_cleanup!();
+    let lock = unsafe { SharedRwLock::new_with_val(function!(), 0u64) };
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+
+    let mut write = lock.write().unwrap();
+    *write = 1;
+    // the outer guard would go on writing under the read lock
+    let nested = unsafe { lock.write_nested() }.unwrap();
+    drop(nested.downgrade().unwrap_err());
+    assert!(lock.is_write_locked());
+    thread::scope(|s| {
+        let writer = s.spawn(|| {
+            started_tx.send(()).unwrap();
+            *lock.write().unwrap() = 2;
+        });
+        started_rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(10));
+
+        let read = write.downgrade().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(*read, 1);
+        assert!(!writer.is_finished());
+
+        drop(read);
+        writer.join().unwrap();
+    });
+    assert_eq!(*lock.read().unwrap(), 2);
+}
+
+#[test]
+fn test_lock_with_liveness_takes_over_hung_owner() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+    let (held_tx, held_rx) = std::sync::mpsc::channel();
+
+    thread::scope(|s| {
+        let mutex = &mutex;
+        s.spawn(move || {
+            let mut guard = mutex.lock().unwrap();
+            *guard = 7;
+            held_tx.send(unsafe { gettid() }).unwrap();
+            // hung for longer than the liveness window. It must not unlock afterwards,
+            // its lock was taken.
+            thread::sleep(Duration::from_millis(500));
+            std::mem::forget(guard);
+        });
+        let hung = held_rx.recv().unwrap();
+
+        let start = std::time::Instant::now();
+        let Err(LockError::Poisoned(guard)) =
+            (unsafe { mutex.lock_with_liveness(Duration::from_millis(50)) })
+        else {
+            panic!("taking over a hung owner should report poison");
+        };
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(*guard, 7);
+        assert_eq!(mutex.owner_tid(), Some(unsafe { gettid() }));
+        assert_eq!(mutex.last_dead_owner(), Some(hung));
+    });
+}
+
+#[test]
+fn test_guard_debug_shows_owner() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 42u64) };
+    let guard = mutex.lock().unwrap();
+    let debug = format!("{guard:?}");
+    assert_eq!(
+        debug,
+        format!("SharedGuard {{ data: 42, owner: Some({}) }}", unsafe {
+            gettid()
+        })
+    );
+    assert_eq!(guard.to_string(), "42");
+}
+
+#[test]
+fn test_into_inner() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+    *mutex.lock().unwrap() = 99;
+    assert_eq!(mutex.into_inner().unwrap(), 99);
+}
+
+#[test]
+fn test_get_mut_before_sharing() {
+    maybe_cleanup!();
+    let name = function!();
+    let mut mutex = unsafe { SharedMutex::new_with_val(name, [0u8; 4]) };
+    let before = futex::SYSCALLS.with(Cell::get);
+    unsafe { mutex.get_mut() }.copy_from_slice(b"init");
+    assert_eq!(futex::SYSCALLS.with(Cell::get), before);
+    assert!(!mutex.is_locked());
+
+    thread::spawn(move || {
+        let other = unsafe { SharedMutex::<[u8; 4]>::new_with_val(name, [0; 4]) };
+        assert_eq!(&*other.lock().unwrap(), b"init");
+    })
+    .join()
+    .unwrap();
+}
+
+#[test]
+fn test_try_open_reports_bad_name() {
+    let name = "x".repeat(300);
+    let Err(error) = (unsafe { SharedMutex::try_open(&name, || 0u64) }) else {
+        panic!("a 300 byte name is longer than shm_open allows");
+    };
+    // glibc turns this into EINVAL before the kernel can say ENAMETOOLONG
+    let io_error = error.root_cause().downcast_ref::<std::io::Error>();
+    assert!(
+        matches!(
+            io_error.and_then(std::io::Error::raw_os_error),
+            Some(libc::EINVAL | libc::ENAMETOOLONG)
+        ),
+        "{error:#}"
+    );
+    let sized = unsafe { SharedMutex::try_new_sized(&name, 16, || 0u64) };
+    assert!(sized.is_err());
+
+    let Err(error) = (unsafe { SharedMutex::try_open("nul\0byte", || 0u64) }) else {
+        panic!("opened a name with a NUL in it");
+    };
+    let io_error = error.root_cause().downcast_ref::<std::io::Error>();
+    let kind = io_error.map(std::io::Error::kind);
+    assert_eq!(kind, Some(std::io::ErrorKind::InvalidInput), "{error:#}");
+}
+
+#[test]
+fn test_try_open_while_holding_the_lock() {
+    maybe_cleanup!();
+    let name = function!();
+    let mutex = unsafe { SharedMutex::new_with_val(name, 0u64) };
+    let guard = mutex.lock().unwrap();
+    // attaching takes the lock to check the value, which this thread can't
+    let Err(error) = (unsafe { SharedMutex::try_open(name, || 0u64) }) else {
+        panic!("attached while holding the lock");
+    };
+    let io_error = error.root_cause().downcast_ref::<std::io::Error>();
+    let kind = io_error.map(std::io::Error::kind);
+    assert_eq!(kind, Some(std::io::ErrorKind::Deadlock), "{error:#}");
+    drop(guard);
+    assert!(unsafe { SharedMutex::try_open(name, || 0u64) }.is_ok());
+}
+
+#[test]
+fn test_sized_mutex_tail_payload() {
+    maybe_cleanup!();
+    let name = function!();
+    let mutex = unsafe { SharedMutex::new_sized(name, 256, || 0u32) };
+    {
+        let payload = b"variable length payload";
+        let mut guard = mutex.lock().unwrap();
+        *guard = payload.len() as u32;
+        let tail = guard.tail();
+        assert_eq!(tail.len(), 256);
+        tail[..payload.len()].copy_from_slice(payload);
+    }
+
+    thread::spawn(move || {
+        // the creator decided the size
+        let other = unsafe { SharedMutex::new_sized(name, 0, || 0u32) };
+        let mut guard = other.lock().unwrap();
+        let len = *guard as usize;
+        assert_eq!(&guard.tail()[..len], b"variable length payload");
+    })
+    .join()
+    .unwrap();
+
+    let plain_name = "test_sized_mutex_tail_plain";
+    let _cleanup = CleanupGuard::new(plain_name);
+    let plain = unsafe { SharedMutex::new_with_val(plain_name, 0u32) };
+    assert!(plain.lock().unwrap().tail().is_empty());
+}
+
+#[test]
+fn test_compare_exchange_increments() {
+    maybe_cleanup!();
+    const THREADS: u64 = 4;
+    const INCREMENTS: u64 = 1000;
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+
+    let run = |increment: &(dyn Fn() + Sync)| {
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| (0..INCREMENTS).for_each(|_| increment()));
+            }
+        });
+    };
+    run(&|| *mutex.lock().unwrap() += 1);
+    let locked = *mutex.lock().unwrap();
+
+    // nobody holds the lock from here on
+    run(&|| {
+        let mut current = 0;
+        while let Err(actual) = unsafe { mutex.compare_exchange(current, current + 1) } {
+            current = actual;
+        }
+    });
+    let total = *mutex.lock().unwrap();
+    assert_eq!(locked, THREADS * INCREMENTS);
+    assert_eq!(total - locked, locked);
+}
+
+#[test]
+fn test_pi_mutex_lock_timeout_waits() {
+    let mutex = PiMutex::new();
+    let (held_tx, held_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    thread::scope(|s| {
+        let mutex = &mutex;
+        s.spawn(move || {
+            let _guard = mutex.lock().unwrap();
+            held_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        held_rx.recv().unwrap();
+
+        let start = std::time::Instant::now();
+        let Err(error) = mutex.lock_timeout(Duration::from_millis(100)) else {
+            panic!("the lock is held elsewhere");
+        };
+        let elapsed = start.elapsed();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert!(elapsed >= Duration::from_millis(100), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(300), "{elapsed:?}");
+        release_tx.send(()).unwrap();
+    });
+    assert!(mutex.lock_timeout(Duration::from_millis(100)).is_ok());
+}
+
+#[test]
+fn test_pi_mutex_from_raw() {
+    // 8-aligned, big enough even with the tsan fields, and zeroed like fresh shared memory
+    const _: () = assert!(size_of::<futex::AosMutex>() <= 128);
+    let mut buffer = [0u64; 16];
+    let raw = buffer.as_mut_ptr().cast::<futex::AosMutex>();
+    let mutex = unsafe { PiMutex::from_raw(raw) };
+    {
+        let _guard = mutex.lock().unwrap();
+        assert!(mutex.is_locked_by_me());
+        thread::scope(|s| {
+            s.spawn(|| assert!(mutex.try_lock().unwrap().is_none()));
+        });
+    }
+    assert!(!mutex.is_locked());
+    assert_eq!(buffer[0] as u32, 0);
+}
+
+#[test]
+fn test_poison_reason_reaches_next_owner() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u32) };
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            let mut guard = mutex.lock().unwrap();
+            *guard = 1;
+            guard.poison_with(42);
+        });
+    });
+    let guard = mutex.lock().unwrap_err().into_poisoned().unwrap();
+    assert_eq!(*guard, 1);
+    assert_eq!(guard.poison_reason(), Some(42));
+    drop(guard);
+
+    // the clean unlock above cleared both the poison and the reason
+    let guard = mutex.lock().unwrap();
+    assert_eq!(guard.poison_reason(), None);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_segment_length_is_whole_pages() {
+    use crate::shared_mem::page_size;
+
+    maybe_cleanup!();
+    let page = page_size();
+    assert!(page >= 4096 && page.is_power_of_two(), "{page}");
+    // an odd tail so the unrounded length is never a multiple of anything
+    let _mutex = unsafe { SharedMutex::new_sized(function!(), 1, || 0u8) };
+    let length = std::fs::metadata(format!("/dev/shm/{}", function!()))
+        .unwrap()
+        .len() as usize;
+    assert_eq!(length % page, 0, "{length}");
+}
+
+#[test]
+fn test_robust_list_unlock_out_of_order() {
+    maybe_cleanup!();
+    const LOCKS: usize = 64;
+    let array = unsafe { SharedMutexArray::<u32, LOCKS>::new(function!(), |_| 0) };
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            let mut guards: Vec<_> = array.iter().map(|m| Some(m.lock().unwrap())).collect();
+            // oldest first, each from the far end of the list, with the rest interleaved
+            for i in (0..LOCKS).step_by(2) {
+                guards[i] = None;
+            }
+            // die holding the odd ones, which the kernel finds by walking what's left
+            guards.into_iter().flatten().for_each(std::mem::forget);
+        });
+    });
+    for (i, mutex) in array.iter().enumerate() {
+        assert_eq!(mutex.lock().is_err(), i % 2 == 1, "{i}");
+    }
+}
+
+#[test]
+fn test_keep_valid_preserves_data_after_crash() {
+    maybe_cleanup!();
+    let name = function!();
+    // odd values are consistent, an owner mid-update leaves an even one
+    let is_odd = |value: &u32| value % 2 == 1;
+    let mutex = unsafe { SharedMutex::new_with_val(name, 1u32) };
+
+    let die_holding = |value| {
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut guard = mutex.lock().unwrap();
+                *guard = value;
+                std::mem::forget(guard);
+            });
+        })
+    };
+
+    die_holding(7);
+    let kept = unsafe { SharedMutex::new_keep_valid(name, || 1, is_odd) };
+    assert_eq!(*kept.lock().unwrap(), 7);
+
+    die_holding(8);
+    let reset = unsafe { SharedMutex::new_keep_valid(name, || 1, is_odd) };
+    assert_eq!(*reset.lock().unwrap(), 1);
+}
+
+#[test]
+fn test_read_snapshot_matches_guarded_read() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), [1u32, 2, 3]) };
+    *mutex.lock().unwrap() = [4, 5, 6];
+
+    let snapshot = mutex.read_snapshot().unwrap();
+    // released, so a plain lock doesn't block and sees the same value
+    assert!(!mutex.is_locked());
+    assert_eq!(snapshot, *mutex.lock().unwrap());
+
+    thread::scope(|s| {
+        s.spawn(|| std::mem::forget(mutex.lock().unwrap()));
+    });
+    let snapshot = mutex.read_snapshot().unwrap_err().into_poisoned();
+    assert_eq!(snapshot, Some([4, 5, 6]));
+}
+
+#[test]
+fn test_same_name_different_type_in_one_process() {
+    maybe_cleanup!();
+    let name = function!();
+    let first = unsafe { SharedMutex::new_with_val(name, 1u64) };
+
+    let error = unsafe { SharedMutex::try_open(name, || 0u32) }
+        .err()
+        .unwrap();
+    assert!(
+        format!("{error:#}").contains("already mapped in this process"),
+        "{error:#}"
+    );
+    // still fine for the type it was first mapped as
+    let second = unsafe { SharedMutex::try_open(name, || 0u64) }.unwrap();
+    assert_eq!(*second.lock().unwrap(), 1);
+    drop(first);
+}
+
+#[test]
+fn test_lock_retry_budget() {
+    use nix::errno::Errno;
+    use std::sync::mpsc;
+
+    maybe_cleanup!();
+    let mutex = unsafe {
+        SharedMutexOptions::new()
+            .max_lock_retries(3)
+            .open(function!(), || 0u32)
+    };
+    let (held_tx, held_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel();
+
+    let mutex = &mutex;
+
+    thread::scope(|s| {
+        s.spawn(move || {
+            let _guard = mutex.lock().unwrap();
+            held_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        held_rx.recv().unwrap();
+
+        // the first attempt and all 3 retries fail, so the error comes back
+        futex::FAIL_LOCK_PI.set((Errno::EAGAIN, 4));
+        let Err(LockError::Failed(e)) = mutex.lock() else {
+            panic!("running out of retries should fail without a guard");
+        };
+        assert_eq!(e.raw_os_error(), Some(libc::EAGAIN));
+        assert_eq!(futex::FAIL_LOCK_PI.get().1, 0);
+        assert!(!mutex.futex.is_locked_by_me());
+
+        // one failure fewer, and the next attempt waits for the lock as usual
+        futex::FAIL_LOCK_PI.set((Errno::EAGAIN, 3));
+        s.spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            release_tx.send(()).unwrap();
+        });
+        assert!(mutex.lock().is_ok());
+        assert_eq!(futex::FAIL_LOCK_PI.get().1, 0);
+    });
+}
+
+#[test]
+fn test_condvar_wait_notify() {
+    let mutex = PiMutex::new();
+    let condvar = PiCondvar::new();
+    let ready = AtomicBool::new(false);
+
+    let mut guard = mutex.lock().unwrap();
+    let guard = thread::scope(|s| {
+        s.spawn(|| {
+            // only gets the lock once the waiter has released it inside `wait`
+            let _guard = mutex.lock().unwrap();
+            ready.store(true, Ordering::Relaxed);
+            condvar.notify_one(&mutex).unwrap();
+        });
+        while !ready.load(Ordering::Relaxed) {
+            guard = condvar.wait(guard).unwrap();
+        }
+        assert!(mutex.is_locked_by_me());
+        guard
+    });
+    drop(guard);
+    assert!(!mutex.is_locked());
+    // the requeued lock went through the robust list like any other
+    thread::scope(|s| {
+        s.spawn(|| assert!(mutex.try_lock().unwrap().is_some()));
+    });
+}
+
+#[test]
+fn test_condvar_wait_timeout_returns_guard() {
+    let mutex = PiMutex::new();
+    let condvar = PiCondvar::new();
+    // only touched with `mutex` held
+    let state = AtomicU32::new(0);
+
+    let guard = mutex.lock().unwrap();
+    let guard = thread::scope(|s| {
+        s.spawn(|| {
+            // changes the state while the waiter sleeps, but never notifies
+            let _guard = mutex.lock().unwrap();
+            state.store(7, Ordering::Relaxed);
+        });
+        let start = std::time::Instant::now();
+        let (guard, result) = condvar
+            .wait_timeout(guard, Duration::from_millis(100))
+            .unwrap();
+        assert!(result.timed_out());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(mutex.is_locked_by_me());
+        assert_eq!(state.load(Ordering::Relaxed), 7);
+        guard
+    });
+
+    let guard = thread::scope(|s| {
+        s.spawn(|| {
+            let _guard = mutex.lock().unwrap();
+            condvar.notify_one(&mutex).unwrap();
+        });
+        let (guard, result) = condvar
+            .wait_timeout(guard, Duration::from_secs(10))
+            .unwrap();
+        assert!(!result.timed_out());
+        guard
+    });
+    drop(guard);
+    assert!(!mutex.is_locked());
+}
+
+#[test]
+fn test_condvar_group_bounded_buffer() {
+    const CAP: usize = 4;
+    const ITEMS: u32 = 200;
+    const NOT_EMPTY: usize = 0;
+    const NOT_FULL: usize = 1;
+    let mutex = PiMutex::new();
+    let conditions = PiCondvarGroup::<2>::new();
+    // only touched with `mutex` held
+    let slots: [AtomicU32; CAP] = Default::default();
+    let head = AtomicUsize::new(0);
+    let len = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            for item in 0..ITEMS {
+                let mut guard = mutex.lock().unwrap();
+                while len.load(Ordering::Relaxed) == CAP {
+                    guard = conditions.wait_on(NOT_FULL, guard).unwrap();
+                }
+                let tail = (head.load(Ordering::Relaxed) + len.load(Ordering::Relaxed)) % CAP;
+                slots[tail].store(item, Ordering::Relaxed);
+                len.fetch_add(1, Ordering::Relaxed);
+                conditions.notify_one_on(NOT_EMPTY, &mutex).unwrap();
+                drop(guard);
+            }
+        });
+        for expected in 0..ITEMS {
+            let mut guard = mutex.lock().unwrap();
+            while len.load(Ordering::Relaxed) == 0 {
+                guard = conditions.wait_on(NOT_EMPTY, guard).unwrap();
+            }
+            let first = head.load(Ordering::Relaxed);
+            assert_eq!(slots[first].load(Ordering::Relaxed), expected);
+            head.store((first + 1) % CAP, Ordering::Relaxed);
+            len.fetch_sub(1, Ordering::Relaxed);
+            conditions.notify_one_on(NOT_FULL, &mutex).unwrap();
+            drop(guard);
+        }
+    });
+    assert_eq!(len.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_try_lock_io() -> std::io::Result<()> {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u32) };
+    *mutex.try_lock_io()? = 1;
+
+    let guard = mutex.lock().unwrap();
+    thread::scope(|s| {
+        s.spawn(|| {
+            let error = mutex.try_lock_io().err().unwrap();
+            assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+        });
+    });
+    drop(guard);
+
+    thread::scope(|s| {
+        s.spawn(|| mutex.lock().unwrap().poison_with(7));
+    });
+    let error = mutex.try_lock_io().err().unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    // still poisoned for a lock to repair
+    let guard = mutex.lock().unwrap_err().into_poisoned().unwrap();
+    assert_eq!(guard.poison_reason(), Some(7));
+    assert_eq!(*guard, 1);
+    drop(guard);
+    assert_eq!(*mutex.try_lock_io()?, 1);
+
+    // taking over from a dead owner enters the kernel, which can fail
+    thread::spawn({
+        let mutex = mutex.clone();
+        move || std::mem::forget(mutex.lock().unwrap())
+    })
+    .join()
+    .unwrap();
+    futex::FAIL_LOCK_PI.set((nix::errno::Errno::ENOMEM, 1));
+    let error = mutex.try_lock_io().err().unwrap();
+    assert_eq!(error.raw_os_error(), Some(libc::ENOMEM));
+    assert!(!mutex.held_by_current_thread());
+    let guard = mutex.lock().unwrap_err().into_poisoned().unwrap();
+    assert_eq!(guard.poison_reason(), None);
+    Ok(())
+}
+
+#[test]
+fn test_debug_robust_list_shows_held_locks() {
+    let first = PiMutex::new();
+    let second = PiMutex::new();
+    let me = futex::tid() as u32;
+    let node = |mutex: &PiMutex| (&raw const mutex.0.next).cast_mut().cast();
+
+    let before = futex::debug_robust_list();
+    let first_guard = first.lock().unwrap();
+    let second_guard = second.lock().unwrap();
+    let list = futex::debug_robust_list();
+    assert_eq!(list.len(), before.len() + 2);
+    // newest first, each pointing at a futex word owned by this thread
+    assert_eq!(list[0], (node(&second), me));
+    assert_eq!(list[1], (node(&first), me));
+
+    drop(first_guard);
+    assert_eq!(futex::debug_robust_list()[0], (node(&second), me));
+    drop(second_guard);
+    assert_eq!(futex::debug_robust_list(), before);
+}
+
+#[test]
+fn test_guard_slice_borrows_window() {
+    maybe_cleanup!();
+    const LEN: usize = 1 << 16;
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), [0u8; LEN]) };
+    let mut guard = mutex.lock().unwrap();
+    for (i, byte) in guard.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+
+    let window = guard.slice(1000..1016);
+    assert_eq!(window, &guard[1000..1016]);
+    // the segment itself, not a copy
+    assert_eq!(window.as_ptr(), guard.as_ptr().wrapping_add(1000));
+    assert!(guard.slice(LEN..LEN).is_empty());
+
+    let out_of_bounds = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        guard.slice(LEN - 1..LEN + 1)
+    }));
+    assert!(out_of_bounds.is_err());
+}
+
+#[test]
+fn test_condvar_concurrent_notifiers_wake_everyone() {
+    use std::sync::mpsc;
+
+    const WAITERS: u32 = 4;
+    const NOTIFIERS: u32 = 4;
+    const ROUNDS: u32 = 200;
+    struct State {
+        mutex: PiMutex,
+        condvar: PiCondvar,
+        /// only touched with `mutex` held
+        tickets: AtomicU32,
+    }
+    let state = Arc::new(State {
+        mutex: PiMutex::new(),
+        condvar: PiCondvar::new(),
+        tickets: AtomicU32::new(0),
+    });
+
+    // not scoped, so a stuck waiter fails the test instead of hanging it
+    let (done_tx, done_rx) = mpsc::channel();
+    for _ in 0..WAITERS {
+        let state = state.clone();
+        let done_tx = done_tx.clone();
+        thread::spawn(move || {
+            for _ in 0..ROUNDS * NOTIFIERS / WAITERS {
+                let mut guard = state.mutex.lock().unwrap();
+                while state.tickets.load(Ordering::Relaxed) == 0 {
+                    guard = state.condvar.wait(guard).unwrap();
+                }
+                state.tickets.fetch_sub(1, Ordering::Relaxed);
+                drop(guard);
+            }
+            done_tx.send(()).unwrap();
+        });
+    }
+    for _ in 0..NOTIFIERS {
+        let state = state.clone();
+        thread::spawn(move || {
+            for _ in 0..ROUNDS {
+                let guard = state.mutex.lock().unwrap();
+                state.tickets.fetch_add(1, Ordering::Relaxed);
+                drop(guard);
+                // outside the lock, so notifiers race each other on the generation
+                state.condvar.notify_one(&state.mutex).unwrap();
+            }
+        });
+    }
+    for _ in 0..WAITERS {
+        done_rx
+            .recv_timeout(Duration::from_secs(10))
+            .expect("a waiter missed its wakeup");
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_prefault_avoids_faults_in_critical_section() {
+    use crate::shared_mem::page_size;
+
+    const SIZE: usize = 8 << 20;
+
+    fn minor_faults() -> i64 {
+        let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+        assert_eq!(
+            unsafe { libc::getrusage(libc::RUSAGE_THREAD, usage.as_mut_ptr()) },
+            0
+        );
+        unsafe { usage.assume_init() }.ru_minflt
+    }
+
+    maybe_cleanup!();
+    let name = function!();
+    // `initial` returns the array by value
+    thread::Builder::new()
+        .stack_size(4 * SIZE)
+        .spawn(move || {
+            drop(unsafe { SharedMutex::new(name, || [0u8; SIZE]) });
+            let page = page_size();
+            let pages = SIZE / page;
+
+            // a fresh mapping of the same segment each time, so only its page tables differ
+            let first_write = |prefault: bool| {
+                let options = SharedMutexOptions::new().prefault(prefault);
+                let mutex = unsafe { options.open(name, || [0u8; SIZE]) };
+                let mut guard = mutex.lock().unwrap();
+                let faults = minor_faults();
+                let start = std::time::Instant::now();
+                for i in (0..SIZE).step_by(page) {
+                    guard[i] = guard[i].wrapping_add(1);
+                }
+                let elapsed = start.elapsed();
+                let faults = minor_faults() - faults;
+                println!("prefault {prefault}: {faults} faults in {elapsed:?} for {pages} pages");
+                faults as usize
+            };
+
+            let lazy = first_write(false);
+            let prefaulted = first_write(true);
+            // the kernel may fault in more than one page at a time, but never none
+            assert!(lazy > 0);
+            assert!(prefaulted * 4 < lazy, "{prefaulted} vs {lazy}");
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_lock_memory() {
+    /// `Locked:` of our mapping of `name`, in kB
+    fn locked_kb(name: &str) -> Option<u64> {
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let path = format!("/dev/shm/{name}");
+        let mut lines = smaps.lines();
+        lines.find(|line| line.ends_with(&path))?;
+        lines
+            .find_map(|line| line.strip_prefix("Locked:"))
+            .map(|kb| kb.trim().trim_end_matches("kB").trim().parse().unwrap())
+    }
+
+    maybe_cleanup!();
+    let options = SharedMutexOptions::new().lock_memory(true);
+    let mutex = match unsafe { options.try_open(function!(), || [0u8; 16384]) } {
+        Ok(mutex) => mutex,
+        Err(e) => {
+            let kind = e.downcast_ref::<std::io::Error>().map(|e| e.kind());
+            assert!(
+                matches!(
+                    kind,
+                    Some(std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::OutOfMemory)
+                ),
+                "{e:#}"
+            );
+            println!("can't lock memory here, skipping: {e:#}");
+            return;
+        }
+    };
+    // the value alone, plus at least a page of header
+    let locked = locked_kb(function!()).unwrap();
+    assert!(locked > 16, "{locked} kB");
+    drop(mutex.lock().unwrap());
+
+    drop(mutex);
+    assert_eq!(locked_kb(function!()), None);
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_initial_runs_once_across_processes() {
+    use std::io::Write;
+
+    const CHILDREN: usize = 8;
+
+    maybe_cleanup!();
+    let name = function!();
+    // every run of `initial` appends a byte here
+    let runs = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_file(&runs);
+    let start = std::time::Instant::now() + Duration::from_millis(100);
+    let children: Vec<_> = (0..CHILDREN)
+        .map(|_| {
+            fork(|| {
+                thread::sleep(start.saturating_duration_since(std::time::Instant::now()));
+                let mutex = unsafe {
+                    SharedMutex::new(name, || {
+                        let mut file = std::fs::OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(&runs)
+                            .unwrap();
+                        file.write_all(b"x").unwrap();
+                        // long enough for everyone else to pile up on the lock
+                        thread::sleep(Duration::from_millis(50));
+                        7u64
+                    })
+                };
+                let value = *mutex.lock().unwrap();
+                unsafe { libc::_exit(value as i32) };
+            })
+        })
+        .collect();
+
+    for child in children {
+        let status = wait(child);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 7);
+    }
+    assert_eq!(std::fs::read(&runs).unwrap(), b"x");
+    std::fs::remove_file(&runs).unwrap();
+}
+
+#[test]
+fn test_panicking_initial_leaves_segment_usable() {
+    use std::sync::mpsc;
+
+    maybe_cleanup!();
+    let name = function!();
+    // caught, so the thread that was running `initial` lives on
+    let panicked = std::panic::catch_unwind(|| unsafe {
+        SharedMutex::new(name, || -> u32 { panic!("initial failed") })
+    });
+    assert!(panicked.is_err());
+
+    // not left locked by this thread, and its value counts as never written
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mutex = unsafe { SharedMutex::new(name, || 5u32) };
+        tx.send(*mutex.try_lock().unwrap().unwrap()).unwrap();
+    });
+    assert_eq!(rx.recv_timeout(Duration::from_secs(10)), Ok(5));
+}
+
+/// What a mutex needs from any backend, run against each of them
+fn exercise_backend(backend: &(impl MemoryBackend + Sync), name: &str) {
+    let mutex = unsafe { SharedMutex::try_open_in(backend, name, || 0u64) }.unwrap();
+    // a second mapping of the segment is the same memory
+    let other = unsafe { SharedMutex::try_open_in(backend, name, || 5u64) }.unwrap();
+    *mutex.lock().unwrap() = 1;
+    assert_eq!(*other.lock().unwrap(), 1);
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..100 {
+                    *mutex.lock().unwrap() += 1;
+                }
+            });
+        }
+    });
+    // the robust list doesn't care what the memory is
+    thread::scope(|s| {
+        s.spawn(|| std::mem::forget(mutex.lock().unwrap()));
+    });
+    let Err(LockError::Poisoned(guard)) = other.lock() else {
+        panic!("the thread died holding the lock");
+    };
+    assert_eq!(*guard, 401);
+    drop(guard);
+
+    // existing mappings outlive unlinking, the name starts over
+    backend.unlink(name).unwrap();
+    assert_eq!(*mutex.lock().unwrap(), 401);
+    let fresh = unsafe { SharedMutex::try_open_in(backend, name, || 7u64) }.unwrap();
+    assert_eq!(*fresh.lock().unwrap(), 7);
+    assert_eq!(*mutex.lock().unwrap(), 401);
+    backend.unlink(name).unwrap();
+}
+
+#[test]
+fn test_shm_backend() {
+    maybe_cleanup!();
+    exercise_backend(&ShmBackend, function!());
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_dir_backend() {
+    use crate::DirBackend;
+
+    let name = function!();
+    let dir = std::env::temp_dir().join(format!("{name}.{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let backend = DirBackend::new(&dir);
+    let file = backend.path_of(name);
+    assert_eq!(file, dir.join(name));
+
+    let options = SharedMutexOptions::new().path(&dir);
+    let mutex = unsafe { options.open(name, || 3u32) };
+    assert!(file.exists());
+    assert!(!std::path::Path::new("/dev/shm").join(name).exists());
+    *mutex.lock().unwrap() = 4;
+    let other = unsafe { options.open(name, || 0u32) };
+    assert_eq!(*other.lock().unwrap(), 4);
+    backend.unlink(name).unwrap();
+    assert!(!file.exists());
+    drop((mutex, other));
+
+    exercise_backend(&backend, name);
+    assert!(!file.exists());
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_backends_are_separate_namespaces() {
+    use crate::DirBackend;
+
+    maybe_cleanup!();
+    let name = function!();
+    let dir = std::env::temp_dir().join(format!("{name}.{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let backend = DirBackend::new(&dir);
+
+    // one name, two segments, so two types is fine
+    let in_dir = unsafe { SharedMutex::try_open_in(&backend, name, || 1u32) }.unwrap();
+    let in_shm = unsafe { SharedMutex::new_checked(name, || 2u64) }.unwrap();
+    assert_eq!(*in_dir.lock().unwrap(), 1);
+    assert_eq!(*in_shm.lock().unwrap(), 2);
+
+    // but a directory over /dev/shm holds the same segment as ShmBackend
+    let aliased = DirBackend::new("/dev/shm");
+    let other_type = unsafe { SharedMutex::try_open_in(&aliased, name, || 0u32) };
+    assert!(other_type.err().unwrap().is::<TypeMismatch>());
+    drop((in_dir, in_shm));
+
+    backend.unlink(name).unwrap();
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+#[cfg(feature = "mock_backend")]
+#[test]
+fn test_mock_backend_stays_in_process() {
+    use crate::MockBackend;
+
+    let name = function!();
+    let on_disk = std::path::Path::new("/dev/shm").join(name);
+    exercise_backend(&MockBackend, name);
+
+    let options = SharedMutexOptions::new().fair(true).prefault(true);
+    let mutex = unsafe { options.try_open_in(&MockBackend, name, || 3u32) }.unwrap();
+    assert_eq!(*mutex.lock().unwrap(), 3);
+    assert!(!on_disk.exists());
+    MockBackend.unlink(name).unwrap();
+    assert!(MockBackend.unlink(name).is_err());
+
+    // the tests written against any backend
+    type BackendTest = fn(&MockBackend, &str);
+    let suite: [(&str, BackendTest); 5] = [
+        ("mutex", basic_mutex_operations),
+        ("rwlock", reentrant_rwlock_nesting),
+        ("queue", queue_producer_consumer),
+        ("barrier", barrier_rounds),
+        ("event", manual_reset_event_releases_everyone),
+    ];
+    for (kind, test) in suite {
+        let name = format!("{name}_{kind}");
+        test(&MockBackend, &name);
+        assert!(!std::path::Path::new("/dev/shm").join(&name).exists());
+        MockBackend.unlink(&name).unwrap();
+    }
+}
+
+#[test]
+fn test_bit_ops() {
+    const THREADS: u32 = 8;
+    maybe_cleanup!();
+    let flags = unsafe { SharedMutex::new_with_val(function!(), 0u16) };
+
+    thread::scope(|s| {
+        for i in 0..THREADS {
+            let flags = &flags;
+            s.spawn(move || {
+                // every thread owns one bit, and only ever sees its own bit change
+                let bit = 1 << i;
+                for round in 0..100 {
+                    let previous = flags.toggle_bits(bit).unwrap();
+                    assert_eq!(previous & bit != 0, round % 2 == 1);
+                }
+                assert_eq!(flags.set_bits(bit).unwrap() & bit, 0);
+                assert_eq!(flags.set_bits(bit).unwrap() & bit, bit);
+            });
+        }
+    });
+    assert_eq!(*flags.lock().unwrap(), 0xff);
+
+    assert_eq!(flags.clear_bits(0x0f).unwrap(), 0xff);
+    assert_eq!(flags.toggle_bits(0x101).unwrap(), 0xf0);
+    assert_eq!(*flags.lock().unwrap(), 0x1f1);
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_crash_between_value_and_init_flag() {
+    use crate::shared_data::CRASH_BEFORE_INIT_DONE;
+
+    maybe_cleanup!();
+    let name = function!();
+    let child = fork(|| {
+        CRASH_BEFORE_INIT_DONE.set(true);
+        unsafe { SharedMutex::new(name, || 0xdeadu64) };
+        unreachable!();
+    });
+    let status = wait(child);
+    assert!(libc::WIFEXITED(status));
+    assert_eq!(libc::WEXITSTATUS(status), 1);
+
+    // the child's value was complete, but nothing said so, so it's replaced
+    let Err(mutex) = (unsafe { SharedMutex::try_new(name, || 7u64) }) else {
+        panic!("the child died holding the lock");
+    };
+    assert_eq!(*mutex.lock().unwrap(), 7);
+}
+
+#[test]
+fn test_try_unlock_from_non_owner() {
+    use nix::errno::Errno;
+    use std::sync::mpsc;
+
+    let mutex = PiMutex::new();
+    let (locked_tx, locked_rx) = mpsc::channel();
+    let (tried_tx, tried_rx) = mpsc::channel();
+
+    thread::scope(|s| {
+        let mutex = &mutex;
+        s.spawn(move || {
+            std::mem::forget(mutex.lock().unwrap());
+            locked_tx.send(()).unwrap();
+            tried_rx.recv().unwrap();
+            // still ours
+            assert!(mutex.is_locked_by_me());
+            unsafe { mutex.try_unlock() }.unwrap();
+        });
+        locked_rx.recv().unwrap();
+        let e = unsafe { mutex.try_unlock() }.unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(Errno::EPERM as i32));
+        assert!(mutex.is_locked());
+        tried_tx.send(()).unwrap();
+    });
+    assert!(!mutex.is_locked());
+    // nobody holds it now, which is no different to the kernel
+    assert!(unsafe { mutex.try_unlock() }.is_err());
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_weak_upgrade() {
+    use crate::SharedMutexWeak;
+
+    maybe_cleanup!();
+    let weak = unsafe { SharedMutexWeak::<u64>::new(function!()) };
+    assert!(weak.upgrade().is_none(), "not created yet");
+
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 5u64) };
+    *mutex.lock().unwrap() = 6;
+    drop(mutex);
+    // unmapped, but the segment is still there
+    let mutex = weak.clone().upgrade().unwrap();
+    assert_eq!(*mutex.lock().unwrap(), 6);
+    let other_type = unsafe { SharedMutexWeak::<[u32; 2]>::new(function!()) };
+    assert!(other_type.upgrade().is_none());
+
+    drop(mutex);
+    unlink_if_exists(function!()).unwrap();
+    assert!(weak.upgrade().is_none(), "unlinked");
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_name_is_stored() {
+    use crate::{NAME_LEN, SharedMutexArray};
+
+    maybe_cleanup!();
+    let name = function!();
+    let mutex = unsafe { SharedMutex::new_with_val(name, 0u32) };
+    assert_eq!(mutex.name(), name);
+    drop(mutex);
+    unlink_if_exists(name).unwrap();
+
+    // cut at the last whole character that fits
+    let long = format!("{name}_{}é", "x".repeat(NAME_LEN - name.len() - 2));
+    assert_eq!(long.len(), NAME_LEN + 1);
+    let mutex = unsafe { SharedMutex::new_with_val(&long, 0u32) };
+    assert_eq!(mutex.name(), &long[..NAME_LEN - 1]);
+    drop(mutex);
+    unlink_if_exists(&long).unwrap();
+
+    let array_name = format!("{name}_array");
+    let array = unsafe { SharedMutexArray::<u32, 2>::new(&array_name, |_| 0) };
+    assert_eq!(array.get(1).name(), format!("{array_name}[1]"));
+    drop(array);
+    unlink_if_exists(&array_name).unwrap();
+}
+
+#[test]
+fn test_lock_and_try_lock_report_poison_alike() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+    let poisoners: [fn(&SharedMutex<u64>, u64); 3] = [
+        |mutex, value| {
+            // joined explicitly: a scope can end before the kernel is done with the thread
+            thread::scope(|s| {
+                s.spawn(|| {
+                    let mut guard = mutex.lock().unwrap();
+                    *guard = value;
+                    std::mem::forget(guard);
+                })
+                .join()
+                .unwrap();
+            })
+        },
+        |mutex, value| {
+            thread::scope(|s| {
+                s.spawn(|| {
+                    let mut guard = mutex.lock().unwrap();
+                    *guard = value;
+                    panic!("poisoning on purpose");
+                })
+                .join()
+                .unwrap_err();
+            })
+        },
+        |mutex, value| {
+            let mut guard = mutex.lock().unwrap();
+            *guard = value;
+            guard.poison_with(7);
+        },
+    ];
+
+    for (i, poison) in poisoners.iter().enumerate() {
+        let value = i as u64 + 1;
+        poison(&mutex, value);
+        let guard = mutex.lock().unwrap_err().into_poisoned().unwrap();
+        let from_lock = (*guard, guard.poison_reason());
+        drop(guard);
+        assert!(mutex.lock().is_ok(), "dropping the guard clears the poison");
+
+        poison(&mutex, value);
+        let guard = mutex.try_lock().unwrap_err().into_poisoned().unwrap();
+        assert_eq!((*guard, guard.poison_reason()), from_lock);
+        drop(guard);
+        assert!(matches!(mutex.try_lock(), Ok(Some(_))));
+    }
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_lock_many_opposite_orders() {
+    use crate::lock_many;
+
+    maybe_cleanup!();
+    let name = function!();
+    let mutexes: Vec<_> = (0..3)
+        .map(|i| unsafe { SharedMutex::new_with_val(&format!("{name}_{i}"), 0u64) })
+        .collect();
+    let forward: Vec<&crate::shared_data::SharedMutexInner<u64>> =
+        mutexes.iter().map(|m| &**m).collect();
+    let backward: Vec<_> = forward.iter().rev().copied().collect();
+
+    thread::scope(|s| {
+        for order in [&forward, &backward] {
+            s.spawn(move || {
+                for _ in 0..1000 {
+                    let mut guards = lock_many(order).unwrap();
+                    for guard in &mut guards {
+                        **guard += 1;
+                    }
+                }
+            });
+        }
+    });
+    for mutex in &mutexes {
+        assert_eq!(*mutex.lock().unwrap(), 2000);
+    }
+
+    // the middle one dies locked, the rest are fine
+    thread::scope(|s| {
+        s.spawn(|| std::mem::forget(mutexes[1].lock().unwrap()))
+            .join()
+            .unwrap();
+    });
+    let poisoned = lock_many(&backward).err().unwrap();
+    assert_eq!(poisoned.poisoned(), [1]);
+    let guards = poisoned.into_guards();
+    let values: Vec<u64> = guards.iter().map(|guard| **guard).collect();
+    assert_eq!(values, [2000; 3]);
+    drop(guards);
+    assert!(lock_many(&forward).is_ok());
+
+    drop(mutexes);
+    for i in 0..3 {
+        unlink_if_exists(&format!("{name}_{i}")).unwrap();
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_condvar_notify_counts() {
+    use std::sync::mpsc;
+
+    struct State {
+        mutex: PiMutex,
+        condvar: PiCondvar,
+    }
+    let state = Arc::new(State {
+        mutex: PiMutex::new(),
+        condvar: PiCondvar::new(),
+    });
+    let sleeping = |tid| futex::is_sleeping(tid).unwrap();
+
+    let (tid_tx, tid_rx) = mpsc::channel();
+    let waiters: Vec<_> = (0..3)
+        .map(|_| {
+            let state = state.clone();
+            let tid_tx = tid_tx.clone();
+            thread::spawn(move || {
+                let guard = state.mutex.lock().unwrap();
+                tid_tx.send(unsafe { gettid() }).unwrap();
+                // nothing else sleeps between sending the TID and this
+                drop(state.condvar.wait(guard).unwrap());
+            })
+        })
+        .collect();
+    let tids: Vec<i32> = tid_rx.iter().take(3).collect();
+    while !tids.iter().all(|&tid| sleeping(tid)) {
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    assert_eq!(state.condvar.notify_one_count(&state.mutex).unwrap(), 1);
+    assert_eq!(state.condvar.notify_all_count(&state.mutex).unwrap(), 2);
+    for waiter in waiters {
+        waiter.join().unwrap();
+    }
+    assert_eq!(state.condvar.notify_all_count(&state.mutex).unwrap(), 0);
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_lock_free_counter_across_processes() {
+    use crate::LockFree;
+    use std::sync::atomic::AtomicU64;
+
+    maybe_cleanup!();
+    let name = function!();
+    let mutex = unsafe { SharedMutex::new(name, LockFree::<[AtomicU64; 2]>::default) };
+    // nothing here takes `&mut` from a guard
+    let counters = unsafe { mutex.lock_free() };
+    let bump = || {
+        for _ in 0..10_000 {
+            counters[0].fetch_add(1, Ordering::Relaxed);
+        }
+    };
+    // hold the lock throughout, the counter doesn't care
+    let guard = mutex.lock().unwrap();
+    let child = fork(bump);
+    bump();
+    wait_ok(child);
+    assert_eq!(guard[0].load(Ordering::Relaxed), 20_000);
+
+    // compound updates still go through the lock
+    let moved = guard[0].swap(0, Ordering::Relaxed);
+    guard[1].fetch_add(moved, Ordering::Relaxed);
+    drop(guard);
+    assert_eq!(counters[1].load(Ordering::Relaxed), 20_000);
+}
+
+#[test]
+fn test_relocking_reports_deadlock() {
+    maybe_cleanup!();
+    let futex = PiMutex::new();
+    let guard = futex.lock().unwrap();
+    let error = futex.lock().err().unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::Deadlock);
+    assert!(error.to_string().contains("already holds"), "{error}");
+    drop(guard);
+    drop(futex.lock().unwrap());
+
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u32) };
+    let guard = mutex.lock().unwrap();
+    let Err(LockError::Failed(error)) = mutex.lock() else {
+        panic!("relocked");
+    };
+    assert_eq!(error.kind(), std::io::ErrorKind::Deadlock);
+    let message = error.to_string();
+    assert!(message.contains("already held by this thread"), "{message}");
+    let Err(LockError::Failed(error)) = mutex.lock_shared_read() else {
+        panic!("relocked");
+    };
+    assert_eq!(error.kind(), std::io::ErrorKind::Deadlock);
+    assert!(mutex.is_locked(), "the outer guard still holds it");
+    drop(guard);
+    assert!(!mutex.is_locked());
+}
+
+#[test]
+fn test_outer_lock_methods() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 1u32) };
+    *SharedMutex::lock(&mutex).unwrap() += 1;
+    let guard = SharedMutex::try_lock(&mutex).unwrap().unwrap();
+    thread::scope(|s| {
+        s.spawn(|| {
+            assert!(SharedMutex::try_lock(&mutex).unwrap().is_none());
+            let timed_out = SharedMutex::lock_timeout(&mutex, Duration::from_millis(10));
+            assert!(timed_out.unwrap().is_none());
+        });
+    });
+    drop(guard);
+    let guard = SharedMutex::lock_timeout(&mutex, Duration::from_secs(1)).unwrap();
+    assert_eq!(guard.as_deref(), Some(&2));
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_checksum_catches_writes_outside_the_lock() {
+    use crate::CHECKSUM_MISMATCH;
+
+    maybe_cleanup!();
+    let options = SharedMutexOptions::new().checksum::<[u32; 16]>(true);
+    let other = format!("{}_other", function!());
+    assert!(unsafe { options.try_open(&other, || [0u64; 8]) }.is_err());
+    let mut mutex = unsafe { options.open(function!(), || [0u32; 16]) };
+    mutex.lock().unwrap()[3] = 7;
+    assert_eq!(mutex.lock().unwrap()[3], 7, "untouched since the unlock");
+
+    // a stray write that never took the lock
+    unsafe { mutex.get_mut()[9] = 1 };
+    let guard = mutex.lock().unwrap_err().into_poisoned().unwrap();
+    assert_eq!(guard.poison_reason(), Some(CHECKSUM_MISMATCH));
+    assert_eq!(guard[9], 1);
+    let reserved = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        guard.poison_with(CHECKSUM_MISMATCH);
+    }));
+    assert!(reserved.is_err(), "reserved");
+    drop(guard);
+    assert_eq!(mutex.lock().unwrap()[9], 1, "accepted by the last owner");
+
+    // without the option nothing is checked
+    let name = format!("{}_unchecked", function!());
+    let mut unchecked = unsafe { SharedMutex::new_with_val(&name, 0u32) };
+    unsafe { *unchecked.get_mut() = 1 };
+    assert_eq!(*unchecked.lock().unwrap(), 1);
+    drop(unchecked);
+    unlink_if_exists(&name).unwrap();
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_unlock_from_signal_handler() {
+    // forced before the handler can run, which then only reads it
+    static MUTEX: std::sync::LazyLock<PiMutex> = std::sync::LazyLock::new(PiMutex::new);
+    extern "C" fn release(_: libc::c_int) {
+        // panicking can't unwind out of a handler, the asserts below catch a failure
+        let _ = unsafe { MUTEX.async_signal_safe_unlock() };
+    }
+    let _handlers = take_signal_handlers();
+    let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = release as *const () as usize;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGUSR2, &action, &mut previous);
+    }
+
+    // uncontended, just the word
+    std::mem::forget(MUTEX.lock().unwrap());
+    unsafe { libc::raise(libc::SIGUSR2) };
+    assert!(!MUTEX.is_locked());
+
+    // with a waiter in the kernel to hand it to
+    std::mem::forget(MUTEX.lock().unwrap());
+    thread::scope(|s| {
+        let waiter = s.spawn(|| drop(MUTEX.lock().unwrap()));
+        while MUTEX.0.futex.load(Ordering::Relaxed) & libc::FUTEX_WAITERS == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        unsafe { libc::raise(libc::SIGUSR2) };
+        waiter.join().unwrap();
+    });
+    assert!(!MUTEX.is_locked());
+    drop(MUTEX.try_lock().unwrap().unwrap());
+    unsafe { libc::sigaction(libc::SIGUSR2, &previous, std::ptr::null_mut()) };
+}
+
+#[test]
+fn test_counter_saturates_and_checks() {
+    use crate::{CounterOverflow, SharedCounter};
+
+    maybe_cleanup!();
+    let counter = SharedCounter::new(function!(), u64::MAX - 2);
+    assert_eq!(counter.fetch_add_checked(5), Err(CounterOverflow));
+    assert_eq!(counter.load(), u64::MAX - 2, "left alone on overflow");
+    assert_eq!(counter.fetch_add_checked(1), Ok(u64::MAX - 2));
+    assert_eq!(counter.fetch_add_saturating(5), u64::MAX - 1);
+    assert_eq!(counter.load(), u64::MAX);
+    assert_eq!(counter.fetch_add_saturating(1), u64::MAX);
+    assert_eq!(counter.load(), u64::MAX);
+
+    // the same segment through the options builder
+    let options = SharedMutexOptions::new().fair(true);
+    let reopened = SharedCounter::from(unsafe { options.open(function!(), || 0) });
+    assert_eq!(reopened.load(), u64::MAX);
+}
+
+#[test]
+fn test_counter_wraps_under_concurrent_increments() {
+    use crate::SharedCounter;
+
+    maybe_cleanup!();
+    let step = u64::MAX / 3;
+    let counter = SharedCounter::new(function!(), 0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..100 {
+                    counter.fetch_add_wrapping(step);
+                }
+            });
+        }
+    });
+    assert_eq!(counter.load(), step.wrapping_mul(400));
+}
+
+#[test]
+fn test_try_lock_never_waits_on_owner_died_bit() {
+    use std::sync::mpsc;
+
+    let mutex = &PiMutex::new();
+    let (held_tx, held_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    thread::scope(|s| {
+        s.spawn(move || {
+            let guard = mutex.lock().unwrap();
+            // how the word looks right after the kernel handed a dead owner's lock to a
+            // waiter, before that waiter clears the bit
+            let word = &mutex.0.futex;
+            word.fetch_or(futex::FUTEX_OWNER_DIED, Ordering::Relaxed);
+            held_tx.send(()).unwrap();
+            let _ = release_rx.recv_timeout(Duration::from_secs(5));
+            drop(guard);
+        });
+        held_rx.recv().unwrap();
+
+        let start = std::time::Instant::now();
+        assert!(mutex.try_lock().unwrap().is_none());
+        assert!(start.elapsed() < Duration::from_secs(1), "try_lock waited");
+        release_tx.send(()).unwrap();
+    });
+    assert!(!mutex.is_locked());
+    drop(mutex.try_lock().unwrap().unwrap());
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_anonymous_mutex_shared_with_fork_child() {
+    maybe_cleanup!();
+    let mutex = SharedMutex::new_anonymous(|| 0u64);
+    assert_eq!(mutex.name(), "(anonymous)");
+    let bump = || {
+        for _ in 0..1000 {
+            *mutex.lock().unwrap() += 1;
+        }
+    };
+    let child = fork(bump);
+    bump();
+    wait_ok(child);
+    assert_eq!(*mutex.lock().unwrap(), 2000);
+
+    // the child's death while holding it is seen like any owner's
+    wait_ok(fork(|| std::mem::forget(mutex.lock().unwrap())));
+    let guard = mutex.lock().unwrap_err().into_poisoned().unwrap();
+    assert_eq!(*guard, 2000);
+}
+
+#[test]
+fn test_seqlock_reads_are_never_torn() {
+    maybe_cleanup!();
+    let options = SharedMutexOptions::new().seqlock(true);
+    let mutex = unsafe { options.open(function!(), || [0u64; 512]) };
+    let done = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        for _ in 0..2 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    let mut guard = mutex.lock().unwrap();
+                    let next = guard[0] + 1;
+                    guard[..256].fill(next);
+                    // let the reader in mid-write
+                    std::thread::yield_now();
+                    guard[256..].fill(next);
+                }
+            });
+        }
+        let reader = s.spawn(|| {
+            let mut reads = 0;
+            while !done.load(Ordering::Relaxed) {
+                let value = mutex.read_seqlock().unwrap();
+                assert!(value.iter().all(|&v| v == value[0]), "torn read");
+                reads += 1;
+            }
+            reads
+        });
+        while mutex.read_seqlock().unwrap()[0] < 2000 {
+            std::thread::yield_now();
+        }
+        done.store(true, Ordering::Relaxed);
+        assert!(reader.join().unwrap() > 0);
+    });
+
+    // a guard that can't write doesn't hold readers off, not even its own thread
+    let guard = mutex.lock_shared_read().unwrap();
+    assert_eq!(mutex.read_seqlock().unwrap()[0], 2000);
+    drop(guard);
+
+    // a writer that dies mid-write is poison to readers too
+    std::thread::scope(|s| {
+        let writer = s.spawn(|| std::mem::forget(mutex.lock().unwrap()));
+        writer.join().unwrap();
+    });
+    let copy = mutex.read_seqlock().unwrap_err().into_poisoned().unwrap();
+    assert_eq!(copy[0], 2000);
+    assert_eq!(mutex.read_seqlock().unwrap()[0], 2000);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_lock_granted_in_priority_order() {
+    maybe_cleanup!();
+    let seen = crate::__testing_priority_order(4).unwrap();
+    let mut granted = seen.order.clone();
+    granted.sort_unstable();
+    assert_eq!(granted, [0, 1, 2, 3]);
+    if seen.realtime {
+        assert_eq!(seen.order, [3, 2, 1, 0]);
+    }
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_from_fd() {
+    maybe_cleanup!();
+    let name = function!();
+    let mutex = unsafe { SharedMutex::new_with_val(name, 1u32) };
+    let shm_name = std::ffi::CString::new(format!("/{name}")).unwrap();
+    let fd = unsafe { libc::shm_open(shm_name.as_ptr(), libc::O_RDWR, 0) };
+    assert!(fd >= 0, "{}", std::io::Error::last_os_error());
+    // as if it had come over a socket
+    let passed = unsafe { libc::dup(fd) };
+    unsafe { libc::close(fd) };
+
+    let from_fd = unsafe { SharedMutex::<u32>::from_fd(passed) }.unwrap();
+    let wrong_type = unsafe { SharedMutex::<u64>::from_fd(passed) };
+    let kind = wrong_type.err().unwrap().kind();
+    assert_eq!(kind, std::io::ErrorKind::InvalidData);
+    // the descriptor stays ours, the mapping doesn't need it
+    assert_eq!(unsafe { libc::close(passed) }, 0);
+    *from_fd.lock().unwrap() = 2;
+    assert_eq!(*mutex.lock().unwrap(), 2);
+    assert_eq!(from_fd.name(), name);
+    ShmBackend.unlink(name).unwrap();
+}
+
+#[test]
+fn test_read_guard_releases() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 7u32) };
+    let guard = mutex.lock_shared_read().unwrap();
+    assert_eq!(*guard, 7);
+    assert!(mutex.is_locked());
+    assert!(mutex.try_lock().unwrap().is_none());
+    drop(guard);
+    assert!(!mutex.is_locked());
+
+    std::thread::scope(|s| {
+        let poisoner = s.spawn(|| mutex.lock().unwrap().poison_with(3));
+        poisoner.join().unwrap();
+    });
+    let guard = mutex.lock_shared_read().unwrap_err().into_poisoned();
+    let guard = guard.unwrap();
+    assert_eq!(guard.poison_reason(), Some(3));
+    drop(guard);
+    assert_eq!(*mutex.lock_shared_read().unwrap(), 7);
+}
+
+#[test]
+fn test_panic_inside_robust_list_op() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u32) };
+    // while linking the lock in after taking it, then while unlinking it to unlock
+    for unlocking in [false, true] {
+        std::thread::scope(|s| {
+            let holder = s.spawn(|| {
+                let guard = unlocking.then(|| mutex.lock().unwrap());
+                futex::PANIC_IN_ROBUST_OP.set(true);
+                let op = std::panic::AssertUnwindSafe(|| match guard {
+                    Some(guard) => drop(guard),
+                    None => std::mem::forget(mutex.lock()),
+                });
+                let panicked = std::panic::catch_unwind(op);
+                assert!(panicked.is_err());
+                // still held, and still on the list for the kernel to release at exit
+                assert!(futex::list_op_pending().is_null());
+                assert_eq!(futex::debug_robust_list().len(), 1);
+                assert!(mutex.futex.is_locked_by_me());
+            });
+            holder.join().unwrap();
+        });
+        assert!(mutex.lock().is_err());
+        assert!(mutex.lock().is_ok());
+    }
+}
+
+#[test]
+fn test_huge_timeouts_clamp() {
+    maybe_cleanup!();
+    for forever in [Duration::MAX, Duration::from_secs(u64::MAX)] {
+        let ts = futex::duration_to_timespec(forever);
+        assert_eq!((ts.tv_sec, ts.tv_nsec), (libc::time_t::MAX, 999_999_999));
+    }
+    let ts = futex::duration_to_timespec(Duration::new(5, 7));
+    assert_eq!((ts.tv_sec, ts.tv_nsec), (5, 7));
+
+    // and a lock taken with one still works, instead of timing out or panicking
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u32) };
+    let guard = mutex.lock_timeout(Duration::MAX).unwrap();
+    assert!(guard.is_some());
+    drop(guard);
+    let futex = PiMutex::new();
+    drop(futex.lock_timeout(Duration::from_secs(u64::MAX)).unwrap());
+    // a deadline far out but representable goes to the kernel, which has to wait for it
+    let guard = futex.lock().unwrap();
+    std::thread::scope(|s| {
+        let waiter = s.spawn(|| futex.lock_timeout(Duration::from_secs(1 << 40)).is_ok());
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        assert!(waiter.join().unwrap());
+    });
+}
+
+#[test]
+fn test_poison_on_purpose() {
+    maybe_cleanup!();
+    let name = function!();
+    let mutex = unsafe { SharedMutex::new_with_val(name, 1u32) };
+    let not_held = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| mutex.poison()));
+    assert!(not_held.is_err());
+    assert!(mutex.lock().is_ok());
+
+    let guard = mutex.lock().unwrap();
+    mutex.poison();
+    drop(guard);
+    let guard = mutex.lock().unwrap_err().into_poisoned().unwrap();
+    assert_eq!((*guard, guard.poison_reason()), (1, None));
+    drop(guard);
+    assert!(mutex.lock().is_ok());
+
+    // everyone opening it afterwards starts over
+    let guard = mutex.lock().unwrap();
+    mutex.poison();
+    drop(guard);
+    let reopened = unsafe { SharedMutex::new_with_val(name, 2u32) };
+    assert_eq!(*reopened.lock().unwrap(), 2);
+}
+
+#[test]
+fn test_inspect_poison_without_the_lock() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 5u32) };
+    assert_eq!(*mutex.lock_or_copy_poisoned().unwrap(), 5);
+
+    mutex.lock().unwrap().poison_with(9);
+    let poisoned = mutex.lock_or_copy_poisoned().unwrap_err().into_poisoned();
+    let poisoned = poisoned.unwrap();
+    assert_eq!((*poisoned, poisoned.poison_reason()), (5, Some(9)));
+    assert!(!mutex.is_locked());
+    // looking didn't clear it
+    let poisoned = mutex.lock_or_copy_poisoned().unwrap_err().into_poisoned();
+    let poisoned = poisoned.unwrap();
+    assert_eq!(poisoned.poison_reason(), Some(9));
+
+    let mut guard = poisoned.into_guard();
+    assert_eq!(guard.poison_reason(), Some(9));
+    *guard = 6;
+    drop(guard);
+    assert_eq!(*mutex.lock_or_copy_poisoned().unwrap(), 6);
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_undersized_segment_is_grown() {
+    maybe_cleanup!();
+    let name = function!();
+    // left behind by something with a smaller layout
+    let shm_name = std::ffi::CString::new(format!("/{name}")).unwrap();
+    let flags = libc::O_RDWR | libc::O_CREAT;
+    let fd = unsafe { libc::shm_open(shm_name.as_ptr(), flags, 0o666) };
+    assert!(fd >= 0, "{}", std::io::Error::last_os_error());
+    assert_eq!(unsafe { libc::ftruncate(fd, 64) }, 0);
+
+    let mutex = unsafe { SharedMutex::new_with_val(name, [7u64; 2048]) };
+    assert!(mutex.lock().unwrap().iter().all(|&x| x == 7));
+    let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+    assert_eq!(unsafe { libc::fstat(fd, &mut stat) }, 0);
+    assert!(stat.st_size as usize >= size_of::<[u64; 2048]>());
+    unsafe { libc::close(fd) };
+    ShmBackend.unlink(name).unwrap();
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_segment_truncated_while_mapping() {
+    use crate::shared_mem::TRUNCATE_BEFORE_MAP;
+
+    maybe_cleanup!();
+    let name = function!();
+    TRUNCATE_BEFORE_MAP.set(true);
+    let opened = unsafe { SharedMutex::try_open_in(&ShmBackend, name, || [0u8; 65536]) };
+    let message = format!("{:#}", opened.err().unwrap());
+    assert!(message.contains("truncated"), "{message}");
+    ShmBackend.unlink(name).unwrap();
+}
+
+/// Maps a single byte, whatever it's asked for, like a segment someone truncated
+struct ShortBackend;
+
+impl MemoryBackend for ShortBackend {
+    fn get_memory(&self, name: &str, _length: usize) -> std::io::Result<Mapping> {
+        ShmBackend.get_memory(name, 1)
+    }
+
+    fn unlink(&self, name: &str) -> std::io::Result<()> {
+        ShmBackend.unlink(name)
+    }
+}
+
+#[test]
+fn test_short_mapping_is_an_error() {
+    maybe_cleanup!();
+    let name = function!();
+    let opened = unsafe { SharedMutex::try_open_in(&ShortBackend, name, || [0u8; 65536]) };
+    let message = format!("{:#}", opened.err().unwrap());
+    assert!(message.contains("were asked for"), "{message}");
+    ShmBackend.unlink(name).unwrap();
+}
+
+#[test]
+fn test_held_by_current_thread() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u32) };
+    assert!(!mutex.held_by_current_thread());
+    let guard = mutex.lock().unwrap();
+    assert!(mutex.held_by_current_thread());
+    thread::scope(|s| {
+        let other = s.spawn(|| mutex.held_by_current_thread());
+        assert!(!other.join().unwrap());
+    });
+    drop(guard);
+    assert!(!mutex.held_by_current_thread());
+}
+
+#[cfg(feature = "unsafe_peek")]
+#[test]
+fn test_peek_while_locked() {
+    use std::sync::mpsc;
+
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+    let (held_tx, held_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel();
+    let mutex = &mutex;
+    thread::scope(|s| {
+        s.spawn(move || {
+            let mut guard = mutex.lock().unwrap();
+            *guard = 7;
+            held_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        held_rx.recv().unwrap();
+        // doesn't wait for the owner, which is done writing
+        assert_eq!(unsafe { mutex.peek() }, 7);
+        release_tx.send(()).unwrap();
+    });
+    assert_eq!(unsafe { mutex.peek() }, *mutex.lock().unwrap());
+
+    // compare_exchange is atomic, so it may run alongside
+    thread::scope(|s| {
+        s.spawn(|| {
+            for i in 7..1000 {
+                unsafe { mutex.compare_exchange(i, i + 1) }.unwrap();
+            }
+        });
+        let mut last = 7;
+        while last < 1000 {
+            let peeked = unsafe { mutex.peek() };
+            // a word is never torn, so it only ever moves forward
+            assert!(peeked >= last);
+            last = peeked;
+        }
+    });
+}
+
+#[test]
+fn test_clone_is_the_same_mutex() {
+    maybe_cleanup!();
+    let name = function!();
+    let mutex = unsafe { SharedMutex::new_with_val(name, 0u32) };
+    let clone = mutex.clone();
+    thread::scope(|s| {
+        let clone = clone.clone();
+        s.spawn(move || *clone.lock().unwrap() = 1);
+    });
+    assert_eq!(*mutex.lock().unwrap(), 1);
+    // still mapped, and still registered as a u32, after the original is gone
+    drop(mutex);
+    *clone.lock().unwrap() += 1;
+    let other_type = unsafe { SharedMutex::new_checked(name, || 0u64) };
+    assert!(other_type.err().unwrap().is::<TypeMismatch>());
+    let reopened = unsafe { SharedMutex::new_with_val(name, 0u32) };
+    assert_eq!(*reopened.lock().unwrap(), 2);
+
+    let anonymous = SharedMutex::new_anonymous(|| 5u8);
+    let clone = anonymous.clone();
+    drop(anonymous);
+    assert_eq!(*clone.lock().unwrap(), 5);
+}
+
+#[test]
+fn test_lock_with_spins() {
+    maybe_cleanup!();
+    const THREADS: usize = 4;
+    const LOCKS: u64 = 1_000;
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+
+    for spins in [0, 20, 200] {
+        let config = LockConfig::new().spins(spins);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..LOCKS {
+                        *mutex.lock_with(config).unwrap() += 1;
+                    }
+                });
+            }
+        });
+    }
+    assert_eq!(*mutex.lock().unwrap(), 3 * THREADS as u64 * LOCKS);
+
+    // poison found while spinning is still poison
+    thread::scope(|s| {
+        s.spawn(|| std::mem::forget(mutex.lock().unwrap()));
+    });
+    let config = LockConfig::new().spins(100);
+    assert!(mutex.lock_with(config).is_err());
+}
+
+#[test]
+#[ignore = "prints throughput, run with --ignored --nocapture on the target machine"]
+fn bench_lock_with_spins() {
+    maybe_cleanup!();
+    const THREADS: usize = 4;
+    const LOCKS: u64 = 5_000;
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+
+    for spins in [0, 20, 200] {
+        let config = LockConfig::new().spins(spins);
+        let start = std::time::Instant::now();
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..LOCKS {
+                        *mutex.lock_with(config).unwrap() += 1;
+                        // moderate contention: some work outside the lock too
+                        std::hint::black_box((0..50).sum::<u64>());
+                    }
+                });
+            }
+        });
+        let elapsed = start.elapsed();
+        let per_sec = (THREADS as u64 * LOCKS) as f64 / elapsed.as_secs_f64();
+        println!("{spins} spins: {per_sec:.0} locks/s");
+    }
+}
+
+#[test]
+fn test_robust_list_active() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u32) };
+    thread::scope(|s| {
+        s.spawn(|| {
+            *mutex.lock().unwrap() += 1;
+            assert!(futex::robust_list_active());
+
+            // someone else's head replaces ours, kept until the kernel walks it at exit
+            let other = Box::leak(Box::new(RobustListHead {
+                list: RobustList {
+                    next: std::ptr::null_mut(),
+                },
+                futex_offset: 0,
+                list_op_pending: std::ptr::null_mut(),
+            }));
+            other.list.next = other.head_value();
+            let size = size_of::<RobustListHead>();
+            let r = unsafe { libc::syscall(libc::SYS_set_robust_list, &raw mut *other, size) };
+            assert_eq!(r, 0);
+            assert!(!futex::robust_list_active());
+        });
+    });
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_condvar_notify_n() {
+    use std::sync::mpsc;
+
+    const CONSUMERS: usize = 5;
+    let mutex = PiMutex::new();
+    let condvar = PiCondvar::new();
+    // only touched with the mutex held
+    let items = AtomicUsize::new(0);
+    let consumed = AtomicUsize::new(0);
+    let sleeping = |tid| futex::is_sleeping(tid).unwrap();
+
+    thread::scope(|s| {
+        let (tid_tx, tid_rx) = mpsc::channel();
+        for _ in 0..CONSUMERS {
+            let tid_tx = tid_tx.clone();
+            let (mutex, condvar, items, consumed) = (&mutex, &condvar, &items, &consumed);
+            s.spawn(move || {
+                let mut guard = mutex.lock().unwrap();
+                tid_tx.send(unsafe { gettid() }).unwrap();
+                while items.load(Ordering::Relaxed) == 0 {
+                    guard = condvar.wait(guard).unwrap();
+                }
+                items.fetch_sub(1, Ordering::Relaxed);
+                consumed.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        let tids: Vec<i32> = tid_rx.iter().take(CONSUMERS).collect();
+        while !tids.iter().all(|&tid| sleeping(tid)) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let guard = mutex.lock().unwrap();
+        items.store(3, Ordering::Relaxed);
+        assert_eq!(condvar.notify_n(&mutex, 3).unwrap(), 3);
+        drop(guard);
+        while consumed.load(Ordering::Relaxed) < 3 {
+            thread::yield_now();
+        }
+        // nobody else was woken to find nothing
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(consumed.load(Ordering::Relaxed), 3);
+        assert_eq!(condvar.notify_n(&mutex, 0).unwrap(), 0);
+
+        let guard = mutex.lock().unwrap();
+        items.store(CONSUMERS - 3, Ordering::Relaxed);
+        condvar.notify_all(&mutex).unwrap();
+        drop(guard);
+    });
+    assert_eq!(consumed.load(Ordering::Relaxed), CONSUMERS);
+}
+
+#[test]
+fn test_attach() {
+    maybe_cleanup!();
+    let name = function!();
+    let absent = unsafe { SharedMutex::<u32>::attach(name) };
+    assert_eq!(absent.err().unwrap().kind(), std::io::ErrorKind::NotFound);
+
+    let mutex = unsafe { SharedMutex::new_with_val(name, 1u32) };
+    let attached = unsafe { SharedMutex::<u32>::attach(name) }.unwrap();
+    *attached.lock().unwrap() = 2;
+    assert_eq!(*mutex.lock().unwrap(), 2);
+
+    let mismatched = unsafe { SharedMutex::<i32>::attach(name) }.err().unwrap();
+    assert_eq!(mismatched.kind(), std::io::ErrorKind::InvalidData);
+
+    // the attached handle alone keeps the name registered for `u32`, even for a fresh
+    // segment without a header to check
+    drop(mutex);
+    #[cfg(not(miri))]
+    {
+        unlink_if_exists(name).unwrap();
+        assert!(unsafe { SharedMutex::<i32>::new_checked(name, || 0) }.is_err());
+        drop(attached);
+        assert!(unsafe { SharedMutex::<i32>::new_checked(name, || 0) }.is_ok());
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_priority_ceiling() {
+    fn scheduling() -> (i32, i32) {
+        let mut param = libc::sched_param { sched_priority: 0 };
+        assert_eq!(unsafe { libc::sched_getparam(0, &mut param) }, 0);
+        (unsafe { libc::sched_getscheduler(0) }, param.sched_priority)
+    }
+
+    let mutex = PiMutex::new();
+    // on a thread of its own, so a failed restore can't leak into other tests
+    thread::scope(|s| {
+        s.spawn(|| {
+            let before = scheduling();
+            let guard = match mutex.lock_with_ceiling(10) {
+                Ok(guard) => guard,
+                Err(e) => {
+                    assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied, "{e}");
+                    assert!(!mutex.is_locked());
+                    println!("can't raise priority here, skipping: {e}");
+                    return;
+                }
+            };
+            assert!(guard.is_locked_by_me());
+            assert_eq!(scheduling(), (libc::SCHED_FIFO, 10));
+            drop(guard);
+            assert!(!mutex.is_locked());
+            assert_eq!(scheduling(), before);
+
+            let invalid = mutex.lock_with_ceiling(1000).err().unwrap();
+            assert_eq!(invalid.kind(), std::io::ErrorKind::InvalidInput);
+            assert!(!mutex.is_locked());
+        });
+    });
+}
+
+#[test]
+fn test_wait_until_free() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u32) };
+    mutex.wait_until_free(Some(Duration::ZERO)).unwrap();
+
+    let guard = mutex.lock().unwrap();
+    let held = mutex.wait_until_free(None).err().unwrap();
+    assert_eq!(held.kind(), std::io::ErrorKind::Deadlock);
+    thread::scope(|s| {
+        let observer = s.spawn(|| {
+            let timeout = Some(Duration::from_millis(20));
+            let timed_out = mutex.wait_until_free(timeout).err().unwrap();
+            assert_eq!(timed_out.kind(), std::io::ErrorKind::TimedOut);
+            mutex.wait_until_free(None).unwrap();
+            let freed = std::time::Instant::now();
+            // not taken along the way
+            assert!(!mutex.is_locked());
+            freed
+        });
+        thread::sleep(Duration::from_millis(100));
+        drop(guard);
+        let released = std::time::Instant::now();
+        let freed = observer.join().unwrap();
+        assert!(freed.duration_since(released) < Duration::from_millis(50));
+    });
+    assert!(mutex.try_lock().unwrap().is_some());
+}
+
+#[test]
+fn test_attach_existing() {
+    maybe_cleanup!();
+    let name = function!();
+    // no Default needed, there's never an initial value
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Config {
+        limit: u32,
+    }
+
+    let early = unsafe { SharedMutex::<Config>::attach_existing(name, None) };
+    assert_eq!(early.err().unwrap().kind(), std::io::ErrorKind::NotFound);
+    let short_wait = Some(Duration::from_millis(30));
+    let timed_out = unsafe { SharedMutex::<Config>::attach_existing(name, short_wait) }.err();
+    assert_eq!(timed_out.unwrap().kind(), std::io::ErrorKind::TimedOut);
+
+    thread::scope(|s| {
+        let consumer = s.spawn(|| {
+            let wait = Some(Duration::from_secs(10));
+            let mutex = unsafe { SharedMutex::<Config>::attach_existing(name, wait) }.unwrap();
+            *mutex.lock().unwrap()
+        });
+        thread::sleep(Duration::from_millis(50));
+        let producer = unsafe { SharedMutex::new_with_val(name, Config { limit: 3 }) };
+        assert_eq!(consumer.join().unwrap(), Config { limit: 3 });
+        drop(producer);
+    });
+
+    // another type is final, however long the wait
+    let start = std::time::Instant::now();
+    let wait = Some(Duration::from_secs(10));
+    let other = unsafe { SharedMutex::<u64>::attach_existing(name, wait) };
+    assert_eq!(other.err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[cfg(feature = "capi")]
+#[test]
+fn test_capi_lock_unlock() {
+    use crate::capi::*;
+
+    maybe_cleanup!();
+    let name = std::ffi::CString::new(function!()).unwrap();
+    unsafe {
+        let mutex = rsm_open(name.as_ptr(), 64);
+        assert!(!mutex.is_null());
+        assert_eq!(rsm_lock(mutex), RSM_OK);
+        *rsm_data(mutex).cast::<u64>() = 42;
+
+        // relocking panics in Rust, which mustn't unwind into C
+        assert_eq!(rsm_lock(mutex), RSM_ERROR);
+
+        let handle = mutex as usize;
+        thread::spawn(move || {
+            assert_eq!(rsm_try_lock(handle as *mut _), RSM_BUSY);
+            assert_eq!(rsm_unlock(handle as *mut _), RSM_ERROR, "not the owner");
+        })
+        .join()
+        .unwrap();
+        assert!((*mutex).is_locked());
+        assert_eq!(rsm_unlock(mutex), RSM_OK);
+        assert_eq!(rsm_unlock(mutex), RSM_ERROR, "unlocked already");
+
+        // a Rust handle to the same name sees the bytes written through C
+        let rust = SharedMutex::new_sized(function!(), 0, || ());
+        assert_eq!(rust.lock().unwrap().tail()[0], 42);
+
+        assert_eq!(rsm_try_lock(mutex), RSM_OK);
+        assert_eq!(rsm_unlock(mutex), RSM_OK);
+
+        // a dead owner is reported, and taking over from one can fail
+        thread::spawn(move || assert_eq!(rsm_lock(handle as *mut _), RSM_OK))
+            .join()
+            .unwrap();
+        futex::FAIL_LOCK_PI.set((nix::errno::Errno::ENOMEM, 1));
+        assert_eq!(rsm_try_lock(mutex), RSM_ERROR);
+        assert_eq!(rsm_lock(mutex), RSM_OWNER_DIED);
+        assert_eq!(rsm_unlock(mutex), RSM_OK);
+        rsm_close(mutex);
+        assert_eq!(rsm_lock(std::ptr::null_mut()), RSM_ERROR);
+    }
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_last_dead_owner_from_forked_child() {
+    maybe_cleanup!();
+    // the child's first lock registers its robust list, see the next test for a parent
+    // that registered before forking
+    let name = function!();
+    let child = fork(|| {
+        let mutex = unsafe { SharedMutex::new_with_val(name, 5u64) };
+        std::mem::forget(mutex.lock().unwrap());
+    });
+    wait_ok(child);
+
+    let Err(mutex) = (unsafe { SharedMutex::try_new(name, || 0u64) }) else {
+        panic!("the child died holding the lock");
+    };
+    assert_eq!(mutex.last_dead_owner(), Some(child));
+    assert_eq!(*mutex.lock().unwrap(), 5);
+    assert_eq!(mutex.last_dead_owner(), Some(child));
+}
+
+#[cfg(not(miri))]
+#[test]
+fn test_forked_child_of_registered_thread_recovers() {
+    maybe_cleanup!();
+    let mutex = unsafe { SharedMutex::new_with_val(function!(), 0u64) };
+    // registers this thread's robust list, which the child inherits but the kernel doesn't
+    *mutex.lock().unwrap() = 1;
+
+    let child = fork(|| {
+        let mut guard = mutex.lock().unwrap();
+        *guard = 2;
+        std::mem::forget(guard);
+        unsafe { libc::kill(libc::getpid(), libc::SIGKILL) };
+        unreachable!();
+    });
+    assert!(libc::WIFSIGNALED(wait(child)));
+
+    // Only the kernel walking the child's list marks the word owner-died. A blocking lock
+    // would get in through a dead TID anyway, but try_lock never enters the kernel.
+    let Err(LockError::Poisoned(guard)) = mutex.try_lock() else {
+        panic!("the child's lock wasn't cleaned up");
+    };
+    assert_eq!(*guard, 2);
+    assert_eq!(mutex.last_dead_owner(), Some(child));
+}
+
+#[test]
+fn test_rwlock_try_upgrade_with_other_reader() {
+    maybe_cleanup!();
+    let lock = &unsafe { SharedRwLock::new_with_val(function!(), 0u64) };
+
+    let read = lock.read().unwrap();
+    let write = read.try_upgrade().unwrap();
+    drop(write);
+
+    let (held_tx, held_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    thread::scope(|s| {
+        let other = s.spawn(move || {
+            let _read = lock.read().unwrap();
+            held_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        held_rx.recv().unwrap();
+
+        let read = lock.read().unwrap();
+        let read = read.try_upgrade().unwrap_err();
+        release_tx.send(()).unwrap();
+        other.join().unwrap();
+
+        // the handed back guard is still a valid read lock and can retry
+        let mut write = read.try_upgrade().unwrap();
+        *write = 1;
+    });
+    assert_eq!(*lock.read().unwrap(), 1);
+}
+
+extern "C" fn noop_handler(_: libc::c_int) {}
+
+/// Held by tests that install signal handlers, which are process-wide, so that one
+/// restoring a handler can't pull it from under another.
+static SIGNAL_HANDLERS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+fn take_signal_handlers() -> std::sync::MutexGuard<'static, ()> {
+    SIGNAL_HANDLERS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Installs a handler without `SA_RESTART` so blocking syscalls see `EINTR`, holding
+/// [`SIGNAL_HANDLERS`] for as long as the test keeps the guard.
+fn install_noop_handler(signal: libc::c_int) -> std::sync::MutexGuard<'static, ()> {
+    let handlers = take_signal_handlers();
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = noop_handler as *const () as usize;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, std::ptr::null_mut());
+    }
+    handlers
+}
+
+/// Forks a child that runs `child` and exits with 0, unless `child` exits first.
+#[cfg(not(miri))]
+fn fork(child: impl FnOnce()) -> libc::pid_t {
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0, "{}", std::io::Error::last_os_error());
+    if pid == 0 {
+        child();
+        unsafe { libc::_exit(0) };
+    }
+    pid
+}
+
+/// Reaps the child `pid`, returning its status for the `libc::WIF*` macros.
+#[cfg(not(miri))]
+fn wait(pid: libc::pid_t) -> libc::c_int {
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    status
+}
+
+/// Reaps the child `pid`, which must have exited with 0.
+#[cfg(not(miri))]
+fn wait_ok(pid: libc::pid_t) {
+    let status = wait(pid);
+    assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+}
+
 struct CleanupGuard {
     #[allow(dead_code)]
     name: &'static str,
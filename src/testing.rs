@@ -0,0 +1,124 @@
+//! Hooks for checking the crate's guarantees from other crates' tests.
+//!
+//! Priority inheritance is the reason this crate uses `FUTEX_LOCK_PI` rather than a plain
+//! futex, and part of it is that the kernel hands a contended lock to its waiters highest
+//! priority first. [`__testing_priority_order`] shows that happening on the machine the
+//! tests run on.
+
+use std::{
+    io,
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    futex::{is_sleeping, tid},
+    mutex::PiMutex,
+};
+
+/// What [`__testing_priority_order`] saw.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PriorityOrder {
+    /// Whether the threads ran under `SCHED_FIFO`. Without `CAP_SYS_NICE` they fall back
+    /// to `SCHED_OTHER` at descending nice values instead, and the kernel serves
+    /// `SCHED_OTHER` waiters of a PI futex in arrival order, nice or not.
+    pub realtime: bool,
+    /// Thread indices in the order they were granted the lock. Thread `i` has the `i`th
+    /// lowest priority, so under `SCHED_FIFO` this is descending.
+    pub order: Vec<usize>,
+}
+
+/// Blocks `threads` threads of increasing priority on one held [`PiMutex`], releases it
+/// once all of them are waiting in the kernel, and reports the order they got it in.
+///
+/// Each thread asks for `SCHED_FIFO` with `sched_setscheduler`; if that's not permitted,
+/// it's niced instead, which doesn't need privileges but doesn't order the grants either,
+/// see [`PriorityOrder::realtime`]. The caller's own scheduling is left alone. Linux
+/// only, since it waits for the threads to be asleep by reading `/proc`.
+///
+/// # Panics
+///
+/// If `threads` is more than there are `SCHED_FIFO` priorities, or above 20.
+pub fn __testing_priority_order(threads: usize) -> io::Result<PriorityOrder> {
+    let min = unsafe { libc::sched_get_priority_min(libc::SCHED_FIFO) };
+    let max = unsafe { libc::sched_get_priority_max(libc::SCHED_FIFO) };
+    assert!(
+        threads <= 20 && threads as i32 <= max - min,
+        "{threads} threads can't all get distinct priorities"
+    );
+    let mutex = PiMutex::new();
+    let realtime = AtomicBool::new(true);
+    let order = Mutex::new(Vec::with_capacity(threads));
+    let tids = Mutex::new(Vec::with_capacity(threads));
+    // threads about to lock, after which they can only sleep on the futex
+    let locking = AtomicUsize::new(0);
+
+    let guard = mutex.lock()?;
+    let waited = thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|i| {
+                let (mutex, realtime, order, tids, locking) =
+                    (&mutex, &realtime, &order, &tids, &locking);
+                s.spawn(move || -> io::Result<()> {
+                    let priority = set_priority(min + 1 + i as i32, (threads - 1 - i) as i32);
+                    lock(tids).push(tid());
+                    if let Ok(false) = priority {
+                        realtime.store(false, Ordering::Relaxed);
+                    }
+                    // counted even on failure, the caller then finds us gone
+                    locking.fetch_add(1, Ordering::Release);
+                    priority?;
+                    let _guard = mutex.lock()?;
+                    lock(order).push(i);
+                    Ok(())
+                })
+            })
+            .collect();
+        while locking.load(Ordering::Acquire) < threads {
+            thread::sleep(Duration::from_millis(1));
+        }
+        for &tid in lock(&tids).iter() {
+            while !is_sleeping(tid)? {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        drop(guard);
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().unwrap())
+    });
+    waited?;
+    Ok(PriorityOrder {
+        realtime: realtime.into_inner(),
+        order: order.into_inner().unwrap_or_else(PoisonError::into_inner),
+    })
+}
+
+/// Moves the calling thread to `SCHED_FIFO` at `priority`, or, without permission, to
+/// `nice`. Returns whether it's realtime.
+fn set_priority(priority: i32, nice: i32) -> io::Result<bool> {
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    // 0 is the calling thread for both calls on Linux
+    if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) } == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() != Some(libc::EPERM) {
+        return Err(e);
+    }
+    // raising nice needs no privileges
+    match unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } {
+        0 => Ok(false),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,62 @@
+//! Handles to a named mutex that don't keep it mapped.
+
+use std::marker::PhantomData;
+
+use crate::{
+    shared_data::{SharedMutex, SharedMutexInner},
+    shared_mem::{self, SharedMemorySafe},
+};
+
+/// A reference to the mutex `name` that holds nothing but the name, for registries of many
+/// mutexes that would otherwise keep them all mapped. [`Self::upgrade`] maps it again, if
+/// it's still there. Unlike [`std::sync::Weak`] it doesn't keep anything alive either: the
+/// segment lives on its own until it's unlinked.
+pub struct SharedMutexWeak<T: SharedMemorySafe> {
+    name: String,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T: SharedMemorySafe> Clone for SharedMutexWeak<T> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            _type: PhantomData,
+        }
+    }
+}
+
+impl<T: SharedMemorySafe> std::fmt::Debug for SharedMutexWeak<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedMutexWeak")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl<T: SharedMemorySafe> SharedMutexWeak<T> {
+    /// A handle to `name`, which doesn't need to exist yet.
+    ///
+    /// # Safety
+    ///
+    /// The caller should ensure that for a given name all callers of this function
+    /// across any process on the same system, specify the same `T`. `upgrade` checks the
+    /// type like [`SharedMutex::new_checked`] does, with the same blind spots.
+    pub unsafe fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            _type: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Maps the mutex again. `None` if the segment doesn't exist (never created, or
+    /// unlinked since), or doesn't hold a finished mutex of this `T`.
+    pub fn upgrade(&self) -> Option<SharedMutex<T>> {
+        let memory =
+            shared_mem::open_existing(&self.name, size_of::<SharedMutexInner<T>>()).ok()?;
+        SharedMutex::from_existing(memory)
+    }
+}